@@ -1,3 +1,32 @@
+use std::process::Command;
+
+/// Salida de un comando sin el salto final, o None si falla o no existe.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
 fn main() {
+    // Metadatos de compilación para get_build_info (ver lib.rs)
+    let git_sha = command_output("git", &["rev-parse", "--short=12", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=STACKLUME_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=STACKLUME_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=STACKLUME_BUILD_PROFILE={}", std::env::var("PROFILE").unwrap_or_default());
+    println!("cargo:rustc-env=TARGET={}", std::env::var("TARGET").unwrap_or_default());
+    // Recalcular el commit al cambiar de rama o hacer commit (solo si hay repo:
+    // vigilar una ruta inexistente haría que build.rs se ejecutara siempre)
+    for git_file in ["../.git/HEAD", "../.git/index"] {
+        if std::path::Path::new(git_file).exists() {
+            println!("cargo:rerun-if-changed={}", git_file);
+        }
+    }
+
     tauri_build::build()
 }
@@ -1,4 +1,5 @@
 use std::io::Read as _;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use tauri::{Emitter, Manager, State};
 use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutState};
@@ -14,6 +15,76 @@ struct ServerState {
     /// el OS cierra este handle automáticamente y mata node.exe con él.
     #[cfg(windows)]
     node_job: Mutex<isize>,
+    /// Configuración con la que se lanzó node.exe (solo en producción)
+    #[cfg(not(dev))]
+    launch: Mutex<Option<ServerLaunch>>,
+    /// true cuando /api/health ha respondido. Hasta entonces el guard de navegación
+    /// bloquea cualquier intento de cargar el origen del servidor en el webview.
+    ready: AtomicBool,
+    /// Petición de cancelar el arranque en curso: el hilo de espera la comprueba
+    /// en cada sondeo y abandona sin mostrar la página de timeout.
+    cancel: AtomicBool,
+    /// Se incrementa cada vez que la ventana se oculta o recupera el foco. El hilo de
+    /// keep-alive lo compara al despertar para saber si la ventana se reabrió entretanto.
+    #[cfg(not(dev))]
+    visibility_seq: std::sync::atomic::AtomicU64,
+    /// Se incrementa en cada `spawn_server` y al parar node (`take_server_child`). Cada
+    /// hilo de espera recibe el suyo y, si al terminar ya no coincide, su arranque se
+    /// sustituyó o se detuvo a propósito: no navega ni muestra nada.
+    #[cfg(not(dev))]
+    spawn_generation: std::sync::atomic::AtomicU64,
+    /// true si node.exe se detuvo por keep-alive agotado: al reabrir hay que relanzarlo.
+    #[cfg(not(dev))]
+    idle_stopped: AtomicBool,
+    /// true mientras se muestra la página de recuperación de la DB: solo entonces se
+    /// atienden las navegaciones a stacklume://recover/... (ver `handle_recovery_action`).
+    #[cfg(not(dev))]
+    db_recovery_pending: AtomicBool,
+    /// true mientras se muestra la página de mantenimiento: solo entonces se atiende
+    /// stacklume://maintenance/retry (ver `handle_maintenance_retry`).
+    maintenance_pending: AtomicBool,
+    /// true mientras la UI tiene trabajo en curso que no debe interrumpirse
+    /// (importaciones, sincronizaciones...). Lo marca el frontend con `set_server_busy`;
+    /// el reinicio programado se pospone mientras siga activo.
+    busy: AtomicBool,
+    /// Estado de salida del último node.exe que terminó inesperadamente (lo anota
+    /// el watchdog; se limpia en cada spawn)
+    #[cfg(not(dev))]
+    last_exit: Mutex<Option<std::process::ExitStatus>>,
+    /// PID del node.exe vivo (None si no hay servidor o en dev, donde lo lanza Tauri CLI)
+    pid: Mutex<Option<u32>>,
+    /// Momento en que se lanzó el node.exe actual (para el uptime de `get_health_status`)
+    #[cfg(not(dev))]
+    spawned_at: Mutex<Option<std::time::Instant>>,
+    /// Contadores de autorreparación de la sesión (ver `supervision_counters`)
+    supervision: Mutex<SupervisionCounters>,
+    /// Secreto aleatorio de esta sesión, pasado a node en STACKLUME_SESSION_TOKEN:
+    /// el servidor puede exigirlo en sus rutas API para rechazar a otros procesos locales.
+    /// En dev el servidor lo lanza beforeDevCommand y no lo recibe.
+    session_token: String,
+    /// Rutas resueltas en el arranque (ver `get_diagnostics`). None hasta que setup
+    /// resuelve los recursos, y siempre en dev.
+    diagnostics: Mutex<Option<DiagnosticsDto>>,
+    /// Salida de `node --version` (p.ej. "v20.11.1"), leída una vez al arrancar en
+    /// segundo plano (ver `probe_node_version`). None hasta entonces y en dev.
+    node_version: Mutex<Option<String>>,
+    /// Milisegundos desde el spawn de node.exe hasta que respondió al health check en
+    /// el último arranque correcto (ver `get_startup_metrics`). None hasta entonces y en dev.
+    last_startup_ms: Mutex<Option<u64>>,
+}
+
+/// Cuánta autorreparación ha habido en esta sesión: reinicios por crash (watchdog),
+/// arranques fallidos (timeout del health check) y el último reinicio.
+#[derive(Clone, Default, serde::Serialize)]
+struct SupervisionCounters {
+    crash_restarts: u32,
+    /// Reservado para reinicios por cuelgue: aún no hay watchdog de cuelgues, así que
+    /// de momento siempre vale 0.
+    hang_restarts: u32,
+    startup_failures: u32,
+    last_restart_reason: Option<String>,
+    /// RFC 3339, hora local
+    last_restart_at: Option<String>,
 }
 
 /// Estado del servidor LLM local integrado (llama.cpp llama-server)
@@ -38,9 +109,10 @@ struct LlamaState {
 /// Con JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: cuando el proceso padre termina
 /// por CUALQUIER motivo (kill, crash, NSIS TerminateProcess...), el OS cierra
 /// el handle del job y mata automáticamente todos los procesos asignados.
-/// Devuelve el HANDLE del job como isize (0 = fallo), mantenerlo vivo mientras viva la app.
+/// Devuelve el HANDLE del job como isize (mantenerlo vivo mientras viva la app) o el
+/// paso que falló: sin job, el hijo solo muere si se le mata explícitamente al salir.
 #[cfg(windows)]
-fn create_job_for_child(child_pid: u32) -> isize {
+fn create_job_for_child(child_pid: u32) -> Result<isize, String> {
     use windows_sys::Win32::Foundation::CloseHandle;
     use windows_sys::Win32::System::JobObjects::{
         AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject,
@@ -56,7 +128,7 @@ fn create_job_for_child(child_pid: u32) -> isize {
         // Crear job object anónimo
         let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
         if job.is_null() {
-            return 0;
+            return Err(format!("CreateJobObjectW falló: {}", std::io::Error::last_os_error()));
         }
 
         // std::mem::zeroed() rellena con ceros y luego seteamos solo LimitFlags
@@ -71,759 +143,5220 @@ fn create_job_for_child(child_pid: u32) -> isize {
             std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
         );
         if result == 0 {
+            let err = std::io::Error::last_os_error();
             CloseHandle(job);
-            return 0;
+            return Err(format!("SetInformationJobObject falló: {}", err));
         }
 
         // Asignar el proceso hijo al job (privilegios mínimos, no PROCESS_ALL_ACCESS)
         let process = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, child_pid);
-        if !process.is_null() {
-            AssignProcessToJobObject(job, process);
-            CloseHandle(process);
+        if process.is_null() {
+            let err = std::io::Error::last_os_error();
+            CloseHandle(job);
+            return Err(format!("OpenProcess({}) falló: {}", child_pid, err));
+        }
+        // Falla p.ej. si el proceso ya está en un job que no permite anidar (algunos
+        // CI y sandboxes): el job quedaría vacío y no protegería nada
+        let assigned = AssignProcessToJobObject(job, process);
+        let err = std::io::Error::last_os_error();
+        CloseHandle(process);
+        if assigned == 0 {
+            CloseHandle(job);
+            return Err(format!("AssignProcessToJobObject falló: {}", err));
         }
 
         // Devolver el job handle como isize — NO llamar CloseHandle(job) aquí.
         // El OS lo cierra al morir Stacklume.exe (por cualquier causa) y mata node.exe.
-        job as isize
+        Ok(job as isize)
     }
 }
 
-// ─── Utilidades de producción ─────────────────────────────────────────────────
+/// Cierra un job creado por `create_job_for_child`. Solo debe llamarse con node.exe
+/// ya muerto: por KILL_ON_JOB_CLOSE, cerrarlo mata lo que quede dentro. 0 = nada.
+#[cfg(windows)]
+fn close_job(handle: isize) {
+    if handle != 0 {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(handle as _) };
+    }
+}
 
-use std::net::TcpListener;
+/// Cierra y olvida el job de `ServerState::node_job` (deja 0), para no acumular un
+/// handle por cada reinicio. Con el hijo muerto; no-op en macOS/Linux.
+#[cfg(not(dev))]
+fn release_node_job(app: &tauri::AppHandle) {
+    #[cfg(windows)]
+    close_job(std::mem::take(&mut *app.state::<ServerState>().node_job.lock().unwrap()));
+    #[cfg(not(windows))]
+    let _ = app;
+}
 
-/// Puerto preferido estable para producción.
-/// Se usa siempre que esté disponible, garantizando URLs de MCP estables
-/// entre reinicios (p. ej. http://127.0.0.1:7879/api/mcp).
-/// El puerto de dev es 7878, así que usamos 7879 para producción.
+// ─── Configuración por entorno ────────────────────────────────────────────────
+
+/// Lee un flag booleano de entorno ("1", "true", "yes", "on" → true).
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Overrides pensados para testers/forks (p.ej. STACKLUME_APP_ID).
+/// Deshabilitados salvo que se active explícitamente STACKLUME_ENABLE_DEV_OVERRIDES=1.
+fn dev_overrides_enabled() -> bool {
+    env_flag("STACKLUME_ENABLE_DEV_OVERRIDES")
+}
+
+/// Comandos de soporte con efectos peligrosos (scripts contra la DB en vivo, etc.).
+/// Solo disponibles con STACKLUME_ENABLE_DEBUG_CMDS=1.
+fn debug_commands_enabled() -> bool {
+    env_flag("STACKLUME_ENABLE_DEBUG_CMDS")
+}
+
+/// Milisegundos que node.exe sigue vivo tras ocultar la ventana al tray
+/// (STACKLUME_KEEP_ALIVE_MS). 0 (por defecto) = no se detiene nunca al ocultar.
 #[cfg(not(dev))]
-const PREFERRED_PORT: u16 = 7879;
+fn keep_alive_ms() -> u64 {
+    std::env::var("STACKLUME_KEEP_ALIVE_MS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
 
-/// Busca un puerto TCP libre intentando primero PREFERRED_PORT (estable entre
-/// reinicios para que las configuraciones MCP en Claude Desktop / Cursor no se
-/// rompan) y recurriendo a asignación aleatoria del OS solo si está ocupado.
+/// Hook de integración al quedar listo el servidor (STACKLUME_ON_READY): una URL
+/// http(s) recibe un POST JSON `{port, pid}`; cualquier otro valor se ejecuta como
+/// comando de shell con STACKLUME_PORT/STACKLUME_PID en el entorno.
 #[cfg(not(dev))]
-fn find_free_port() -> u16 {
-    // 1. Intentar el puerto preferido estable.
-    if TcpListener::bind(("127.0.0.1", PREFERRED_PORT)).is_ok() {
-        return PREFERRED_PORT;
-    }
-    eprintln!(
-        "[Stacklume] INFO: Puerto preferido {} ocupado, buscando puerto libre alternativo...",
-        PREFERRED_PORT
-    );
+fn on_ready_hook() -> Option<String> {
+    std::env::var("STACKLUME_ON_READY")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
 
-    // 2. Si está ocupado, pedir al OS un puerto libre aleatorio (hasta 50 intentos).
-    for _ in 0..50 {
-        if let Ok(listener) = TcpListener::bind("127.0.0.1:0") {
-            if let Ok(addr) = listener.local_addr() {
-                return addr.port();
-            }
-        }
-    }
+/// Margen que se da a node.exe para cerrar limpio (volcar el WAL de SQLite, cerrar
+/// conexiones) antes de matarlo (STACKLUME_SHUTDOWN_GRACE_MS, por defecto 3000;
+/// 0 = matar directamente).
+#[cfg(not(dev))]
+fn shutdown_grace_ms() -> u64 {
+    std::env::var("STACKLUME_SHUTDOWN_GRACE_MS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(3_000)
+}
 
-    // 3. Último recurso (extremadamente improbable).
-    eprintln!("[Stacklume] WARN: No se pudo obtener puerto libre tras 50 intentos, usando fallback 49152");
-    49152
+/// Tiempo mínimo que se mantiene la página de carga en el primer arranque
+/// (STACKLUME_MIN_SPLASH_MS, por defecto 0): si el servidor responde antes, se espera
+/// hasta cumplirlo para no ver un parpadeo del splash. Limitado a 5s.
+#[cfg(not(dev))]
+fn min_splash_ms() -> u64 {
+    std::env::var("STACKLUME_MIN_SPLASH_MS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        .min(5_000)
 }
 
-/// Espera hasta que el servidor Next.js responda en /api/health (máx 40 s).
-/// Devuelve true si el servidor respondió, false si hubo timeout.
+/// Arranque en caliente (STACKLUME_WARM_START=1): lanzar node y mostrar la ventana
+/// solo cuando el servidor responde, sin pasar por la página de carga.
 #[cfg(not(dev))]
-fn wait_for_server(port: u16) -> bool {
-    let url = format!("http://127.0.0.1:{}/api/health", port);
-    for _ in 0..80 {
-        match ureq::get(&url).call() {
-            Ok(resp) if resp.status() < 500 => return true,
-            _ => {}
+fn warm_start_enabled() -> bool {
+    env_flag("STACKLUME_WARM_START")
+}
+
+/// Consola visible para node.exe (STACKLUME_SHOW_CONSOLE=1): no se pasa
+/// CREATE_NO_WINDOW, para seguir el servidor en directo al diagnosticar. Solo Windows.
+#[cfg(all(windows, any(test, not(dev))))]
+fn show_server_console() -> bool {
+    env_flag("STACKLUME_SHOW_CONSOLE")
+}
+
+/// Herramientas de desarrollo del webview. Tauri solo las incluye en builds debug
+/// (en release haría falta la feature "devtools", que no activamos).
+fn devtools_enabled() -> bool {
+    cfg!(debug_assertions)
+}
+
+/// Prioridad de CPU de node.exe respecto al resto del sistema
+/// (STACKLUME_SERVER_PRIORITY=low|normal|high, por defecto normal).
+#[cfg(not(dev))]
+#[derive(Clone, Copy, PartialEq)]
+enum ServerPriority {
+    Low,
+    Normal,
+    High,
+}
+
+#[cfg(not(dev))]
+impl ServerPriority {
+    fn from_env() -> Self {
+        match std::env::var("STACKLUME_SERVER_PRIORITY") {
+            Err(_) => ServerPriority::Normal,
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "low" => ServerPriority::Low,
+                "normal" | "" => ServerPriority::Normal,
+                "high" => ServerPriority::High,
+                other => {
+                    eprintln!(
+                        "[Stacklume] WARN: STACKLUME_SERVER_PRIORITY='{}' no válido, usando 'normal'",
+                        other
+                    );
+                    ServerPriority::Normal
+                }
+            },
         }
-        std::thread::sleep(std::time::Duration::from_millis(500));
     }
-    false
-}
 
-/// Busca cualquier puerto TCP libre (sin puerto preferido específico)
-fn find_any_free_port() -> u16 {
-    for _ in 0..50 {
-        if let Ok(listener) = TcpListener::bind("127.0.0.1:0") {
-            if let Ok(addr) = listener.local_addr() {
-                return addr.port();
-            }
+    fn as_str(self) -> &'static str {
+        match self {
+            ServerPriority::Low => "low",
+            ServerPriority::Normal => "normal",
+            ServerPriority::High => "high",
         }
     }
-    7881
 }
 
-/// Espera hasta que llama-server responda al health check.
-/// Usa TCP raw + HTTP manual en vez de ureq (que tiene problemas en producción).
-fn wait_for_llama_server(port: u16) -> bool {
-    use std::io::{Read, Write};
-    use std::net::TcpStream;
+/// Valida un identificador de app usado como nombre de directorio:
+/// 1-64 caracteres [A-Za-z0-9._-], sin empezar por '.' ni contener "..".
+fn is_safe_app_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && !id.starts_with('.')
+        && !id.contains("..")
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
 
-    let addr = format!("127.0.0.1:{}", port);
-    // 360 × 500ms = 180 segundos (3 min)
-    for _ in 0..360 {
-        if let Ok(mut stream) = TcpStream::connect_timeout(
-            &addr.parse().unwrap(),
-            std::time::Duration::from_secs(2),
-        ) {
-            let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(2)));
-            let req = format!("GET /health HTTP/1.0\r\nHost: 127.0.0.1:{}\r\n\r\n", port);
-            if stream.write_all(req.as_bytes()).is_ok() {
-                let mut buf = [0u8; 512];
-                if let Ok(n) = stream.read(&mut buf) {
-                    let resp = String::from_utf8_lossy(&buf[..n]);
-                    if resp.contains("200") || resp.contains("ok") {
-                        return true;
-                    }
+/// Identificador alternativo para el subdirectorio de datos (STACKLUME_APP_ID).
+/// Permite que varias variantes/forks convivan sin recompilar. Solo con dev overrides.
+/// Se resuelve una única vez por proceso.
+fn app_id_override() -> Option<&'static str> {
+    static APP_ID: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+    APP_ID
+        .get_or_init(|| {
+            let id = std::env::var("STACKLUME_APP_ID").ok()?;
+            let id = id.trim();
+            if id.is_empty() {
+                return None;
+            }
+            if !dev_overrides_enabled() {
+                eprintln!("[Stacklume] WARN: STACKLUME_APP_ID ignorado (requiere STACKLUME_ENABLE_DEV_OVERRIDES=1)");
+                return None;
+            }
+            if !is_safe_app_id(id) {
+                eprintln!("[Stacklume] WARN: STACKLUME_APP_ID inválido: {:?}", id);
+                return None;
+            }
+            Some(id.to_string())
+        })
+        .as_deref()
+}
+
+/// Argumentos de línea de comandos que sustituyen a la configuración por entorno
+/// (p.ej. para tests automatizados): `--port 4567` y `--data-dir C:\test\data`,
+/// también con `=`. Prioridad: CLI > variables de entorno > valores por defecto.
+#[derive(Default)]
+struct CliArgs {
+    port: Option<u16>,
+    data_dir: Option<std::path::PathBuf>,
+}
+
+/// `CliArgs` del proceso, leídos una única vez. Un valor no válido se ignora con aviso.
+fn cli_args() -> &'static CliArgs {
+    static ARGS: std::sync::OnceLock<CliArgs> = std::sync::OnceLock::new();
+    ARGS.get_or_init(|| {
+        let mut parsed = CliArgs::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) => (f.to_string(), Some(v.to_string())),
+                None => (arg, None),
+            };
+            if flag != "--port" && flag != "--data-dir" {
+                continue;
+            }
+            let Some(value) = inline.or_else(|| args.next()) else {
+                eprintln!("[Stacklume] WARN: {} sin valor, ignorado", flag);
+                continue;
+            };
+            if flag == "--port" {
+                match value.trim().parse::<u16>() {
+                    Ok(port) if port != 0 => parsed.port = Some(port),
+                    _ => eprintln!("[Stacklume] WARN: --port '{}' no válido, ignorado", value),
                 }
+            } else if value.trim().is_empty() {
+                eprintln!("[Stacklume] WARN: --data-dir vacío, ignorado");
+            } else {
+                // Relativa al directorio actual, para no depender del cwd de cada uso
+                let dir = std::path::PathBuf::from(value.trim());
+                parsed.data_dir = Some(if dir.is_absolute() {
+                    dir
+                } else {
+                    std::env::current_dir().unwrap_or_default().join(dir)
+                });
             }
         }
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        parsed
+    })
+}
+
+/// Directorio de datos preferido: normalmente `app_data_dir()` (derivado del
+/// identifier de tauri.conf.json); con STACKLUME_APP_ID se usa `data_dir()/<id>`
+/// en su lugar y con --data-dir, que tiene prioridad, esa ruta tal cual.
+fn preferred_data_dir(app: &tauri::AppHandle) -> tauri::Result<std::path::PathBuf> {
+    if let Some(dir) = &cli_args().data_dir {
+        return Ok(dir.clone());
+    }
+    match app_id_override() {
+        Some(id) => app.path().data_dir().map(|d| d.join(id)),
+        None => app.path().app_data_dir(),
     }
-    false
 }
 
-/// Detecta cuántas GPU layers usar.
-/// Solo usa GPU si ggml-cuda.dll existe junto al binario de llama-server.
-fn detect_gpu_layers(binary_path: &str, model_path: &str, llm_log: &dyn Fn(&str)) -> String {
-    use std::process::Command;
+/// Crea `dir` si hace falta y comprueba que se puede escribir en él con un archivo
+/// de prueba (que se borra). Err explica por qué no sirve.
+fn ensure_writable_dir(dir: &std::path::Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("no se pudo crear {}: {}", dir.display(), e))?;
+    let probe = dir.join(".stacklume-write-test");
+    std::fs::write(&probe, b"").map_err(|e| format!("{} no admite escritura: {}", dir.display(), e))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
 
-    let clean_path = binary_path.strip_prefix(r"\\?\").unwrap_or(binary_path);
-    let llama_dir = std::path::Path::new(clean_path).parent();
-    let cuda_path = llama_dir.map(|d| d.join("ggml-cuda.dll"));
-    let has_cuda = cuda_path.as_ref().map(|p| p.exists()).unwrap_or(false);
-    let has_vulkan = llama_dir.map(|d| d.join("ggml-vulkan.dll").exists()).unwrap_or(false);
+/// Resultado de elegir el directorio de datos (ver `resolve_data_dir`).
+struct DataDirResolution {
+    /// Primer candidato con permiso de escritura (None si ninguno lo tiene)
+    dir: Option<std::path::PathBuf>,
+    /// Por qué se descartó cada candidato anterior, para dejarlo en el log
+    rejected: Vec<String>,
+}
 
-    llm_log(&format!("detect_gpu: binary={} dir={:?} cuda={} vulkan={}",
-        clean_path, llama_dir.map(|d| d.display().to_string()), has_cuda, has_vulkan));
+/// Elige una sola vez el directorio de datos: el preferido y, si no se puede resolver
+/// o no admite escritura, %LOCALAPPDATA%\<identifier> y después el directorio
+/// temporal. Nunca el directorio actual, que en una app instalada suele ser
+/// System32 o una carpeta de solo lectura.
+fn resolve_data_dir(app: &tauri::AppHandle) -> &'static DataDirResolution {
+    static RESOLVED: std::sync::OnceLock<DataDirResolution> = std::sync::OnceLock::new();
+    RESOLVED.get_or_init(|| {
+        let identifier = &app.config().identifier;
+        let mut rejected = Vec::new();
+        let mut candidates = Vec::new();
+        match preferred_data_dir(app) {
+            Ok(dir) => candidates.push(dir),
+            Err(e) => rejected.push(format!("app_data_dir no disponible: {}", e)),
+        }
+        if let Some(local) = std::env::var_os("LOCALAPPDATA").filter(|v| !v.is_empty()) {
+            candidates.push(std::path::PathBuf::from(local).join(identifier));
+        }
+        candidates.push(std::env::temp_dir().join(identifier));
 
-    if !has_cuda && !has_vulkan {
-        llm_log("detect_gpu: no GPU backend found → ngl 0");
-        return "0".to_string();
+        for dir in candidates {
+            match ensure_writable_dir(&dir) {
+                Ok(()) => return DataDirResolution { dir: Some(dir), rejected },
+                Err(e) => rejected.push(e),
+            }
+        }
+        DataDirResolution { dir: None, rejected }
+    })
+}
+
+/// Directorio de datos de la app (DB, logs, ajustes): `preferred_data_dir` o, si no
+/// sirve, el primer respaldo escribible de `resolve_data_dir`.
+fn app_data_dir(app: &tauri::AppHandle) -> tauri::Result<std::path::PathBuf> {
+    let resolved = resolve_data_dir(app);
+    resolved.dir.clone().ok_or_else(|| {
+        tauri::Error::Io(std::io::Error::other(format!(
+            "ningún directorio de datos admite escritura ({})",
+            resolved.rejected.join("; ")
+        )))
+    })
+}
+
+/// De dónde salen el directorio de datos y el puerto, para dejarlo en el log.
+#[cfg(not(dev))]
+fn config_sources() -> (&'static str, &'static str) {
+    let data_dir = if cli_args().data_dir.is_some() {
+        "--data-dir"
+    } else if app_id_override().is_some() {
+        "STACKLUME_APP_ID"
+    } else {
+        "predeterminado"
+    };
+    let port = if cli_args().port.is_some() {
+        "--port"
+    } else if env_flag("STACKLUME_RANDOM_PORT") {
+        "STACKLUME_RANDOM_PORT"
+    } else if std::env::var_os("STACKLUME_PORT_START").is_some() {
+        "STACKLUME_PORT_START"
+    } else {
+        "predeterminado (o port.txt)"
+    };
+    (data_dir, port)
+}
+
+// ─── Visor de eventos de Windows ──────────────────────────────────────────────
+
+/// Escribe una entrada de error en el log Application del Visor de eventos
+/// (origen "Stacklume") para que la monitorización corporativa detecte el fallo.
+#[cfg(windows)]
+fn report_windows_event(message: &str) {
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    };
+
+    let source: Vec<u16> = "Stacklume".encode_utf16().chain(std::iter::once(0)).collect();
+    let text: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+    unsafe {
+        let handle = RegisterEventSourceW(std::ptr::null(), source.as_ptr());
+        if handle.is_null() {
+            return;
+        }
+        let strings = [text.as_ptr()];
+        ReportEventW(
+            handle,
+            EVENTLOG_ERROR_TYPE,
+            0,                    // categoría
+            1,                    // event id
+            std::ptr::null_mut(), // sin SID de usuario
+            1,
+            0,
+            strings.as_ptr(),
+            std::ptr::null(),
+        );
+        DeregisterEventSource(handle);
     }
+}
 
-    // Si hay backend CUDA, detectar VRAM y calcular layers
-    let mut nv_cmd = Command::new("C:\\Windows\\System32\\nvidia-smi.exe");
-    nv_cmd.args(["--query-gpu=memory.free", "--format=csv,noheader,nounits"]);
+/// Registra un fallo fatal del arranque en el Visor de eventos si el ajuste
+/// `windows_event_log` está activo (opt-in). No hace nada fuera de Windows.
+#[cfg(not(dev))]
+fn report_fatal_event(app: &tauri::AppHandle, summary: &str) {
     #[cfg(windows)]
-    {
-        use std::os::windows::process::CommandExt;
-        nv_cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
+    if load_settings(app).windows_event_log {
+        report_windows_event(&format!("Stacklume no pudo arrancar: {}", summary));
     }
-    let vram_free_mb = nv_cmd
-        .output()
-        .ok()
-        .filter(|o| o.status.success())
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .and_then(|s| s.lines().next().and_then(|l| l.trim().parse::<u64>().ok()))
-        .unwrap_or(0);
+    #[cfg(not(windows))]
+    let _ = (app, summary);
+}
 
-    if vram_free_mb == 0 {
-        return "0".to_string();
+// ─── Utilidades de producción ─────────────────────────────────────────────────
+
+use std::net::TcpListener;
+
+/// Puerto preferido estable para producción.
+/// Se usa siempre que esté disponible, garantizando URLs de MCP estables
+/// entre reinicios (p. ej. http://127.0.0.1:7879/api/mcp).
+/// El puerto de dev es 7878, así que usamos 7879 para producción.
+#[cfg(not(dev))]
+const PREFERRED_PORT: u16 = 7879;
+
+/// Rango contiguo de puertos que se prueba primero: STACKLUME_PORT_START (por defecto
+/// PREFERRED_PORT) y STACKLUME_PORT_COUNT (por defecto 1, solo el preferido).
+#[cfg(not(dev))]
+#[derive(Clone, Copy)]
+struct PortRange {
+    start: u16,
+    count: u16,
+}
+
+#[cfg(not(dev))]
+impl PortRange {
+    fn from_env() -> Self {
+        let start = match std::env::var("STACKLUME_PORT_START") {
+            Err(_) => PREFERRED_PORT,
+            Ok(v) => match v.trim().parse::<u16>() {
+                Ok(p) if p != 0 => p,
+                _ => {
+                    eprintln!(
+                        "[Stacklume] WARN: STACKLUME_PORT_START='{}' no válido, usando {}",
+                        v, PREFERRED_PORT
+                    );
+                    PREFERRED_PORT
+                }
+            },
+        };
+        let count = match std::env::var("STACKLUME_PORT_COUNT") {
+            Err(_) => 1,
+            Ok(v) => match v.trim().parse::<u16>() {
+                Ok(n) if n != 0 => n,
+                _ => {
+                    eprintln!("[Stacklume] WARN: STACKLUME_PORT_COUNT='{}' no válido, usando 1", v);
+                    1
+                }
+            },
+        };
+        // No pasar de 65535
+        let count = count.min(u16::MAX - start + 1);
+        PortRange { start, count }
     }
 
-    let model_size_mb = std::fs::metadata(model_path)
-        .map(|m| m.len() / (1024 * 1024))
-        .unwrap_or(0);
-    let needed_mb = (model_size_mb as f64 * 1.1) as u64 + 200;
+    /// Primer puerto tras el rango (o 65535 si llega al final)
+    fn end(&self) -> u16 {
+        self.start.saturating_add(self.count)
+    }
 
-    if vram_free_mb >= needed_mb {
-        "99".to_string()
-    } else if vram_free_mb > 500 {
-        let ratio = vram_free_mb as f64 / needed_mb as f64;
-        let layers = (ratio * 40.0).max(1.0).min(40.0) as u32;
-        layers.to_string()
-    } else {
-        "0".to_string()
+    fn ports(&self) -> impl Iterator<Item = u16> {
+        let (start, count) = (self.start, self.count);
+        (0..count).map(move |i| start + i)
     }
 }
 
-/// Arranca llama-server de forma síncrona (llamar desde un hilo background).
-fn spawn_llama_server_blocking(app: &tauri::AppHandle) -> Result<(), String> {
-    use std::process::{Command, Stdio};
-
-    // Log de LLM para diagnóstico (se limpia al arrancar)
-    let llm_log_path = app.path().app_data_dir().unwrap_or_default().join("llm.log");
-    let _ = std::fs::write(&llm_log_path, ""); // Limpiar al inicio
-    let llm_log = |msg: &str| {
-        use std::io::Write;
-        if let Ok(mut f) = std::fs::OpenOptions::new().create(true).append(true).open(&llm_log_path) {
-            let ts = chrono_lite();
-            let _ = writeln!(f, "[{}] {}", ts, msg);
+#[cfg(not(dev))]
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 1 {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.start + (self.count - 1))
         }
-    };
+    }
+}
 
-    let binary_path;
-    let model_path;
-    let port;
+/// Qué hacer cuando los puertos de `PortRange` están ocupados (STACKLUME_PORT_EXHAUSTED):
+/// - `ephemeral` (por defecto): pedir al OS un puerto libre aleatorio (bind a `:0`)
+/// - `widen`: probar en orden los WIDEN_PORT_SPAN puertos siguientes al rango
+/// - `error`: no buscar alternativa y mostrar la página de error
+#[cfg(not(dev))]
+#[derive(Clone, Copy)]
+enum PortExhausted {
+    Error,
+    Ephemeral,
+    Widen,
+}
 
-    {
-        let state = app.state::<LlamaState>();
-        // Strip \\?\ prefix que Tauri añade en producción — causa problemas con
-        // DLL loading de CUDA (ggml-cuda.dll busca cublas64_12.dll en el directorio
-        // del exe, pero con \\?\ prefix el directorio no se resuelve correctamente)
-        binary_path = state
-            .binary_path
-            .lock()
-            .unwrap()
-            .clone()
-            .map(|p| p.strip_prefix(r"\\?\").unwrap_or(&p).to_string())
-            .ok_or_else(|| "llama-server no disponible".to_string())?;
-        model_path = state
-            .model_path
-            .lock()
-            .unwrap()
-            .clone()
-            .map(|p| p.strip_prefix(r"\\?\").unwrap_or(&p).to_string())
-            .ok_or_else(|| "Modelo no configurado".to_string())?;
-        port = *state.port.lock().unwrap();
+#[cfg(not(dev))]
+impl PortExhausted {
+    fn from_env() -> Self {
+        match std::env::var("STACKLUME_PORT_EXHAUSTED") {
+            Err(_) => PortExhausted::Ephemeral,
+            Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "error" => PortExhausted::Error,
+                "ephemeral" | "" => PortExhausted::Ephemeral,
+                "widen" => PortExhausted::Widen,
+                other => {
+                    eprintln!(
+                        "[Stacklume] WARN: STACKLUME_PORT_EXHAUSTED='{}' no válido, usando 'ephemeral'",
+                        other
+                    );
+                    PortExhausted::Ephemeral
+                }
+            },
+        }
     }
+}
 
-    if port == 0 {
-        return Err("Puerto llama-server no asignado".to_string());
-    }
+/// Puertos probados tras el rango con STACKLUME_PORT_EXHAUSTED=widen.
+#[cfg(not(dev))]
+const WIDEN_PORT_SPAN: u16 = 100;
 
-    let ngl = detect_gpu_layers(&binary_path, &model_path, &llm_log);
+/// Puerto aleatorio por sesión (STACKLUME_RANDOM_PORT=1): se pide al OS un puerto
+/// efímero en vez de usar el recordado o el rango estable, para que no sea predecible.
+#[cfg(not(dev))]
+fn random_port_enabled() -> bool {
+    env_flag("STACKLUME_RANDOM_PORT")
+}
 
+/// Archivo de app_data donde se recuerda el último puerto del servidor.
+#[cfg(not(dev))]
+fn last_port_path(app_data: &std::path::Path) -> std::path::PathBuf {
+    app_data.join("port.txt")
+}
 
-    // Detectar número de CPUs lógicos para --threads (mínimo 2, máximo 8)
-    let n_threads = std::thread::available_parallelism()
-        .map(|n| n.get().min(8).max(2))
-        .unwrap_or(4)
-        .to_string();
-
-    // Detectar familia del modelo para auto-configurar parámetros del servidor
-    let model_filename = std::path::Path::new(&model_path)
-        .file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-    let family = detect_model_family(&model_filename);
-
-    // Parámetros de llama-server adaptados por familia de modelo.
-    // Los parámetros de sampling (temp, top_k, etc.) se envían per-request desde route.ts,
-    // pero ctx-size, n-predict, jinja y reasoning son configuraciones del servidor.
-    let (ctx_size, n_predict, use_jinja, reasoning_mode, default_temp, default_top_k, default_top_p) = match family {
-        "qwen3" => ("16384", "2048", true, "auto", "0.7", "20", "0.8"),
-        "qwen2" => ("8192",  "2048", true, "off",  "0.7", "20", "0.8"),
-        "llama3" => ("8192",  "2048", true, "off",  "0.6", "40", "0.9"),
-        "mistral" => ("8192", "2048", true, "off",  "0.7", "50", "0.9"),
-        "phi" =>    ("4096",  "1024", true, "off",  "0.7", "40", "0.9"),
-        "gemma" =>  ("8192",  "2048", true, "off",  "0.7", "40", "0.9"),
-        "deepseek" => ("16384","4096", true, "auto", "0.6", "20", "0.95"),
-        _ =>        ("4096",  "1024", true, "off",  "0.7", "40", "0.9"),
-    };
+/// Último puerto usado por el servidor (port.txt), si hay uno válido.
+#[cfg(not(dev))]
+fn load_last_port(app_data: &std::path::Path) -> Option<u16> {
+    std::fs::read_to_string(last_port_path(app_data))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|p| *p != 0)
+}
 
-    // Usar ctx-size de la familia directamente (llama.cpp ajusta automáticamente si no cabe)
-    let effective_ctx = ctx_size;
+/// Recuerda `port` en port.txt para reutilizarlo en el próximo arranque.
+#[cfg(not(dev))]
+fn save_last_port(app_data: &std::path::Path, port: u16) {
+    let _ = std::fs::write(last_port_path(app_data), port.to_string());
+}
 
-    llm_log(&format!("binary: {}", binary_path));
-    llm_log(&format!("model: {}", model_path));
-    llm_log(&format!("port: {} | ngl: {} ({}) | ctx: {}", port, ngl, if ngl == "0" { "CPU" } else { "GPU" }, effective_ctx));
+/// true si se puede abrir `port` en `bind_host()` (donde escuchará node) ahora mismo
+/// (el listener se suelta enseguida, así que solo vale como comprobación puntual).
+/// El 0 nunca es libre: bind lo aceptaría asignando uno cualquiera.
+fn port_is_free(port: u16) -> bool {
+    port != 0 && TcpListener::bind((bind_host(), port)).is_ok()
+}
 
-    // Detectar proyector multimodal (mmproj) para soporte de visión
-    // Solo usar mmproj si el modelo activo es compatible (Qwen3.5-2B).
-    // El mmproj-F16.gguf descargado es específico del 2B — cargarlo con
-    // otro modelo (4B, 0.8B) causa crash silencioso de llama-server.
-    let model_filename_lower = std::path::Path::new(&model_path)
-        .file_name().unwrap_or_default().to_string_lossy().to_lowercase();
-    let mmproj_path: Option<std::path::PathBuf> = if model_filename_lower.contains("2b") {
-        std::path::Path::new(&model_path)
-            .parent()
-            .map(|d| d.join("mmproj-F16.gguf"))
-            .filter(|p| p.exists())
-    } else {
-        None
-    };
+/// Busca un puerto TCP libre intentando primero --port (ver `cli_args`), después
+/// `last_port` (el del arranque anterior, para que las URLs guardadas sigan
+/// funcionando), luego `PortRange` (por defecto PREFERRED_PORT, estable entre
+/// reinicios para que las configuraciones MCP en Claude Desktop / Cursor no se
+/// rompan) y, si está ocupado, según `PortExhausted::from_env()`.
+/// Devuelve None si no hay ningún puerto utilizable.
+#[cfg(not(dev))]
+fn find_free_port(last_port: Option<u16>) -> Option<u16> {
+    // --port manda sobre todo lo demás mientras esté libre
+    if let Some(port) = cli_args().port {
+        if port_is_free(port) {
+            return Some(port);
+        }
+        eprintln!("[Stacklume] WARN: Puerto {} de --port ocupado, buscando otro...", port);
+    }
+    if random_port_enabled() {
+        return ephemeral_port();
+    }
+    let range = PortRange::from_env();
 
-    let mut cmd = Command::new(&binary_path);
-    cmd.arg("--model").arg(&model_path)
-        .arg("--host").arg("127.0.0.1")
-        .arg("--port").arg(port.to_string())
-        .arg("--ctx-size").arg(effective_ctx)
-        .arg("-ngl").arg(&ngl)
-        .arg("--threads").arg(&n_threads)
-        .arg("--threads-batch").arg(&n_threads)
-        .arg("--n-predict").arg(n_predict)
-        .arg("--temp").arg(default_temp)
-        .arg("--top-k").arg(default_top_k)
-        .arg("--top-p").arg(default_top_p)
-        .arg("--min-p").arg("0")
-        .arg("--no-context-shift")
-        .arg("--log-disable")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null());
+    // 0. Reutilizar el puerto del arranque anterior si sigue libre.
+    if let Some(last) = last_port.filter(|p| *p != range.start) {
+        if port_is_free(last) {
+            return Some(last);
+        }
+        eprintln!("[Stacklume] INFO: Puerto anterior {} ocupado, buscando otro...", last);
+    }
 
-    // CRÍTICO: establecer current_dir al directorio de llama-server para que
-    // Windows cargue NUESTRAS DLLs de CUDA bundleadas (no las del CUDA Toolkit del sistema).
-    // Windows busca DLLs en: 1) directorio del exe, 2) current directory, 3) System32, 4) PATH.
-    // Con current_dir apuntando al directorio del exe, nuestras DLLs tienen máxima prioridad.
-    if let Some(llama_dir) = std::path::Path::new(&binary_path).parent() {
-        cmd.current_dir(llama_dir);
-        llm_log(&format!("current_dir: {}", llama_dir.display()));
+    // 1. Recorrer el rango configurado (por defecto solo el puerto preferido estable).
+    if let Some(port) = range.ports().find(|p| port_is_free(*p)) {
+        return Some(port);
     }
+    eprintln!(
+        "[Stacklume] INFO: Puertos {} ocupados, buscando puerto libre alternativo...",
+        range
+    );
 
-    if use_jinja { cmd.arg("--jinja"); }
-    cmd.arg("--reasoning").arg(reasoning_mode);
-    if let Some(ref mp) = mmproj_path { cmd.arg("--mmproj").arg(mp); }
+    match PortExhausted::from_env() {
+        PortExhausted::Error => None,
 
-    #[cfg(windows)]
-    {
-        use std::os::windows::process::CommandExt;
-        // Tauri es GUI subsystem → procesos hijos no pueden inicializar CUDA sin consola.
-        // AllocConsole crea una consola temporal, pero CREATE_NO_WINDOW la bloquea.
-        // Solución: solo ABOVE_NORMAL_PRIORITY. La consola se oculta porque
-        // stdout/stderr van a Stdio::null(). No aparece ventana visible.
-        cmd.creation_flags(0x00008000); // ABOVE_NORMAL_PRIORITY_CLASS
-    }
+        // 2a. Probar en orden los puertos siguientes al rango.
+        PortExhausted::Widen => (0..WIDEN_PORT_SPAN)
+            .filter_map(|i| range.end().checked_add(i))
+            .find(|p| port_is_free(*p)),
 
-    // Matar CUALQUIER llama-server previo antes de spawnar (evitar duplicados)
-    #[cfg(windows)]
-    {
-        let _ = silent_command("taskkill")
-            .args(["/f", "/im", "llama-server.exe"])
-            .output();
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        // 2b. Pedir al OS un puerto libre aleatorio.
+        PortExhausted::Ephemeral => ephemeral_port(),
     }
+}
 
-    // Spawnar llama-server via un .bat wrapper que crea su propia consola.
-    // Tauri es GUI app sin consola → CUDA falla con Stdio::null/inherit/piped.
-    // Un .bat siempre crea su propia consola de forma nativa.
-    let wrapper_path = app.path().app_data_dir().unwrap_or_default().join("_llama_run.bat");
-    {
-        let mut bat_content = format!(
-            "@echo off\r\n\"{binary}\" --model \"{model}\" --host 127.0.0.1 --port {port} --ctx-size {ctx} -ngl {ngl} --threads {threads} --threads-batch {threads} --n-predict {predict} --temp {temp} --top-k {topk} --top-p {topp} --min-p 0 --no-context-shift --log-disable",
-            binary = binary_path,
-            model = model_path,
-            port = port,
-            ctx = effective_ctx,
-            ngl = ngl,
-            threads = n_threads,
-            predict = n_predict,
-            temp = default_temp,
-            topk = default_top_k,
-            topp = default_top_p,
-        );
-        if use_jinja { bat_content.push_str(" --jinja"); }
-        bat_content.push_str(&format!(" --reasoning {}", reasoning_mode));
-        if let Some(ref mp) = mmproj_path { bat_content.push_str(&format!(" --mmproj \"{}\"", mp.display())); }
-        bat_content.push_str("\r\n");
-        let _ = std::fs::write(&wrapper_path, &bat_content);
-        llm_log(&format!("wrapper: {}", wrapper_path.display()));
+/// Pide al OS un puerto libre aleatorio (bind a `:0`, hasta 50 intentos) en la misma
+/// interfaz que usará node (`bind_host`), como `port_is_free`.
+#[cfg(not(dev))]
+fn ephemeral_port() -> Option<u16> {
+    for _ in 0..50 {
+        if let Ok(listener) = TcpListener::bind((bind_host(), 0)) {
+            if let Ok(addr) = listener.local_addr() {
+                return Some(addr.port());
+            }
+        }
     }
+    eprintln!("[Stacklume] WARN: No se pudo obtener puerto libre tras 50 intentos");
+    None
+}
 
-    let mut cmd = Command::new("cmd.exe");
-    cmd.arg("/c").arg(&wrapper_path)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null());
-    if let Some(llama_dir) = std::path::Path::new(&binary_path).parent() {
-        cmd.current_dir(llama_dir);
+/// Token de sesión: 32 bytes aleatorios del OS en hexadecimal. Si el generador
+/// del sistema falla se deriva de la hora y el PID (peor, pero nunca vacío).
+fn generate_session_token() -> String {
+    use std::fmt::Write;
+    let mut bytes = [0u8; 32];
+    if let Err(e) = getrandom::getrandom(&mut bytes) {
+        eprintln!("[Stacklume] WARN: getrandom falló ({}), token de sesión de respaldo", e);
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            ^ ((std::process::id() as u128) << 64);
+        bytes[..16].copy_from_slice(&seed.to_le_bytes());
+        bytes[16..].copy_from_slice(&seed.rotate_left(37).to_be_bytes());
     }
+    bytes.iter().fold(String::with_capacity(64), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
 
-    #[cfg(windows)]
-    {
-        use std::os::windows::process::CommandExt;
-        cmd.creation_flags(0x08000000 | 0x00008000); // CREATE_NO_WINDOW + ABOVE_NORMAL
+/// Timeout de arranque por defecto y máximo admitido en STACKLUME_STARTUP_TIMEOUT_MS.
+const DEFAULT_STARTUP_TIMEOUT_MS: u64 = 40_000;
+const MAX_STARTUP_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+
+/// Tiempo máximo esperando al servidor (STACKLUME_STARTUP_TIMEOUT_MS, por defecto
+/// 40 s). Valores fuera de [1 s, 10 min] se recortan a ese rango.
+fn startup_timeout_ms() -> u64 {
+    match std::env::var("STACKLUME_STARTUP_TIMEOUT_MS").ok().map(|v| v.trim().parse::<u64>()) {
+        None => DEFAULT_STARTUP_TIMEOUT_MS,
+        Some(Ok(ms)) => ms.clamp(1_000, MAX_STARTUP_TIMEOUT_MS),
+        Some(Err(_)) => {
+            eprintln!("[Stacklume] WARN: STACKLUME_STARTUP_TIMEOUT_MS no es un número, usando 40000");
+            DEFAULT_STARTUP_TIMEOUT_MS
+        }
     }
+}
 
-    llm_log("Spawning llama-server via wrapper...");
-    match cmd.spawn() {
-        Ok(mut child) => {
-            let pid = child.id();
-            llm_log(&format!("wrapper spawned: PID {}", pid));
+/// Configuración efectiva del health check del servidor Next.js.
+/// Es la única fuente de verdad: la usa `wait_for_server` y la expone `health_config`.
+#[derive(serde::Serialize, Clone)]
+struct HealthConfig {
+    scheme: String,
+    host: String,
+    /// Ruta del endpoint de salud (siempre con '/' inicial)
+    path: String,
+    /// Tiempo máximo total esperando a que el servidor responda
+    timeout_ms: u64,
+    /// Primer intervalo entre sondeos; se duplica tras cada fallo (backoff exponencial)
+    poll_ms: u64,
+    /// Tope del intervalo entre sondeos
+    max_poll_ms: u64,
+    /// Tiempo máximo para conectar en cada sondeo: con el puerto aún cerrado algunas
+    /// pilas tardan segundos en rechazar la conexión y el ritmo del bucle se perdería
+    connect_timeout_ms: u64,
+    /// Tiempo máximo esperando la respuesta de cada sondeo
+    read_timeout_ms: u64,
+    /// "http" (GET al endpoint de salud) o "tcp" (basta con que el puerto acepte
+    /// conexiones), según STACKLUME_READY_PROBE
+    probe: &'static str,
+}
 
-            #[cfg(windows)]
-            {
-                let job = create_job_for_child(pid);
-                if job != 0 {
-                    *app.state::<LlamaState>().llama_job.lock().unwrap() = job;
-                }
+impl HealthConfig {
+    fn resolve() -> Self {
+        let probe = match std::env::var("STACKLUME_READY_PROBE") {
+            Ok(v) if v.trim().eq_ignore_ascii_case("tcp") => "tcp",
+            Ok(v) if !v.trim().is_empty() && !v.trim().eq_ignore_ascii_case("http") => {
+                eprintln!("[Stacklume] WARN: STACKLUME_READY_PROBE='{}' no válido, usando 'http'", v);
+                "http"
             }
+            _ => "http",
+        };
+        // STACKLUME_HEALTH_PATH: ruta de readiness propia (p.ej. "healthz" o "/healthz")
+        let path = std::env::var("STACKLUME_HEALTH_PATH")
+            .ok()
+            .map(|p| p.trim().trim_start_matches('/').to_string())
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("/{}", p))
+            .unwrap_or_else(|| "/api/health".to_string());
+        HealthConfig {
+            scheme: if use_tls() { "https" } else { "http" }.to_string(),
+            host: connect_host(),
+            path,
+            timeout_ms: startup_timeout_ms(),
+            poll_ms: 100,
+            max_poll_ms: 2_000,
+            connect_timeout_ms: 300,
+            read_timeout_ms: 1_000,
+            probe,
+        }
+    }
 
-            #[cfg(not(dev))]
-            {
-                *app.state::<LlamaState>().llama_child.lock().unwrap() = Some(child);
-            }
+    fn url(&self, port: u16) -> String {
+        format!("{}://{}:{}{}", self.scheme, self.host, port, self.path)
+    }
 
-            // Esperar a que llama-server arranque (el wrapper cmd.exe lo lanza)
-            std::thread::sleep(std::time::Duration::from_secs(3));
+    /// Cliente del health check. Con https acepta el certificado autofirmado del
+    /// servidor empaquetado, pero solo para 127.0.0.1/localhost (`LocalhostCertVerifier`).
+    fn agent(&self) -> ureq::Agent {
+        self.build_agent(ureq::AgentBuilder::new())
+    }
 
-            // Health check polling
-            llm_log("Health check polling...");
-            let health_ok = wait_for_llama_server(port);
+    /// Cliente de los sondeos de `wait_for_server`, con timeouts cortos de conexión y
+    /// lectura para que un intento fallido vuelva enseguida. Se crea uno por espera y
+    /// se reutiliza en todos los sondeos.
+    #[cfg(not(dev))]
+    fn probe_agent(&self) -> ureq::Agent {
+        self.build_agent(
+            ureq::AgentBuilder::new()
+                .timeout_connect(std::time::Duration::from_millis(self.connect_timeout_ms))
+                .timeout_read(std::time::Duration::from_millis(self.read_timeout_ms)),
+        )
+    }
 
-            if health_ok {
-                llm_log("llama-server READY");
-                *app.state::<LlamaState>().status.lock().unwrap() = "ready".to_string();
-                let _ = app.emit("llm:status-changed", "ready");
-            } else {
-                llm_log("TIMEOUT: llama-server no respondió en 120s");
-                let msg = "error: llama-server no respondió en 120s — prueba un modelo más pequeño".to_string();
-                *app.state::<LlamaState>().status.lock().unwrap() = msg.clone();
-                let _ = app.emit("llm:status-changed", msg.clone());
-                return Err(msg);
-            }
+    fn build_agent(&self, builder: ureq::AgentBuilder) -> ureq::Agent {
+        if self.scheme != "https" {
+            return builder.build();
         }
-        Err(e) => {
-            llm_log(&format!("SPAWN ERROR: {}", e));
-            *app.state::<LlamaState>().status.lock().unwrap() = "error".to_string();
-            let _ = app.emit("llm:status-changed", format!("error: {}", e));
-            return Err(e.to_string());
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = LocalhostCertVerifier { provider: provider.clone() };
+        match rustls::ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions() {
+            Ok(tls) => builder
+                .tls_config(std::sync::Arc::new(
+                    tls.dangerous()
+                        .with_custom_certificate_verifier(std::sync::Arc::new(verifier))
+                        .with_no_client_auth(),
+                ))
+                .build(),
+            Err(e) => {
+                eprintln!("[Stacklume] WARN: configuración TLS no válida ({}), verificación estándar", e);
+                builder.build()
+            }
         }
     }
+}
 
-    Ok(())
+/// Dirección en la que escucha node (STACKLUME_BIND_HOST, por defecto 127.0.0.1),
+/// p. ej. 0.0.0.0 para abrir Stacklume desde el móvil en una red de confianza.
+/// Un valor que no sea una IP se ignora con un aviso.
+fn bind_host() -> std::net::IpAddr {
+    static HOST: std::sync::OnceLock<std::net::IpAddr> = std::sync::OnceLock::new();
+    *HOST.get_or_init(|| {
+        let loopback = std::net::IpAddr::from([127, 0, 0, 1]);
+        match std::env::var("STACKLUME_BIND_HOST") {
+            Ok(v) if !v.trim().is_empty() => v.trim().parse().unwrap_or_else(|_| {
+                eprintln!("[Stacklume] WARN: STACKLUME_BIND_HOST='{}' no es una IP, usando 127.0.0.1", v);
+                loopback
+            }),
+            _ => loopback,
+        }
+    })
 }
 
-/// Resuelve la ruta de un recurso empaquetado.
-/// Prueba `resource_dir/subpath` y `resource_dir/resources/subpath`.
-#[cfg(not(dev))]
-fn resolve_resource(resource_dir: &std::path::Path, subpath: &str) -> std::path::PathBuf {
-    let direct = resource_dir.join(subpath);
-    if direct.exists() {
-        return direct;
-    }
-    let with_prefix = resource_dir.join("resources").join(subpath);
-    if with_prefix.exists() {
-        return with_prefix;
-    }
-    direct // fallback — el error se reportará después
+/// true si node queda accesible desde otras máquinas (bind fuera de loopback).
+/// Entonces el servidor exige el token de sesión (STACKLUME_REQUIRE_SESSION_TOKEN).
+#[cfg(any(test, not(dev)))]
+fn server_exposed() -> bool {
+    !bind_host().is_loopback()
 }
 
-/// Escribe una línea al archivo de log de la aplicación.
-#[cfg(not(dev))]
-fn log(path: &std::path::Path, msg: &str) {
-    use std::io::Write;
-    if let Ok(mut f) = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)
-    {
-        let _ = writeln!(f, "{}", msg);
+/// Host con el que el propio shell conecta con node (health check y navegación):
+/// 127.0.0.1 salvo que node escuche solo en una IP concreta que no sea loopback.
+fn connect_host() -> String {
+    match bind_host() {
+        ip if ip.is_loopback() || ip.is_unspecified() => "127.0.0.1".to_string(),
+        std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+        ip => ip.to_string(),
     }
 }
 
-// ─── Comandos Tauri ───────────────────────────────────────────────────────────
+/// Servidor detrás de TLS (STACKLUME_USE_TLS=1): el health check y la navegación usan
+/// https://127.0.0.1:puerto. Para funciones que exigen contexto seguro (WebCrypto…).
+fn use_tls() -> bool {
+    env_flag("STACKLUME_USE_TLS")
+}
 
-/// Descarga el instalador de actualización desde GitHub y lo ejecuta.
-/// No usa tauri-plugin-updater (evita ACL plugin:updater|check).
-/// Funciona desde cualquier versión de la app que tenga este comando.
-#[tauri::command]
-async fn download_and_run_update(app: tauri::AppHandle, url: String) -> Result<(), String> {
-    // Validar URL con parsing estricto — previene path traversal y esquemas maliciosos
-    let parsed = url::Url::parse(&url).map_err(|e| format!("URL mal formada: {}", e))?;
+/// Acepta cualquier certificado, pero solo si el destino es el servidor local
+/// (127.0.0.1, ::1 o localhost): el certificado autofirmado empaquetado no encadena a
+/// ninguna CA y la conexión nunca sale de la máquina. Las firmas del handshake sí se
+/// comprueban, con los algoritmos del proveedor.
+#[derive(Debug)]
+struct LocalhostCertVerifier {
+    provider: std::sync::Arc<rustls::crypto::CryptoProvider>,
+}
 
-    // Solo HTTPS desde nuestro repo exacto
-    if parsed.scheme() != "https" {
-        return Err("Solo se permiten URLs HTTPS".to_string());
-    }
-    if parsed.host_str() != Some("github.com") {
-        return Err("Solo se permiten descargas desde github.com".to_string());
+impl rustls::client::danger::ServerCertVerifier for LocalhostCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let local = match server_name {
+            rustls::pki_types::ServerName::IpAddress(ip) => std::net::IpAddr::from(*ip).is_loopback(),
+            rustls::pki_types::ServerName::DnsName(name) => name.as_ref().eq_ignore_ascii_case("localhost"),
+            _ => false,
+        };
+        if local {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("{:?} no es el servidor local", server_name)))
+        }
     }
 
-    // Verificar path exacto: /SwonDev/Stacklume/releases/...
-    let path = parsed.path();
-    if !path.starts_with("/SwonDev/Stacklume/releases/") {
-        return Err("URL de actualización no válida".to_string());
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
     }
-    // Bloquear path traversal (.. en cualquier segmento)
-    if path.split('/').any(|seg| seg == "..") {
-        return Err("Path traversal detectado".to_string());
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
     }
-    // Debe terminar en .exe
-    if !path.to_lowercase().ends_with(".exe") {
-        return Err("Solo se permiten archivos .exe".to_string());
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
     }
+}
 
-    let installer_path = std::env::temp_dir().join("StacklumeUpdate.exe");
-    let installer_path_clone = installer_path.clone();
+/// Etapas del arranque que se muestran en la página de carga (`loading-status`).
+/// Solo avanzan: Starting → Responding → Slow → Ready.
+#[cfg(not(dev))]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LoadingStage {
+    /// node.exe lanzado, el puerto aún no responde
+    Starting,
+    /// El servidor responde, pero el health check todavía no da el visto bueno
+    Responding,
+    /// Ha pasado más de la mitad del timeout
+    Slow,
+    /// Health check OK: se navega al servidor
+    Ready,
+}
 
-    // Límite de descarga: 1 GB (el instalador con CUDA DLLs puede superar 500 MB)
-    const MAX_DOWNLOAD_SIZE: u64 = 1024 * 1024 * 1024;
-    // Tamaño mínimo esperado: un instalador NSIS válido siempre supera 1 MB.
-    const MIN_INSTALLER_SIZE: u64 = 1024 * 1024;
+#[cfg(not(dev))]
+impl LoadingStage {
+    fn as_str(self) -> &'static str {
+        match self {
+            LoadingStage::Starting => "starting",
+            LoadingStage::Responding => "responding",
+            LoadingStage::Slow => "slow",
+            LoadingStage::Ready => "ready",
+        }
+    }
 
-    // Descargar via curl con progreso — spawn + monitoreo del archivo por tamaño.
-    // curl -# escribe progreso a stderr pero CREATE_NO_WINDOW lo suprime.
-    // Alternativa: poll del tamaño del archivo cada segundo.
-    let app_clone = app.clone();
-    tokio::task::spawn_blocking(move || {
-        // Borrar archivo previo si existe
-        let _ = std::fs::remove_file(&installer_path_clone);
-        let out_path = installer_path_clone.to_string_lossy().to_string();
+    fn message(self) -> &'static str {
+        match self {
+            LoadingStage::Starting => "Iniciando servidor...",
+            LoadingStage::Responding => "Preparando la base de datos...",
+            LoadingStage::Slow => "Está tardando más de lo normal...",
+            LoadingStage::Ready => "Casi listo...",
+        }
+    }
+}
 
-        // Descargar con reintentos y resume automático.
-        // --retry 5 + -C - = si curl falla a mitad, reintenta continuando desde donde quedó.
-        // --retry-delay 3 = espera 3s entre reintentos.
-        // Sin --max-time (la descarga puede tardar >10 min con conexiones lentas).
-        let mut cmd = std::process::Command::new("C:\\Windows\\System32\\curl.exe");
-        cmd.arg("-L").arg("--fail")
-            .arg("--connect-timeout").arg("30")
-            .arg("--retry").arg("5")
-            .arg("--retry-delay").arg("3")
-            .arg("-C").arg("-") // Resume descargas parciales
-            .arg("-o").arg(&out_path)
-            .arg("-s")
-            .arg("--user-agent").arg("Stacklume/0.3 (desktop)")
-            .arg(&url);
-        #[cfg(windows)]
-        { use std::os::windows::process::CommandExt; cmd.creation_flags(0x08000000); }
+/// Publica una etapa del arranque: evento `loading-status` ({stage, message}) para
+/// la UI y, si la ventana sigue en la página de carga (data URL), reescribe su #status.
+#[cfg(not(dev))]
+fn emit_loading_status(app: &tauri::AppHandle, stage: LoadingStage) {
+    let _ = app.emit("loading-status", serde_json::json!({
+        "stage": stage.as_str(),
+        "message": stage.message(),
+    }));
+    if let Some(window) = app.get_webview_window("main") {
+        let text = serde_json::to_string(stage.message()).unwrap_or_default();
+        let _ = window.eval(format!(
+            "if(location.protocol==='data:'){{var s=document.getElementById('status');if(s)s.textContent={};}}",
+            text
+        ));
+    }
+}
 
-        // Spawn curl (no bloquear con output())
-        let mut child = cmd.spawn().map_err(|e| format!("Error ejecutando curl: {}", e))?;
+/// Resultado de `wait_for_server`.
+#[cfg(not(dev))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ServerWaitResult {
+    Ready,
+    /// Nunca hubo respuesta HTTP: el puerto no llegó a abrirse (o node cayó antes)
+    TimedOutNoConnection,
+    /// El servidor respondía, pero el health check devolvía error (último estado HTTP)
+    TimedOutUnhealthy(u16),
+}
 
-        // Monitor de progreso: poll tamaño del archivo cada 2 segundos
-        // Estimación: instalador ~625 MB (con CUDA DLLs)
-        const ESTIMATED_SIZE: u64 = 650_000_000;
-        loop {
-            std::thread::sleep(std::time::Duration::from_secs(2));
-            match child.try_wait() {
-                Ok(Some(status)) => {
-                    if !status.success() {
-                        let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
-                        return Err(format!(
-                            "Descarga falló (código {}). Descargados: {:.0} MB. Verifica tu conexión a internet.",
-                            status.code().unwrap_or(-1),
-                            file_size as f64 / 1_048_576.0
-                        ));
-                    }
-                    break; // curl terminó exitosamente
+/// Espera hasta que el servidor Next.js esté listo: GET a `HealthConfig::path`
+/// (/api/health o STACKLUME_HEALTH_PATH; cualquier estado < 500 vale) o conexión
+/// TCP con STACKLUME_READY_PROBE=tcp, durante `timeout_ms`. Si no llega a estar listo
+/// (timeout, `cancel` activado o node caído) distingue si el servidor llegó a responder.
+/// `on_stage` recibe cada `LoadingStage` nueva según avanza la espera.
+#[cfg(not(dev))]
+fn wait_for_server(
+    port: u16,
+    cancel: &AtomicBool,
+    exited: &dyn Fn() -> bool,
+    on_stage: &dyn Fn(LoadingStage),
+    log_path: &std::path::Path,
+) -> ServerWaitResult {
+    let cfg = HealthConfig::resolve();
+    // Último estado HTTP >= 500 recibido: el servidor está arriba pero no sano
+    let mut last_status: Option<u16> = None;
+    let failed = |last_status: Option<u16>| match last_status {
+        Some(status) => ServerWaitResult::TimedOutUnhealthy(status),
+        None => ServerWaitResult::TimedOutNoConnection,
+    };
+    let url = cfg.url(port);
+    let agent = cfg.probe_agent();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(cfg.timeout_ms);
+    // Backoff exponencial: 100 ms, 200 ms, 400 ms… hasta `max_poll_ms`. Al principio
+    // el puerto ni siquiera está abierto; así se detecta pronto un arranque rápido
+    // sin martillear al servidor durante todo el timeout.
+    let mut delay = cfg.poll_ms.max(1);
+    let mut attempt = 0u32;
+    let slow_at = std::time::Instant::now() + std::time::Duration::from_millis(cfg.timeout_ms / 2);
+    let mut stage = LoadingStage::Starting;
+    on_stage(stage);
+    let mut advance = |next: LoadingStage| {
+        if next > stage {
+            stage = next;
+            on_stage(next);
+        }
+    };
+    loop {
+        attempt += 1;
+        if std::time::Instant::now() >= slow_at {
+            advance(LoadingStage::Slow);
+        }
+        if cancel.load(Ordering::SeqCst) {
+            return failed(last_status);
+        }
+        // node ya no está: no tiene sentido agotar el timeout
+        if exited() {
+            log_debug(log_path, &format!("health {}: node.exe ya no está en ejecución", attempt));
+            return failed(last_status);
+        }
+        if cfg.probe == "tcp" {
+            // Menos preciso que HTTP (el puerto abre antes de que Next.js sirva
+            // páginas), pero vale para servidores sin endpoint de salud
+            let addr = std::net::ToSocketAddrs::to_socket_addrs(&(cfg.host.as_str(), port))
+                .ok()
+                .and_then(|mut addrs| addrs.next());
+            let timeout = std::time::Duration::from_millis(cfg.connect_timeout_ms);
+            match addr.map(|a| std::net::TcpStream::connect_timeout(&a, timeout)) {
+                Some(Ok(_)) => return ServerWaitResult::Ready,
+                Some(Err(e)) => log_debug(log_path, &format!("tcp {}: {}", attempt, e)),
+                None => log_debug(log_path, &format!("tcp {}: dirección no válida", attempt)),
+            }
+        } else {
+            // ureq devuelve los 4xx/5xx como Error::Status
+            let status = match agent.get(&url).call() {
+                Ok(resp) => Some(resp.status()),
+                Err(ureq::Error::Status(code, _)) => Some(code),
+                Err(e) => {
+                    log_debug(log_path, &format!("health {}: {}", attempt, e));
+                    None
                 }
-                Ok(None) => {
-                    // curl aún corriendo — emitir progreso
-                    let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
-                    let percent = std::cmp::min((file_size as f64 / ESTIMATED_SIZE as f64 * 100.0) as u64, 99);
-                    let _ = app_clone.emit("update:progress", percent);
+            };
+            match status {
+                Some(code) if code < 500 => return ServerWaitResult::Ready,
+                Some(code) => {
+                    log_debug(log_path, &format!("health {}: HTTP {}", attempt, code));
+                    last_status = Some(code);
+                    advance(LoadingStage::Responding);
                 }
-                Err(e) => {
-                    return Err(format!("Error monitoreando curl: {}", e));
+                None => {}
+            }
+        }
+        // Respetar el plazo total: la última espera se recorta hasta el deadline
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return failed(last_status);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(delay).min(remaining));
+        delay = (delay * 2).min(cfg.max_poll_ms.max(cfg.poll_ms));
+    }
+}
+
+/// Indica si `url` apunta al origen del servidor Next.js local (127.0.0.1/localhost:port,
+/// o la IP de STACKLUME_BIND_HOST si node solo escucha ahí).
+fn is_server_origin(url: &tauri::Url, port: u16) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && url
+            .host_str()
+            .is_some_and(|h| h == "127.0.0.1" || h == "localhost" || h == connect_host())
+        && url.port_or_known_default() == Some(port)
+}
+
+/// Busca cualquier puerto TCP libre (sin puerto preferido específico): el que asigne
+/// el OS a un bind a `127.0.0.1:0` o, si eso falla, el primero de 7881-7980 que se
+/// pueda abrir. Siempre en loopback, donde escucha llama-server (`--host 127.0.0.1`),
+/// sea cual sea `bind_host`. El listener se suelta antes de devolver el puerto,
+/// comprobado libre en ese momento.
+fn find_any_free_port() -> u16 {
+    for _ in 0..50 {
+        if let Ok(listener) = TcpListener::bind("127.0.0.1:0") {
+            if let Ok(addr) = listener.local_addr() {
+                return addr.port();
+            }
+        }
+    }
+    if let Some(port) = (7881..7981).find(|p| TcpListener::bind(("127.0.0.1", *p)).is_ok()) {
+        return port;
+    }
+    eprintln!("[Stacklume] WARN: Ningún puerto libre para llama-server, usando 7881 sin verificar");
+    7881
+}
+
+/// Espera hasta que llama-server responda al health check.
+/// Usa TCP raw + HTTP manual en vez de ureq (que tiene problemas en producción).
+fn wait_for_llama_server(port: u16) -> bool {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let addr = format!("127.0.0.1:{}", port);
+    // 360 × 500ms = 180 segundos (3 min)
+    for _ in 0..360 {
+        if let Ok(mut stream) = TcpStream::connect_timeout(
+            &addr.parse().unwrap(),
+            std::time::Duration::from_secs(2),
+        ) {
+            let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(2)));
+            let req = format!("GET /health HTTP/1.0\r\nHost: 127.0.0.1:{}\r\n\r\n", port);
+            if stream.write_all(req.as_bytes()).is_ok() {
+                let mut buf = [0u8; 512];
+                if let Ok(n) = stream.read(&mut buf) {
+                    let resp = String::from_utf8_lossy(&buf[..n]);
+                    if resp.contains("200") || resp.contains("ok") {
+                        return true;
+                    }
                 }
             }
         }
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+    false
+}
+
+/// Detecta cuántas GPU layers usar.
+/// Solo usa GPU si ggml-cuda.dll existe junto al binario de llama-server.
+fn detect_gpu_layers(binary_path: &str, model_path: &str, llm_log: &dyn Fn(&str)) -> String {
+    use std::process::Command;
+
+    let clean_path = binary_path.strip_prefix(r"\\?\").unwrap_or(binary_path);
+    let llama_dir = std::path::Path::new(clean_path).parent();
+    let cuda_path = llama_dir.map(|d| d.join("ggml-cuda.dll"));
+    let has_cuda = cuda_path.as_ref().map(|p| p.exists()).unwrap_or(false);
+    let has_vulkan = llama_dir.map(|d| d.join("ggml-vulkan.dll").exists()).unwrap_or(false);
+
+    llm_log(&format!("detect_gpu: binary={} dir={:?} cuda={} vulkan={}",
+        clean_path, llama_dir.map(|d| d.display().to_string()), has_cuda, has_vulkan));
+
+    if !has_cuda && !has_vulkan {
+        llm_log("detect_gpu: no GPU backend found → ngl 0");
+        return "0".to_string();
+    }
+
+    // Si hay backend CUDA, detectar VRAM y calcular layers
+    let mut nv_cmd = Command::new("C:\\Windows\\System32\\nvidia-smi.exe");
+    nv_cmd.args(["--query-gpu=memory.free", "--format=csv,noheader,nounits"]);
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        nv_cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
+    }
+    let vram_free_mb = nv_cmd
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .and_then(|s| s.lines().next().and_then(|l| l.trim().parse::<u64>().ok()))
+        .unwrap_or(0);
+
+    if vram_free_mb == 0 {
+        return "0".to_string();
+    }
+
+    let model_size_mb = std::fs::metadata(model_path)
+        .map(|m| m.len() / (1024 * 1024))
+        .unwrap_or(0);
+    let needed_mb = (model_size_mb as f64 * 1.1) as u64 + 200;
+
+    if vram_free_mb >= needed_mb {
+        "99".to_string()
+    } else if vram_free_mb > 500 {
+        let ratio = vram_free_mb as f64 / needed_mb as f64;
+        let layers = (ratio * 40.0).max(1.0).min(40.0) as u32;
+        layers.to_string()
+    } else {
+        "0".to_string()
+    }
+}
+
+/// Arranca llama-server de forma síncrona (llamar desde un hilo background).
+fn spawn_llama_server_blocking(app: &tauri::AppHandle) -> Result<(), String> {
+    use std::process::{Command, Stdio};
+
+    // Log de LLM para diagnóstico (se limpia al arrancar)
+    let llm_log_path = app_data_dir(app).unwrap_or_default().join("llm.log");
+    let _ = std::fs::write(&llm_log_path, ""); // Limpiar al inicio
+    let llm_log = |msg: &str| {
+        use std::io::Write;
+        if let Ok(mut f) = std::fs::OpenOptions::new().create(true).append(true).open(&llm_log_path) {
+            let ts = chrono_lite();
+            let _ = writeln!(f, "[{}] {}", ts, msg);
+        }
+    };
+
+    let binary_path;
+    let model_path;
+    let port;
+
+    {
+        let state = app.state::<LlamaState>();
+        // Strip \\?\ prefix que Tauri añade en producción — causa problemas con
+        // DLL loading de CUDA (ggml-cuda.dll busca cublas64_12.dll en el directorio
+        // del exe, pero con \\?\ prefix el directorio no se resuelve correctamente)
+        binary_path = state
+            .binary_path
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|p| p.strip_prefix(r"\\?\").unwrap_or(&p).to_string())
+            .ok_or_else(|| "llama-server no disponible".to_string())?;
+        model_path = state
+            .model_path
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|p| p.strip_prefix(r"\\?\").unwrap_or(&p).to_string())
+            .ok_or_else(|| "Modelo no configurado".to_string())?;
+        port = *state.port.lock().unwrap();
+    }
+
+    if port == 0 {
+        return Err("Puerto llama-server no asignado".to_string());
+    }
+
+    let ngl = detect_gpu_layers(&binary_path, &model_path, &llm_log);
+
+
+    // Detectar número de CPUs lógicos para --threads (mínimo 2, máximo 8)
+    let n_threads = std::thread::available_parallelism()
+        .map(|n| n.get().min(8).max(2))
+        .unwrap_or(4)
+        .to_string();
+
+    // Detectar familia del modelo para auto-configurar parámetros del servidor
+    let model_filename = std::path::Path::new(&model_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let family = detect_model_family(&model_filename);
+
+    // Parámetros de llama-server adaptados por familia de modelo.
+    // Los parámetros de sampling (temp, top_k, etc.) se envían per-request desde route.ts,
+    // pero ctx-size, n-predict, jinja y reasoning son configuraciones del servidor.
+    let (ctx_size, n_predict, use_jinja, reasoning_mode, default_temp, default_top_k, default_top_p) = match family {
+        "qwen3" => ("16384", "2048", true, "auto", "0.7", "20", "0.8"),
+        "qwen2" => ("8192",  "2048", true, "off",  "0.7", "20", "0.8"),
+        "llama3" => ("8192",  "2048", true, "off",  "0.6", "40", "0.9"),
+        "mistral" => ("8192", "2048", true, "off",  "0.7", "50", "0.9"),
+        "phi" =>    ("4096",  "1024", true, "off",  "0.7", "40", "0.9"),
+        "gemma" =>  ("8192",  "2048", true, "off",  "0.7", "40", "0.9"),
+        "deepseek" => ("16384","4096", true, "auto", "0.6", "20", "0.95"),
+        _ =>        ("4096",  "1024", true, "off",  "0.7", "40", "0.9"),
+    };
+
+    // Usar ctx-size de la familia directamente (llama.cpp ajusta automáticamente si no cabe)
+    let effective_ctx = ctx_size;
+
+    llm_log(&format!("binary: {}", binary_path));
+    llm_log(&format!("model: {}", model_path));
+    llm_log(&format!("port: {} | ngl: {} ({}) | ctx: {}", port, ngl, if ngl == "0" { "CPU" } else { "GPU" }, effective_ctx));
+
+    // Detectar proyector multimodal (mmproj) para soporte de visión
+    // Solo usar mmproj si el modelo activo es compatible (Qwen3.5-2B).
+    // El mmproj-F16.gguf descargado es específico del 2B — cargarlo con
+    // otro modelo (4B, 0.8B) causa crash silencioso de llama-server.
+    let model_filename_lower = std::path::Path::new(&model_path)
+        .file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    let mmproj_path: Option<std::path::PathBuf> = if model_filename_lower.contains("2b") {
+        std::path::Path::new(&model_path)
+            .parent()
+            .map(|d| d.join("mmproj-F16.gguf"))
+            .filter(|p| p.exists())
+    } else {
+        None
+    };
+
+    let mut cmd = Command::new(&binary_path);
+    cmd.arg("--model").arg(&model_path)
+        .arg("--host").arg("127.0.0.1")
+        .arg("--port").arg(port.to_string())
+        .arg("--ctx-size").arg(effective_ctx)
+        .arg("-ngl").arg(&ngl)
+        .arg("--threads").arg(&n_threads)
+        .arg("--threads-batch").arg(&n_threads)
+        .arg("--n-predict").arg(n_predict)
+        .arg("--temp").arg(default_temp)
+        .arg("--top-k").arg(default_top_k)
+        .arg("--top-p").arg(default_top_p)
+        .arg("--min-p").arg("0")
+        .arg("--no-context-shift")
+        .arg("--log-disable")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // CRÍTICO: establecer current_dir al directorio de llama-server para que
+    // Windows cargue NUESTRAS DLLs de CUDA bundleadas (no las del CUDA Toolkit del sistema).
+    // Windows busca DLLs en: 1) directorio del exe, 2) current directory, 3) System32, 4) PATH.
+    // Con current_dir apuntando al directorio del exe, nuestras DLLs tienen máxima prioridad.
+    if let Some(llama_dir) = std::path::Path::new(&binary_path).parent() {
+        cmd.current_dir(llama_dir);
+        llm_log(&format!("current_dir: {}", llama_dir.display()));
+    }
+
+    if use_jinja { cmd.arg("--jinja"); }
+    cmd.arg("--reasoning").arg(reasoning_mode);
+    if let Some(ref mp) = mmproj_path { cmd.arg("--mmproj").arg(mp); }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // Tauri es GUI subsystem → procesos hijos no pueden inicializar CUDA sin consola.
+        // AllocConsole crea una consola temporal, pero CREATE_NO_WINDOW la bloquea.
+        // Solución: solo ABOVE_NORMAL_PRIORITY. La consola se oculta porque
+        // stdout/stderr van a Stdio::null(). No aparece ventana visible.
+        cmd.creation_flags(0x00008000); // ABOVE_NORMAL_PRIORITY_CLASS
+    }
+
+    // Matar CUALQUIER llama-server previo antes de spawnar (evitar duplicados)
+    #[cfg(windows)]
+    {
+        let _ = silent_command("taskkill")
+            .args(["/f", "/im", "llama-server.exe"])
+            .output();
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    // Spawnar llama-server via un .bat wrapper que crea su propia consola.
+    // Tauri es GUI app sin consola → CUDA falla con Stdio::null/inherit/piped.
+    // Un .bat siempre crea su propia consola de forma nativa.
+    let wrapper_path = app_data_dir(app).unwrap_or_default().join("_llama_run.bat");
+    {
+        let mut bat_content = format!(
+            "@echo off\r\n\"{binary}\" --model \"{model}\" --host 127.0.0.1 --port {port} --ctx-size {ctx} -ngl {ngl} --threads {threads} --threads-batch {threads} --n-predict {predict} --temp {temp} --top-k {topk} --top-p {topp} --min-p 0 --no-context-shift --log-disable",
+            binary = binary_path,
+            model = model_path,
+            port = port,
+            ctx = effective_ctx,
+            ngl = ngl,
+            threads = n_threads,
+            predict = n_predict,
+            temp = default_temp,
+            topk = default_top_k,
+            topp = default_top_p,
+        );
+        if use_jinja { bat_content.push_str(" --jinja"); }
+        bat_content.push_str(&format!(" --reasoning {}", reasoning_mode));
+        if let Some(ref mp) = mmproj_path { bat_content.push_str(&format!(" --mmproj \"{}\"", mp.display())); }
+        bat_content.push_str("\r\n");
+        let _ = std::fs::write(&wrapper_path, &bat_content);
+        llm_log(&format!("wrapper: {}", wrapper_path.display()));
+    }
+
+    let mut cmd = Command::new("cmd.exe");
+    cmd.arg("/c").arg(&wrapper_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some(llama_dir) = std::path::Path::new(&binary_path).parent() {
+        cmd.current_dir(llama_dir);
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.creation_flags(0x08000000 | 0x00008000); // CREATE_NO_WINDOW + ABOVE_NORMAL
+    }
+
+    llm_log("Spawning llama-server via wrapper...");
+    match cmd.spawn() {
+        Ok(mut child) => {
+            let pid = child.id();
+            llm_log(&format!("wrapper spawned: PID {}", pid));
+
+            #[cfg(windows)]
+            {
+                match create_job_for_child(pid) {
+                    Ok(job) => *app.state::<LlamaState>().llama_job.lock().unwrap() = job,
+                    Err(e) => llm_log(&format!("WARN: Job Object no aplicado: {}", e)),
+                }
+            }
+
+            #[cfg(not(dev))]
+            {
+                *app.state::<LlamaState>().llama_child.lock().unwrap() = Some(child);
+            }
+
+            // Esperar a que llama-server arranque (el wrapper cmd.exe lo lanza)
+            std::thread::sleep(std::time::Duration::from_secs(3));
+
+            // Health check polling
+            llm_log("Health check polling...");
+            let health_ok = wait_for_llama_server(port);
+
+            if health_ok {
+                llm_log("llama-server READY");
+                *app.state::<LlamaState>().status.lock().unwrap() = "ready".to_string();
+                let _ = app.emit("llm:status-changed", "ready");
+            } else {
+                llm_log("TIMEOUT: llama-server no respondió en 120s");
+                let msg = "error: llama-server no respondió en 120s — prueba un modelo más pequeño".to_string();
+                *app.state::<LlamaState>().status.lock().unwrap() = msg.clone();
+                let _ = app.emit("llm:status-changed", msg.clone());
+                return Err(msg);
+            }
+        }
+        Err(e) => {
+            llm_log(&format!("SPAWN ERROR: {}", e));
+            *app.state::<LlamaState>().status.lock().unwrap() = "error".to_string();
+            let _ = app.emit("llm:status-changed", format!("error: {}", e));
+            return Err(e.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Resuelve la ruta de un recurso empaquetado.
+/// Prueba `resource_dir/subpath` y `resource_dir/resources/subpath`.
+fn resolve_resource(resource_dir: &std::path::Path, subpath: &str) -> std::path::PathBuf {
+    let direct = resource_dir.join(subpath);
+    if direct.exists() {
+        return direct;
+    }
+    let with_prefix = resource_dir.join("resources").join(subpath);
+    if with_prefix.exists() {
+        return with_prefix;
+    }
+    direct // fallback — el error se reportará después
+}
+
+/// Binario de Node empaquetado, relativo al directorio de recursos.
+#[cfg(all(not(dev), windows))]
+const NODE_BIN: &str = "node/node.exe";
+#[cfg(all(not(dev), not(windows)))]
+const NODE_BIN: &str = "node/node";
+
+/// Rutas de los recursos empaquetados que necesita el servidor.
+#[cfg(not(dev))]
+struct ServerPaths {
+    resource_dir: std::path::PathBuf,
+    node_exe: std::path::PathBuf,
+    server_js: std::path::PathBuf,
+    llama_exe: std::path::PathBuf,
+    /// Resultado de STACKLUME_NODE_PATH (ver `node_path_override`), para el log del arranque
+    node_override: Result<Option<std::path::PathBuf>, String>,
+}
+
+/// Base de datos fuera de app_data (STACKLUME_DB_PATH), p. ej. en un SSD local
+/// cuando app_data está en una unidad de red. Crea el directorio padre y comprueba
+/// que se puede escribir en él; Err explica por qué no se puede usar.
+#[cfg(not(dev))]
+fn db_path_override() -> Result<Option<std::path::PathBuf>, String> {
+    let Some(raw) = std::env::var_os("STACKLUME_DB_PATH").filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    let path = std::path::PathBuf::from(raw);
+    if !path.is_absolute() {
+        return Err(format!("'{}' no es una ruta absoluta", path.display()));
+    }
+    if path.is_dir() {
+        return Err(format!("'{}' es un directorio", path.display()));
+    }
+    let parent = path
+        .parent()
+        .ok_or_else(|| format!("'{}' no tiene directorio padre", path.display()))?;
+    // SQLite necesita crear -wal/-shm junto a la base: probar en el directorio
+    ensure_writable_dir(parent)?;
+    Ok(Some(path))
+}
+
+/// Node propio del usuario (STACKLUME_NODE_PATH) en lugar del incluido, p. ej. una
+/// instalación corporativa ya auditada. Solo se acepta si es un archivo ejecutable;
+/// Err explica por qué no se puede usar (y se usa el incluido).
+#[cfg(not(dev))]
+fn node_path_override() -> Result<Option<std::path::PathBuf>, String> {
+    let Some(raw) = std::env::var_os("STACKLUME_NODE_PATH").filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    let path = std::path::PathBuf::from(raw);
+    let meta = match std::fs::metadata(&path) {
+        Ok(m) if m.is_file() => m,
+        _ => return Err(format!("'{}' no existe o no es un archivo", path.display())),
+    };
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = {
+        let _ = meta;
+        path.extension().is_some_and(|e| e.eq_ignore_ascii_case("exe"))
+    };
+    if !executable {
+        return Err(format!("'{}' no es ejecutable", path.display()));
+    }
+    Ok(Some(path))
+}
+
+/// Resuelve las rutas de recursos desde cero. `resource_dir()` se deriva de
+/// `current_exe()` en cada llamada, así que si el exe se mueve con la app abierta
+/// el resultado refleja la ubicación actual, no la del arranque.
+#[cfg(not(dev))]
+fn resolve_server_paths(app: &tauri::AppHandle) -> ServerPaths {
+    let resource_dir = app
+        .path()
+        .resource_dir()
+        .unwrap_or_else(|_| {
+            std::env::current_exe()
+                .unwrap_or_default()
+                .parent()
+                .unwrap_or(std::path::Path::new("."))
+                .to_path_buf()
+        });
+
+    let node_override = node_path_override();
+    ServerPaths {
+        node_exe: match &node_override {
+            Ok(Some(path)) => path.clone(),
+            _ => resolve_resource(&resource_dir, NODE_BIN),
+        },
+        server_js: resolve_resource(&resource_dir, "server/server.js"),
+        llama_exe: resolve_resource(&resource_dir, "llama/llama-server.exe"),
+        resource_dir,
+        node_override,
+    }
+}
+
+/// Mensaje para cuando los recursos desaparecen con la app abierta (típico al
+/// mover/renombrar Stacklume.exe durante una actualización manual).
+#[cfg(not(dev))]
+const APP_MOVED_MSG: &str =
+    "La aplicación se ha movido o renombrado mientras estaba abierta. Ciérrala y vuelve a abrirla.";
+
+/// Re-resuelve node.exe y server.js (nunca reutiliza las rutas del arranque) y
+/// actualiza la config de lanzamiento guardada. Lo usan las rutas que relanzan node.
+#[cfg(not(dev))]
+fn refresh_server_launch(app: &tauri::AppHandle) -> Result<ServerLaunch, String> {
+    let paths = resolve_server_paths(app);
+    if !paths.node_exe.exists() || !paths.server_js.exists() {
+        app_log_at(app, LogLevel::Error, &format!(
+            "ERROR: recursos no encontrados al relanzar (exe actual: {}, node: {}, server: {})",
+            std::env::current_exe().unwrap_or_default().display(),
+            paths.node_exe.display(),
+            paths.server_js.display()
+        ));
+        return Err(APP_MOVED_MSG.to_string());
+    }
+
+    let state = app.state::<ServerState>();
+    let mut guard = state.launch.lock().unwrap();
+    let launch = guard
+        .as_mut()
+        .ok_or_else(|| "El servidor no se ha lanzado todavía".to_string())?;
+    if launch.node_exe != paths.node_exe {
+        app_log(app, &format!(
+            "Recursos reubicados: {} → {}",
+            launch.node_exe.display(),
+            paths.node_exe.display()
+        ));
+    }
+    launch.node_exe = paths.node_exe;
+    launch.server_dir = paths
+        .server_js
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."))
+        .to_path_buf();
+    Ok(launch.clone())
+}
+
+/// Quita el prefijo verbatim `\\?\` que a veces devuelve resource_dir en Windows.
+/// Node.js lo arrastra a process.cwd() y a las rutas que construye a partir de él,
+/// y algunos módulos (resolución de paquetes, better-sqlite3) no lo soportan.
+#[cfg(any(test, not(dev)))]
+fn plain_path(path: &std::path::Path) -> std::path::PathBuf {
+    // Solo si la ruta es UTF-8 válido: si no, se deja tal cual para no perder caracteres
+    #[cfg(windows)]
+    if let Some(s) = path.to_str() {
+        if let Some(rest) = s.strip_prefix(r"\\?\UNC\") {
+            return std::path::PathBuf::from(format!(r"\\{}", rest));
+        }
+        if let Some(rest) = s.strip_prefix(r"\\?\") {
+            return std::path::PathBuf::from(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Capacidades del shell nativo en esta plataforma/build, pasadas al servidor en
+/// STACKLUME_CAPABILITIES para que la UI se adapte (DESKTOP_MODE solo dice "hay shell").
+/// Se derivan de los plugins registrados en `run()` y de la plataforma.
+#[cfg(any(test, not(dev)))]
+fn desktop_capabilities() -> serde_json::Value {
+    serde_json::json!({
+        // tauri-plugin-shell: abrir URLs/archivos con la app del sistema
+        "open_external": true,
+        // tauri-plugin-process: salir/reiniciar desde la UI
+        "process": true,
+        "global_shortcut": true,
+        "tray": true,
+        // Sin plugins de portapapeles, diálogos, fs ni notificaciones
+        "clipboard": false,
+        "dialogs": false,
+        "fs": false,
+        "notifications": false,
+        // llama-server y el lanzador .bat solo existen en Windows
+        "local_llm": cfg!(windows),
+        "platform": std::env::consts::OS,
+    })
+}
+
+/// Configuración con la que se lanza node.exe para el servidor Next.js.
+/// Se guarda en `ServerState` para poder relanzar node con exactamente el mismo
+/// binario, directorio y entorno (scripts de mantenimiento, reinicios...).
+#[cfg(any(test, not(dev)))]
+#[derive(Clone)]
+struct ServerLaunch {
+    node_exe: std::path::PathBuf,
+    /// Directorio del servidor standalone (current_dir de node)
+    server_dir: std::path::PathBuf,
+    db_path: std::path::PathBuf,
+    port: u16,
+    llama_port: u16,
+    /// Locale BCP-47 que recibe el servidor en STACKLUME_LOCALE (ver `effective_locale`)
+    locale: String,
+    /// Copia de `ServerState::session_token` (STACKLUME_SESSION_TOKEN)
+    session_token: String,
+}
+
+#[cfg(any(test, not(dev)))]
+impl ServerLaunch {
+    /// Construye un `Command` de node con el current_dir y el entorno del servidor.
+    /// El llamador añade los argumentos (p.ej. "server.js") y la redirección de stdio.
+    fn node_command(&self, log_path: &std::path::Path) -> std::process::Command {
+        // Las rutas se pasan como OsStr (sin conversión a UTF-8 ni comillas manuales):
+        // Command ya se encarga del quoting, así que espacios, paréntesis
+        // ("Program Files (x86)") y unicode llegan intactos a node.exe.
+        let mut cmd = std::process::Command::new(plain_path(&self.node_exe));
+        cmd.env_clear()
+            .current_dir(plain_path(&self.server_dir))
+            // Variables de la aplicación
+            .env("PORT", self.port.to_string())
+            .env("HOSTNAME", bind_host().to_string())
+            .env("DESKTOP_MODE", "true")
+            .env("DATABASE_PATH", plain_path(&self.db_path))
+            .env("NODE_ENV", "production")
+            // Variables del sistema Windows necesarias para Node.js
+            .env("SystemRoot", std::env::var("SystemRoot").unwrap_or_default())
+            .env("SystemDrive", std::env::var("SystemDrive").unwrap_or_default())
+            .env("APPDATA", std::env::var("APPDATA").unwrap_or_default())
+            .env("LOCALAPPDATA", std::env::var("LOCALAPPDATA").unwrap_or_default())
+            .env("TEMP", std::env::var("TEMP").unwrap_or_default())
+            .env("TMP", std::env::var("TMP").unwrap_or_default())
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .env("USERPROFILE", std::env::var("USERPROFILE").unwrap_or_default())
+            .env("COMPUTERNAME", std::env::var("COMPUTERNAME").unwrap_or_default())
+            .env("USERNAME", std::env::var("USERNAME").unwrap_or_default())
+            .env("ProgramData", std::env::var("ProgramData").unwrap_or_default())
+            .env("windir", std::env::var("windir").unwrap_or_default())
+            // Puerto de llama-server para que la API route /api/llm/* lo use
+            .env("LLAMA_PORT", self.llama_port.to_string())
+            // Qué sabe hacer este shell (ver desktop_capabilities)
+            .env("STACKLUME_CAPABILITIES", desktop_capabilities().to_string())
+            .env("STACKLUME_LOCALE", &self.locale)
+            .env("STACKLUME_SESSION_TOKEN", &self.session_token);
+        // Expuesto a la red: el token deja de ser opcional para el servidor
+        if server_exposed() {
+            cmd.env("STACKLUME_REQUIRE_SESSION_TOKEN", "1");
+        }
+
+        // Reenviar tal cual las variables STACKLUME_PUBLIC_* del entorno (flags propios
+        // del servidor sin recompilar). Se conserva el prefijo para que no puedan
+        // pisar PATH, NODE_OPTIONS ni las variables fijadas arriba. Solo se loguean
+        // los nombres, nunca los valores.
+        let mut forwarded = Vec::new();
+        for (k, v) in std::env::vars_os() {
+            let Some(key) = k.to_str() else { continue };
+            if key.len() > "STACKLUME_PUBLIC_".len() && key.starts_with("STACKLUME_PUBLIC_") {
+                forwarded.push(key.to_string());
+                cmd.env(&k, v);
+            }
+        }
+        if !forwarded.is_empty() {
+            log(log_path, &format!("Variables STACKLUME_PUBLIC_ reenviadas: {}", forwarded.join(", ")));
+        }
+
+        // Cargar claves privadas desde .env.keys (generado por build-desktop.mjs).
+        // Este archivo solo existe en builds privadas del propietario — no en el repo público.
+        // Whitelist de variables de entorno permitidas desde .env.keys
+        // para evitar que un archivo .env.keys comprometido inyecte
+        // variables arbitrarias (PATH, LD_PRELOAD, NODE_OPTIONS, etc.)
+        const ALLOWED_ENV_KEYS: &[&str] = &[
+            "AUTH_SECRET",
+            "AUTH_USERNAME",
+            "AUTH_PASSWORD_HASH",
+            "SENTRY_DSN",
+            "NEXT_PUBLIC_SENTRY_DSN",
+            "UPSTASH_REDIS_REST_URL",
+            "UPSTASH_REDIS_REST_TOKEN",
+            "DATABASE_URL",
+        ];
+
+        let env_keys_path = self.server_dir.join(".env.keys");
+        if env_keys_path.exists() {
+            if let Ok(contents) = std::fs::read_to_string(&env_keys_path) {
+                let mut loaded = 0u32;
+                let mut skipped = 0u32;
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.starts_with('#') || line.is_empty() { continue; }
+                    if let Some((k, v)) = line.split_once('=') {
+                        let k = k.trim();
+                        let v = v.trim().trim_matches('"').trim_matches('\'');
+                        if !k.is_empty() && !v.is_empty() {
+                            if ALLOWED_ENV_KEYS.contains(&k) {
+                                cmd.env(k, v);
+                                loaded += 1;
+                            } else {
+                                skipped += 1;
+                            }
+                        }
+                    }
+                }
+                log(log_path, &format!(".env.keys: {} variables cargadas, {} ignoradas (no en whitelist)", loaded, skipped));
+            }
+        }
+
+        // Evitar que node.exe abra una ventana de consola en Windows
+        #[cfg(windows)]
+        if show_server_console() {
+            log(log_path, "STACKLUME_SHOW_CONSOLE activo: node.exe se lanza con consola visible");
+        } else {
+            use std::os::windows::process::CommandExt;
+            cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
+        }
+
+        // macOS/Linux: variables básicas que Node espera (env_clear las quitó) y un
+        // grupo de procesos propio (pgid = pid de node), equivalente al Job Object:
+        // `stop_server_child` mata el grupo entero, incluidos los workers de node.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            for key in ["HOME", "USER", "LANG", "TMPDIR"] {
+                if let Ok(v) = std::env::var(key) {
+                    cmd.env(key, v);
+                }
+            }
+            cmd.process_group(0);
+        }
+
+        cmd
+    }
+}
+
+/// Lanza node.exe server.js con `launch`, redirigiendo stdout/stderr a server.log
+/// (nuevo; el anterior pasa a server.log.1), lo asigna al Job Object y guarda el
+/// handle en `ServerState`.
+/// Devuelve el PID del proceso y su generación (`spawn_generation`), ambos fijados
+/// bajo el lock de `node_child`: el hilo de espera los recibe tal cual, sin releerlos
+/// del estado, donde otro spawn podría haberlos cambiado ya.
+#[cfg(not(dev))]
+fn spawn_server(
+    app: &tauri::AppHandle,
+    launch: &ServerLaunch,
+    log_path: &std::path::Path,
+    slog_path: &std::path::Path,
+) -> std::io::Result<(u32, u64)> {
+    use std::process::Stdio;
+    // Referencia de uptime y de la duración del arranque (`last_startup_ms`)
+    let spawn_started = std::time::Instant::now();
+
+    // Redirigimos stdout y/o stderr al archivo server.log para diagnóstico, según
+    // `log_capture` en settings.json. server.log se vacía siempre, aunque no se
+    // capture nada, para no confundir su contenido con el de un arranque anterior;
+    // antes se rota para conservar el log de la ejecución que falló.
+    let capture = load_settings(app).log_capture;
+    rotate_log(slog_path, LOG_GENERATIONS);
+    let slog_out = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(slog_path)
+        .ok();
+    let slog_err = slog_out
+        .as_ref()
+        .and_then(|f| f.try_clone().ok());
+
+    // Validar antes de lanzar: con un current_dir inexistente Windows solo devuelve
+    // "El nombre del directorio no es válido", sin decir cuál.
+    if !launch.server_dir.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("directorio del servidor no encontrado: {}", launch.server_dir.display()),
+        ));
+    }
+
+    let mut cmd = launch.node_command(log_path);
+    cmd.arg("server.js");
+
+    let (out, err) = match capture {
+        LogCapture::Both => (slog_out, slog_err),
+        LogCapture::Stdout => (slog_out, None),
+        LogCapture::Stderr => (None, slog_out),
+        LogCapture::None => (None, None),
+    };
+    cmd.stdout(out.map(Stdio::from).unwrap_or_else(Stdio::null))
+        .stderr(err.map(Stdio::from).unwrap_or_else(Stdio::null));
+    log(log_path, &format!("Captura de server.log: {}", capture.as_str()));
+
+    log(log_path, &format!(
+        "Spawning: {} {}",
+        launch.node_exe.display(),
+        launch.server_dir.join("server.js").display()
+    ));
+
+    // Un único node.exe: con dos reinicios seguidos el segundo podría lanzar el suyo
+    // antes de que el primero guarde el de él. `node_child` queda bloqueado hasta
+    // guardar el nuevo y cualquier hijo que siga ahí se detiene antes de lanzar.
+    let state = app.state::<ServerState>();
+    let mut slot = state.node_child.lock().unwrap();
+    if let Some(mut stale) = slot.take() {
+        log_at(log_path, LogLevel::Warn, &format!(
+            "WARN: node.exe anterior (PID {}) seguía vivo al relanzar — detenido",
+            stale.id()
+        ));
+        let _ = force_kill_server_child(app, &mut stale, false);
+    }
+    let generation = state.spawn_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let child = {
+        // Sin Ctrl+C ignorado a medio heredar (ver `request_graceful_exit`)
+        #[cfg(windows)]
+        let _console = CONSOLE_CTRL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        cmd.spawn()?
+    };
+    let pid = child.id();
+    log(log_path, &format!("Servidor iniciado (PID: {})", pid));
+    log_json(log_path, LogLevel::Info, &format!("Servidor iniciado (PID: {})", pid));
+
+    let priority = ServerPriority::from_env();
+    if priority != ServerPriority::Normal {
+        match apply_server_priority(&child, priority) {
+            Ok(()) => log(log_path, &format!("Prioridad de node: {}", priority.as_str())),
+            Err(e) => log_at(log_path, LogLevel::Warn, &format!(
+                "WARN: No se pudo aplicar prioridad '{}': {}", priority.as_str(), e
+            )),
+        }
+    }
+
+    // Job Object: node.exe muere automáticamente cuando Stacklume.exe
+    // termina por CUALQUIER razón (incluso TerminateProcess de NSIS).
+    #[cfg(windows)]
+    {
+        match create_job_for_child(pid) {
+            Ok(job) => {
+                log(log_path, "Job Object creado — node.exe se matará al cerrar la app");
+                *app.state::<ServerState>().node_job.lock().unwrap() = job;
+            }
+            // Sin job solo queda la parada explícita de shutdown_children (Destroyed /
+            // quit_app): si Stacklume muere de golpe, node.exe quedará huérfano
+            Err(e) => {
+                log_at(log_path, LogLevel::Warn, &format!(
+                    "WARN: Job Object no aplicado ({}) — node.exe solo se detendrá en un cierre ordenado",
+                    e
+                ));
+                log_json(log_path, LogLevel::Warn, &format!("Job Object no aplicado: {}", e));
+            }
+        }
+    }
+
+    // Guardamos el handle para poder matar el proceso explícitamente al cerrar
+    *state.last_exit.lock().unwrap() = None;
+    *state.spawned_at.lock().unwrap() = Some(spawn_started);
+    *state.pid.lock().unwrap() = Some(pid);
+    *slot = Some(child);
+    Ok((pid, generation))
+}
+
+/// Aplica `priority` a node.exe recién lanzado: SetPriorityClass en Windows
+/// (BELOW_NORMAL / ABOVE_NORMAL, nunca HIGH para no ahogar la UI) y `renice` en
+/// Unix (10 / -5; subir prioridad en Unix suele requerir privilegios).
+#[cfg(not(dev))]
+fn apply_server_priority(child: &std::process::Child, priority: ServerPriority) -> Result<(), String> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::Threading::{
+            SetPriorityClass, ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS,
+            NORMAL_PRIORITY_CLASS,
+        };
+        let class = match priority {
+            ServerPriority::Low => BELOW_NORMAL_PRIORITY_CLASS,
+            ServerPriority::Normal => NORMAL_PRIORITY_CLASS,
+            ServerPriority::High => ABOVE_NORMAL_PRIORITY_CLASS,
+        };
+        let ok = unsafe { SetPriorityClass(child.as_raw_handle(), class) };
+        if ok == 0 {
+            return Err(format!("SetPriorityClass falló: {}", std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    {
+        let nice = match priority {
+            ServerPriority::Low => "10",
+            ServerPriority::Normal => "0",
+            ServerPriority::High => "-5",
+        };
+        let output = silent_command("renice")
+            .args([nice, "-p", &child.id().to_string()])
+            .output()
+            .map_err(|e| format!("Error ejecutando renice: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "renice {} falló: {}",
+                nice,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Ejecuta `node --version` en segundo plano (sin retrasar el arranque) y guarda el
+/// resultado en `ServerState::node_version` para `get_version_info`.
+#[cfg(not(dev))]
+fn probe_node_version(app: &tauri::AppHandle, node_exe: &std::path::Path) {
+    let app = app.clone();
+    let mut cmd = std::process::Command::new(plain_path(node_exe));
+    cmd.arg("--version").stdin(std::process::Stdio::null());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
+    }
+    std::thread::spawn(move || match cmd.output() {
+        Ok(out) if out.status.success() => {
+            let version = String::from_utf8_lossy(&out.stdout).trim().to_string();
+            app_log(&app, &format!("node --version: {}", version));
+            *app.state::<ServerState>().node_version.lock().unwrap() = Some(version);
+        }
+        Ok(out) => app_log_at(&app, LogLevel::Warn, &format!(
+            "WARN: node --version falló ({})",
+            describe_exit(out.status)
+        )),
+        Err(e) => app_log_at(&app, LogLevel::Warn, &format!("WARN: No se pudo ejecutar node --version: {}", e)),
+    });
+}
+
+/// Detiene el node.exe actual (si lo hay): primero le pide cerrar limpio y, si no
+/// termina en `shutdown_grace_ms()`, lo mata. Devuelve su PID.
+#[cfg(not(dev))]
+fn stop_server_child(app: &tauri::AppHandle) -> Option<u32> {
+    take_server_child(app).map(|mut child| {
+        let pid = child.id();
+        let mut exited = false;
+        if request_graceful_exit(pid) {
+            let deadline = std::time::Instant::now()
+                + std::time::Duration::from_millis(shutdown_grace_ms());
+            while !exited && std::time::Instant::now() < deadline {
+                exited = matches!(child.try_wait(), Ok(Some(_)));
+                if !exited {
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+            }
+        }
+        let _ = force_kill_server_child(app, &mut child, exited);
+        pid
+    })
+}
+
+/// Retira el node.exe actual de `ServerState` sin pararlo. Al no quedar en `node_child`
+/// el watchdog no lo ve caer, y el hilo de espera de un arranque en curso queda
+/// superado (`spawn_generation`): no muestra la página de error de una parada pedida.
+#[cfg(not(dev))]
+fn take_server_child(app: &tauri::AppHandle) -> Option<std::process::Child> {
+    let state = app.state::<ServerState>();
+    state.ready.store(false, Ordering::SeqCst);
+    let child = state.node_child.lock().ok().and_then(|mut g| g.take());
+    *state.pid.lock().unwrap() = None;
+    if child.is_some() {
+        state.spawn_generation.fetch_add(1, Ordering::SeqCst);
+    }
+    child
+}
+
+/// Mata a la fuerza `child` (ya retirado con `take_server_child`) y cierra su Job
+/// Object. Con `exited` (ya salió por sí mismo) solo se limpia lo que quede.
+/// Devuelve el error de `kill` si el proceso no se pudo matar.
+#[cfg(not(dev))]
+fn force_kill_server_child(
+    app: &tauri::AppHandle,
+    child: &mut std::process::Child,
+    exited: bool,
+) -> std::io::Result<()> {
+    // Unix: node es líder de su propio grupo (ver node_command) → matar el grupo
+    // (también tras un cierre limpio, por si quedó algún worker colgado)
+    #[cfg(unix)]
+    let _ = silent_command("kill")
+        .args(["-KILL", "--", &format!("-{}", child.id())])
+        .status();
+    let killed = if exited {
+        Ok(())
+    } else {
+        let killed = child.kill();
+        let _ = child.wait();
+        killed
+    };
+    release_node_job(app);
+    killed
+}
+
+/// Parada ordenada de los procesos hijos al salir: node.exe (con su margen de cierre
+/// limpio; `stop_server_child` cierra también su Job Object) y llama-server. Se usa al destruir la
+/// ventana y desde `quit_app`; repetirla no hace nada.
+#[cfg(not(dev))]
+fn shutdown_children(app: &tauri::AppHandle) {
+    // Matar node.exe
+    if let Some(pid) = stop_server_child(app) {
+        app_log(app, &format!("Salida: node.exe (PID {}) detenido", pid));
+    }
+
+    // Matar llama-server
+    let maybe_llama = app
+        .state::<LlamaState>()
+        .llama_child
+        .lock()
+        .ok()
+        .and_then(|mut g| g.take());
+    if let Some(mut child) = maybe_llama {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Serializa los cambios de estado de consola de `request_graceful_exit`: la consola
+/// adjunta y el handler de Ctrl+C son del proceso entero, no del hilo.
+#[cfg(all(windows, not(dev)))]
+static CONSOLE_CTRL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Pide a node.exe que termine por sí mismo: SIGTERM al grupo de procesos en Unix;
+/// en Windows, Ctrl+C a su consola oculta (node lo recibe como SIGINT). Devuelve
+/// false si no hay margen configurado o no se pudo enviar la señal.
+/// En Windows adjunta la consola e ignora Ctrl+C en todo el proceso durante ~100 ms:
+/// se hace bajo `CONSOLE_CTRL_LOCK`, que también toma `spawn_server` para que ningún
+/// node.exe nuevo herede el Ctrl+C ignorado.
+#[cfg(not(dev))]
+fn request_graceful_exit(pid: u32) -> bool {
+    if shutdown_grace_ms() == 0 {
+        return false;
+    }
+
+    #[cfg(unix)]
+    {
+        silent_command("kill")
+            .args(["-TERM", "--", &format!("-{}", pid)])
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    #[cfg(windows)]
+    unsafe {
+        use windows_sys::Win32::System::Console::{
+            AttachConsole, FreeConsole, GenerateConsoleCtrlEvent, SetConsoleCtrlHandler, CTRL_C_EVENT,
+        };
+        // Stacklume no tiene consola: se adjunta un momento a la de node, ignorando
+        // el Ctrl+C en este proceso para no cerrarse a sí mismo. Con paradas
+        // simultáneas (Destroyed + quit_app, watchdog + reinicio) se pisarían.
+        let _console = CONSOLE_CTRL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if AttachConsole(pid) == 0 {
+            return false;
+        }
+        SetConsoleCtrlHandler(None, 1);
+        let sent = GenerateConsoleCtrlEvent(CTRL_C_EVENT, 0) != 0;
+        FreeConsole();
+        // El evento se entrega de forma asíncrona: esperar antes de volver a aceptarlo
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        SetConsoleCtrlHandler(None, 0);
+        sent
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+/// Escapa texto arbitrario (p. ej. el final de server.log) para incrustarlo en el
+/// HTML de una data URL: primero como HTML y después se codifica en porcentaje todo
+/// byte que no sea alfanumérico o `-._~`, así que `?`, `&`, `#`, `%`, saltos de
+/// línea o UTF-8 no pueden romper el parseo de la URL. Se descartan los caracteres
+/// de control (salvo \n y \t), como las secuencias ANSI de color de node.
+fn data_url_text(s: &str) -> String {
+    use std::fmt::Write;
+    let html: String = s
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+    let mut out = String::with_capacity(html.len() * 3);
+    for b in html.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+/// Texto del informe de soporte que copia el botón "Copiar informe".
+#[cfg(not(dev))]
+fn support_report(title: &str, detail: &str, log_path: &std::path::Path, tail: Option<&str>) -> String {
+    let mut report = format!(
+        "Stacklume {} ({}/{})\n{}\n{}\nLog: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        title,
+        detail,
+        log_path.display()
+    );
+    if let Some(tail) = tail {
+        report.push_str("--- server.log ---\n");
+        report.push_str(tail);
+        report.push('\n');
+    }
+    report
+}
+
+/// Pie común de las páginas de error: ruta completa del log (seleccionable con un
+/// clic) y botón "Copiar informe". Las data URL no tienen acceso IPC, así que el
+/// informe va incrustado en la página y se copia con la API del portapapeles
+/// (o execCommand si no está disponible).
+#[cfg(not(dev))]
+fn error_page_footer(report: &str, log_path: &std::path::Path) -> String {
+    format!(
+        concat!(
+            "<p style='margin-top:1rem;font-size:11px;color:%23666'>Log completo: ",
+            "<span style='user-select:all;-webkit-user-select:all;color:%23aaa'>{path}</span></p>",
+            "<textarea id='report' readonly style='position:absolute;left:-9999px'>{report}</textarea>",
+            "<button onclick='copyReport(this)' style='margin-top:.5rem;padding:.5rem 1rem;",
+            "background:%23222;color:%23fff;border:1px solid %23444;border-radius:6px;",
+            "cursor:pointer'>Copiar informe</button>",
+            "<script>function copyReport(b){{var t=document.getElementById('report');t.select();",
+            "var done=function(){{b.textContent='Copiado'}};",
+            "var legacy=function(){{document.execCommand('copy');done()}};",
+            "if(navigator.clipboard){{navigator.clipboard.writeText(t.value).then(done,legacy)}}",
+            "else{{legacy()}}}}</script>"
+        ),
+        path = data_url_text(&log_path.display().to_string()),
+        report = data_url_text(report)
+    )
+}
+
+/// Plantilla de `resources/pages/{name}.html`, si está empaquetada. Permite
+/// retocar las páginas nativas sin recompilar; si falta se usa la copia embebida.
+fn page_template(app: &tauri::AppHandle, name: &str) -> Option<String> {
+    let resource_dir = app.path().resource_dir().ok()?;
+    std::fs::read_to_string(resolve_resource(&resource_dir, &format!("pages/{}.html", name))).ok()
+}
+
+/// Convierte una plantilla HTML en data URL y sustituye cada `{{clave}}` por su
+/// valor de `vars`, que ya debe venir escapado para data URL (`data_url_text` o
+/// fragmentos como `error_page_footer`). Se sustituye en una sola pasada, así que
+/// un valor que contenga `{{...}}` no se vuelve a expandir.
+fn render_page(template: &str, vars: &[(&str, String)]) -> String {
+    let mut html = String::from("data:text/html,");
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&url_escape_template(&rest[..start]));
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let key = after[..end].trim();
+                match vars.iter().find(|(k, _)| *k == key) {
+                    Some((_, value)) => html.push_str(value),
+                    None => html.push_str(&url_escape_template(&rest[start..start + 2 + end + 2])),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                html.push_str(&url_escape_template(&rest[start..]));
+                rest = "";
+            }
+        }
+    }
+    html.push_str(&url_escape_template(rest));
+    html
+}
+
+/// Escapa el HTML de una plantilla para data URL (sin tocar las entidades HTML,
+/// que la plantilla ya trae escritas).
+fn url_escape_template(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('#', "%23")
+        .replace('\r', "")
+        .replace('\n', "%0A")
+}
+
+/// Página de carga (`pages/loading.html` o la embebida) con la paleta clara u
+/// oscura según el tema elegido (theme.txt) o, con "system", el del sistema, para
+/// no mostrar un destello oscuro en un escritorio claro. Los acentos dorados son
+/// iguales en ambos temas.
+fn loading_page(app: &tauri::AppHandle) -> String {
+    let template = page_template(app, "loading").unwrap_or_else(|| LOADING_TEMPLATE.to_string());
+    render_page(&template, &page_palette(app))
+}
+
+/// Página de mantenimiento (`pages/maintenance.html` o la embebida) con `message` y
+/// la paleta de la página de carga.
+fn maintenance_page(app: &tauri::AppHandle, message: &str) -> String {
+    let template = page_template(app, "maintenance").unwrap_or_else(|| MAINTENANCE_TEMPLATE.to_string());
+    let mut vars = page_palette(app).to_vec();
+    vars.push(("message", data_url_text(message)));
+    render_page(&template, &vars)
+}
+
+/// Marcadores bg, fg y muted de las páginas con la marca de Stacklume, según el
+/// tema elegido o el del sistema.
+fn page_palette(app: &tauri::AppHandle) -> [(&'static str, String); 3] {
+    let theme = window_theme(&load_theme(app))
+        .or_else(|| app.get_webview_window("main").and_then(|w| w.theme().ok()))
+        .unwrap_or(tauri::Theme::Dark);
+    // (fondo, texto, texto secundario)
+    let (bg, fg, muted) = match theme {
+        tauri::Theme::Light => ("#f8fafc", "#0f172a", "#64748b"),
+        _ => ("#0d1117", "#e2e8f0", "#666"),
+    };
+    [
+        ("bg", data_url_color(bg)),
+        ("fg", data_url_color(fg)),
+        ("muted", data_url_color(muted)),
+    ]
+}
+
+/// Color `#rrggbb` listo para una data URL (`#` → `%23`).
+fn data_url_color(color: &str) -> String {
+    color.replace('#', "%23")
+}
+
+/// Página de recursos no encontrados (`pages/missing.html` o la genérica).
+#[cfg(not(dev))]
+fn missing_page(app: &tauri::AppHandle, detail: &str, log_path: &std::path::Path) -> String {
+    const TITLE: &str = "Recursos no encontrados";
+    match page_template(app, "missing") {
+        Some(template) => {
+            let report = support_report(TITLE, detail, log_path, None);
+            render_page(&template, &[
+                ("detail", data_url_text(detail)),
+                ("footer", error_page_footer(&report, log_path)),
+            ])
+        }
+        None => error_page(TITLE, detail, log_path),
+    }
+}
+
+/// Página de arranque fallido (timeout o node.exe cerrado) con `summary` y el final
+/// de server.log (`pages/timeout.html` o la embebida).
+#[cfg(not(dev))]
+fn timeout_page(
+    app: &tauri::AppHandle,
+    port: u16,
+    summary: &str,
+    tail: &str,
+    report: &str,
+    log_path: &std::path::Path,
+) -> String {
+    if let Some(template) = page_template(app, "timeout") {
+        return render_page(&template, &[
+            ("port", port.to_string()),
+            ("summary", data_url_text(summary)),
+            ("log", data_url_text(&log_path.display().to_string())),
+            ("tail", data_url_text(tail)),
+            ("footer", error_page_footer(report, log_path)),
+        ]);
+    }
+    format!(
+        concat!(
+            "data:text/html,<html><head><meta charset='utf-8'></head>",
+            "<body style='background:%230d1117;color:%23fff;",
+            "font-family:sans-serif;display:flex;align-items:center;",
+            "justify-content:center;height:100vh;margin:0'>",
+            "<div style='text-align:center;padding:2rem;max-width:700px;width:100%'>",
+            "<h2 style='color:%23f97316;margin-bottom:.5rem'>",
+            "El servidor no arranco</h2>",
+            "<p style='color:%23aaa;margin-bottom:1rem;font-size:14px'>",
+            "{summary}</p>",
+            "<pre style='background:%23111;border:1px solid %23333;",
+            "border-radius:8px;padding:1rem;font-size:11px;",
+            "text-align:left;overflow:auto;max-height:250px;",
+            "color:%23f87171;white-space:pre-wrap;word-break:break-all'>",
+            "{tail}</pre>",
+            "{footer}",
+            "</div></body></html>"
+        ),
+        summary = data_url_text(summary),
+        tail = data_url_text(tail),
+        footer = error_page_footer(report, log_path)
+    )
+}
+
+/// Página de error genérica (mismo estilo que las demás páginas nativas).
+#[cfg(not(dev))]
+fn error_page(title: &str, detail: &str, log_path: &std::path::Path) -> String {
+    let report = support_report(title, detail, log_path, None);
+    format!(
+        concat!(
+            "data:text/html,<html><head><meta charset='utf-8'></head>",
+            "<body style='background:%230d1117;color:%23fff;",
+            "font-family:sans-serif;display:flex;align-items:center;",
+            "justify-content:center;height:100vh;margin:0'>",
+            "<div style='text-align:center;padding:2rem;max-width:600px'>",
+            "<h2 style='color:%23ef4444;margin-bottom:1rem'>{title}</h2>",
+            "<p style='color:%23aaa;margin-bottom:1rem'>{detail}</p>",
+            "{footer}",
+            "</div></body></html>"
+        ),
+        title = data_url_text(title),
+        detail = data_url_text(detail),
+        footer = error_page_footer(&report, log_path)
+    )
+}
+
+/// Ventana "main" de tauri.conf.json. Si falta, la configuración está rota y no hay
+/// dónde mostrar nada: se registra el error y se sale con código 1 en lugar de lanzar
+/// node.exe sin interfaz (la app quedaría colgada en segundo plano).
+#[cfg(not(dev))]
+fn require_main_window(app: &tauri::AppHandle, log_path: &std::path::Path) -> Option<tauri::WebviewWindow> {
+    let window = app.get_webview_window("main");
+    if window.is_none() {
+        log_at(log_path, LogLevel::Error, "FATAL: No se encontró la ventana 'main' (revisa tauri.conf.json) — saliendo");
+        log_json(log_path, LogLevel::Error, "Ventana 'main' no encontrada");
+        report_fatal_event(app, "no se encontró la ventana 'main' (tauri.conf.json)");
+        app.exit(1);
+    }
+    window
+}
+
+/// Registra un fallo al lanzar node.exe y muestra la página de error.
+#[cfg(not(dev))]
+fn show_spawn_error(app: &tauri::AppHandle, log_path: &std::path::Path, e: &str) {
+    log_at(log_path, LogLevel::Error, &format!("ERROR spawning: {}", e));
+    log_json(log_path, LogLevel::Error, &format!("Error al lanzar node.exe: {}", e));
+    report_fatal_event(app, &format!("error al lanzar node.exe: {}", e));
+    if let Some(window) = app.get_webview_window("main") {
+        let html = error_page("Error al iniciar servidor", e, log_path);
+        if let Ok(url) = html.parse::<tauri::Url>() {
+            let _ = window.navigate(url);
+        }
+        let _ = window.show();
+    }
+}
+
+/// Puerto nuevo del servidor: lo anota en `ServerState` y en la config de lanzamiento
+/// y lo recuerda en port.txt (salvo con STACKLUME_RANDOM_PORT).
+#[cfg(not(dev))]
+fn set_server_port(app: &tauri::AppHandle, port: u16) {
+    let state = app.state::<ServerState>();
+    *state.port.lock().unwrap() = port;
+    if let Some(launch) = state.launch.lock().unwrap().as_mut() {
+        launch.port = port;
+    }
+    if let (false, Ok(app_data)) = (random_port_enabled(), app_data_dir(app)) {
+        save_last_port(&app_data, port);
+    }
+}
+
+/// node.exe no pudo abrir su puerto: otro proceso lo ocupó entre `find_free_port` y
+/// el arranque de node (TOCTOU). Se reconoce por EADDRINUSE en server.log.
+#[cfg(not(dev))]
+fn server_log_addr_in_use(slog_path: &std::path::Path) -> bool {
+    std::fs::read_to_string(slog_path)
+        .map(|s| s.contains("EADDRINUSE"))
+        .unwrap_or(false)
+}
+
+/// Relanza node.exe una vez en un puerto nuevo tras un EADDRINUSE, con un hilo de
+/// espera propio que ya no reintenta. Devuelve el puerto nuevo.
+#[cfg(not(dev))]
+fn retry_on_new_port(
+    app: &tauri::AppHandle,
+    old_port: u16,
+    log_path: &std::path::Path,
+    slog_path: &std::path::Path,
+    reveal: bool,
+) -> Result<u16, String> {
+    let port = find_free_port(None)
+        .filter(|p| *p != old_port)
+        .ok_or_else(|| format!("Puerto {} ocupado y sin puerto alternativo", old_port))?;
+    log(log_path, &format!("EADDRINUSE en el puerto {}: reintentando una vez en el {}", old_port, port));
+    log_json(log_path, LogLevel::Warn, &format!("Puerto {} ocupado al arrancar, reintento en {}", old_port, port));
+    set_server_port(app, port);
+    let launch = refresh_server_launch(app)?;
+    let (pid, generation) = spawn_server(app, &launch, log_path, slog_path).map_err(|e| e.to_string())?;
+    start_server_waiter_inner(
+        app.clone(),
+        port,
+        (pid, generation),
+        log_path.to_path_buf(),
+        slog_path.to_path_buf(),
+        reveal,
+        false,
+    );
+    Ok(port)
+}
+
+/// Hilo de espera: navega al servidor cuando responda al health check, o muestra
+/// la página de timeout con las últimas líneas de server.log si no llega a arrancar.
+/// `spawned` es el (PID, generación) que devolvió `spawn_server` para este arranque.
+/// Con `reveal` (primer arranque) además muestra la ventana según `launch_visibility`.
+/// Si node cae con EADDRINUSE se relanza una vez en otro puerto (`retry_on_new_port`).
+/// Emite `loading-status` en cada etapa de la espera (ver `LoadingStage`),
+/// `server-ready` ({port, startup_ms}) antes de navegar o `server-timeout`
+/// ({port, timeout_s, reason, summary, tail}) si no arranca; `reason` es "exited",
+/// "incompatible_arch" (node.exe de otra arquitectura), "no_connection" o "unhealthy".
+#[cfg(not(dev))]
+fn start_server_waiter(
+    app_handle: tauri::AppHandle,
+    port: u16,
+    spawned: (u32, u64),
+    log_path2: std::path::PathBuf,
+    slog_path2: std::path::PathBuf,
+    reveal: bool,
+) {
+    start_server_waiter_inner(app_handle, port, spawned, log_path2, slog_path2, reveal, true);
+}
+
+/// `start_server_waiter` con el reintento por EADDRINUSE opcional (`retry_port`).
+#[cfg(not(dev))]
+fn start_server_waiter_inner(
+    app_handle: tauri::AppHandle,
+    port: u16,
+    spawned: (u32, u64),
+    log_path2: std::path::PathBuf,
+    slog_path2: std::path::PathBuf,
+    reveal: bool,
+    retry_port: bool,
+) {
+    std::thread::spawn(move || {
+        let started = std::time::Instant::now();
+        let timeout_s = HealthConfig::resolve().timeout_ms as f64 / 1000.0;
+        log(&log_path2, &format!("Esperando que el servidor arranque (timeout {}s)...", timeout_s));
+        let (spawned_pid, generation) = (Some(spawned.0), spawned.1);
+        let superseded = || {
+            app_handle.state::<ServerState>().spawn_generation.load(Ordering::SeqCst) != generation
+        };
+        let exited = || !matches!(child_outcome(&app_handle, spawned_pid), ChildOutcome::Running);
+        let on_stage = |stage| emit_loading_status(&app_handle, stage);
+        let result = wait_for_server(
+            port,
+            &app_handle.state::<ServerState>().cancel,
+            &exited,
+            &on_stage,
+            &log_path2,
+        );
+        let outcome = child_outcome(&app_handle, spawned_pid);
+
+        if superseded() {
+            // Otro spawn_server (reinicio rápido, watchdog) lanzó un node.exe nuevo o se
+            // paró a propósito entretanto: navegar o mostrar un error aquí pisaría a su
+            // hilo de espera o a la parada pedida
+            log(&log_path2, &format!("Espera abandonada: arranque #{} sustituido o detenido", generation));
+        } else if result == ServerWaitResult::Ready {
+            let spawned_at = *app_handle.state::<ServerState>().spawned_at.lock().unwrap();
+            let startup_ms = spawned_at.unwrap_or(started).elapsed().as_millis() as u64;
+            *app_handle.state::<ServerState>().last_startup_ms.lock().unwrap() = Some(startup_ms);
+            log(&log_path2, &format!("Arranque del servidor: {} ms", startup_ms));
+            log_json(&log_path2, LogLevel::Info, &format!("Arranque del servidor: {} ms", startup_ms));
+            // Solo con splash visible: en reinicios o con arranque en caliente no hay
+            // página de carga que mantener
+            if reveal && !warm_start_enabled() {
+                let floor = std::time::Duration::from_millis(min_splash_ms());
+                if let Some(rest) = floor.checked_sub(started.elapsed()).filter(|d| !d.is_zero()) {
+                    std::thread::sleep(rest);
+                }
+            }
+            log(&log_path2, "Servidor listo — navegando");
+            log_json(&log_path2, LogLevel::Info, &format!("Servidor listo en el puerto {}", port));
+            // Levantar el guard ANTES de navegar (si no, bloquearía esta navegación)
+            app_handle.state::<ServerState>().ready.store(true, Ordering::SeqCst);
+            emit_loading_status(&app_handle, LoadingStage::Ready);
+            run_on_ready_hook(&app_handle, port, &log_path2);
+            let _ = app_handle.emit("server-ready", serde_json::json!({
+                "port": port,
+                "startup_ms": startup_ms,
+            }));
+            if let Some(window) = app_handle.get_webview_window("main") {
+                let url_str = server_url(port);
+                if let Ok(url) = url_str.parse::<tauri::Url>() {
+                    let rn = window.navigate(url);
+                    // En reinicios no se toca la visibilidad (puede estar oculta en el tray)
+                    if reveal {
+                        let rs = present_main_window(&window, launch_visibility(&app_handle));
+                        log(&log_path2, &format!("navigate: {:?} | show: {:?}", rn, rs));
+                    } else {
+                        log(&log_path2, &format!("navigate: {:?}", rn));
+                    }
+                }
+            }
+        } else if app_handle.state::<ServerState>().cancel.load(Ordering::SeqCst) {
+            log(&log_path2, "Arranque cancelado por el usuario");
+        } else if let ChildOutcome::Gone = outcome {
+            // Parada intencionada o relanzamiento: el nuevo hilo de espera se encarga
+            log(&log_path2, "Espera abandonada: node.exe se detuvo o se relanzó entretanto");
+        } else if retry_port
+            && matches!(outcome, ChildOutcome::Exited(_))
+            && server_log_addr_in_use(&slog_path2)
+        {
+            if let Err(e) = retry_on_new_port(&app_handle, port, &log_path2, &slog_path2, reveal) {
+                show_spawn_error(&app_handle, &log_path2, &format!("Puerto {} ocupado: {}", port, e));
+            }
+        } else {
+            // Timeout o salida prematura: leer el server.log para mostrar el error
+            let wrong_arch = matches!(outcome, ChildOutcome::Exited(status) if is_wrong_arch_exit(status));
+            let (reason, summary) = match (outcome, result) {
+                (ChildOutcome::Exited(status), _) => {
+                    let msg = format!(
+                        "node.exe terminó durante el arranque tras {:.1}s ({})",
+                        started.elapsed().as_secs_f64(),
+                        describe_exit(status)
+                    );
+                    log_at(&log_path2, LogLevel::Error, &format!("ERROR: {}", msg));
+                    report_fatal_event(&app_handle, &msg);
+                    let reason = if wrong_arch { "incompatible_arch" } else { "exited" };
+                    (reason, format!("Puerto {} - {}", port, msg))
+                }
+                (_, ServerWaitResult::TimedOutUnhealthy(status)) => {
+                    let msg = format!(
+                        "el servidor está arriba pero {} devuelve HTTP {}",
+                        HealthConfig::resolve().path,
+                        status
+                    );
+                    log_at(&log_path2, LogLevel::Error, &format!("TIMEOUT: {} tras {}s", msg, timeout_s));
+                    report_fatal_event(&app_handle, &format!("{} (puerto {})", msg, port));
+                    ("unhealthy", format!("Puerto {} - {}", port, msg))
+                }
+                _ => {
+                    log_at(&log_path2, LogLevel::Error, &format!("TIMEOUT: El servidor no respondio en {}s", timeout_s));
+                    report_fatal_event(&app_handle, &format!(
+                        "el servidor no respondió en el puerto {} (ver server.log)",
+                        port
+                    ));
+                    ("no_connection", format!("Puerto {} - timeout {}s", port, timeout_s))
+                }
+            };
+            log_json(&log_path2, LogLevel::Error, &summary);
+            app_handle.state::<ServerState>().supervision.lock().unwrap().startup_failures += 1;
+            let mut tail = std::fs::read_to_string(&slog_path2).unwrap_or_default();
+            if tail.trim().is_empty() {
+                // Crash tan rápido que node no llegó a escribir nada: al menos mostrar
+                // el código de salida y la causa probable
+                tail = match server_exit_status(&app_handle) {
+                    Some(status) => format!(
+                        "node.exe terminó sin escribir nada en server.log.\nSalida: {}\n{}",
+                        describe_exit(status),
+                        crash_hint(status)
+                    ),
+                    None => "(servidor sin output)".into(),
+                };
+            }
+            let tail_last: String = tail
+                .lines()
+                .rev()
+                .take(20)
+                .collect::<Vec<_>>()
+                .iter()
+                .rev()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n");
+            log(&log_path2, &format!("Server.log tail:\n{}", tail_last));
+            let _ = app_handle.emit("server-timeout", serde_json::json!({
+                "port": port,
+                "timeout_s": timeout_s,
+                "reason": reason,
+                "summary": summary,
+                "tail": tail_last,
+            }));
+
+            // Mostrar página de error con los últimos logs del servidor (o la de
+            // arquitectura, donde el log no aporta nada)
+            if wrong_arch {
+                show_arch_error(&app_handle, &log_path2);
+            } else if let Some(window) = app_handle.get_webview_window("main") {
+                let report = support_report("El servidor no arrancó", &summary, &log_path2, Some(&tail_last));
+                let html = timeout_page(&app_handle, port, &summary, &tail_last, &report, &log_path2);
+                if let Ok(url) = html.parse::<tauri::Url>() {
+                    let rn = window.navigate(url);
+                    let rs = window.show();
+                    log(&log_path2, &format!("error page nav: {:?} | show: {:?}", rn, rs));
+                }
+            }
+        }
+    });
+}
+
+/// (Re)arranca el servidor con la misma configuración: para el node.exe actual,
+/// re-resuelve los recursos, vuelve a mostrar el splash y lanza node + hilo de espera.
+/// Devuelve el PID del nuevo proceso.
+#[cfg(not(dev))]
+fn ensure_server(app: &tauri::AppHandle) -> Result<u32, String> {
+    let app_data = app_data_dir(app).unwrap_or_default();
+    let log_path = app_data.join("stacklume.log");
+    let slog_path = app_data.join("server.log");
+
+    if let Some(pid) = stop_server_child(app) {
+        log(&log_path, &format!("ensure_server: node.exe anterior (PID {}) detenido", pid));
+    }
+
+    let launch = match refresh_server_launch(app) {
+        Ok(l) => l,
+        Err(e) => {
+            if let Some(window) = app.get_webview_window("main") {
+                let html = error_page("Stacklume se ha movido", &e, &log_path);
+                if let Ok(url) = html.parse::<tauri::Url>() {
+                    let _ = window.navigate(url);
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    let state = app.state::<ServerState>();
+    state.cancel.store(false, Ordering::SeqCst);
+    if let Some(window) = app.get_webview_window("main") {
+        if let Ok(url) = loading_page(app).parse::<tauri::Url>() {
+            let _ = window.navigate(url);
+        }
+    }
+
+    let (pid, generation) = spawn_server(app, &launch, &log_path, &slog_path).map_err(|e| {
+        show_node_spawn_error(app, &log_path, &e);
+        e.to_string()
+    })?;
+    start_server_waiter(app.clone(), launch.port, (pid, generation), log_path, slog_path, false);
+    Ok(pid)
+}
+
+/// Qué ha sido del node.exe que lanzó un intento de arranque (ver `child_outcome`).
+#[cfg(not(dev))]
+enum ChildOutcome {
+    /// Sigue en ejecución
+    Running,
+    /// Terminó por sí mismo (crash o salida prematura)
+    Exited(std::process::ExitStatus),
+    /// Ya no está en `ServerState` sin haber fallado: se detuvo a propósito o se relanzó
+    Gone,
+}
+
+/// Estado del node.exe con PID `spawned_pid`. Comprueba el Child guardado con
+/// `try_wait` y, si el watchdog ya lo retiró, el `last_exit` que anotó.
+#[cfg(not(dev))]
+fn child_outcome(app: &tauri::AppHandle, spawned_pid: Option<u32>) -> ChildOutcome {
+    let state = app.state::<ServerState>();
+    if let Some(child) = state.node_child.lock().unwrap().as_mut() {
+        if Some(child.id()) == spawned_pid {
+            return match child.try_wait() {
+                Ok(Some(status)) => ChildOutcome::Exited(status),
+                _ => ChildOutcome::Running,
+            };
+        }
+        return ChildOutcome::Gone;
+    }
+    let last = *state.last_exit.lock().unwrap();
+    match last {
+        Some(status) => ChildOutcome::Exited(status),
+        None => ChildOutcome::Gone,
+    }
+}
+
+/// Estado de salida de node.exe si ya ha terminado: el del proceso actual (si acaba
+/// de morir y el watchdog aún no lo ha recogido) o el último anotado por el watchdog.
+#[cfg(not(dev))]
+fn server_exit_status(app: &tauri::AppHandle) -> Option<std::process::ExitStatus> {
+    let state = app.state::<ServerState>();
+    let current = state
+        .node_child
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|c| c.try_wait().ok().flatten());
+    let last = *state.last_exit.lock().unwrap();
+    current.or(last)
+}
+
+/// Código de salida legible. En Windows los crashes del loader llegan como NTSTATUS
+/// (0xC000xxxx), que se muestran en hexadecimal con su significado.
+#[cfg(not(dev))]
+fn describe_exit(status: std::process::ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = status.signal() {
+            return format!("señal {}", sig);
+        }
+    }
+    match status.code() {
+        Some(code) if (code as u32) >= 0xC000_0000 => {
+            let name = match code as u32 {
+                0xC000_0005 => " (STATUS_ACCESS_VIOLATION)",
+                0xC000_007B => " (STATUS_INVALID_IMAGE_FORMAT)",
+                0xC000_0135 => " (STATUS_DLL_NOT_FOUND)",
+                0xC000_0139 => " (STATUS_ENTRYPOINT_NOT_FOUND)",
+                0xC000_0142 => " (STATUS_DLL_INIT_FAILED)",
+                _ => "",
+            };
+            format!("código 0x{:08X}{}", code as u32, name)
+        }
+        Some(code) => format!("código {}", code),
+        None => "sin código de salida".to_string(),
+    }
+}
+
+/// Causa probable de un crash inmediato sin output, según el código de salida.
+#[cfg(not(dev))]
+fn crash_hint(status: std::process::ExitStatus) -> &'static str {
+    match status.code().map(|c| c as u32) {
+        Some(0xC000_0135) | Some(0xC000_0139) | Some(0xC000_0142) => {
+            "Falta una DLL del sistema: instala o repara Microsoft Visual C++ Redistributable (x64)."
+        }
+        Some(0xC000_007B) => {
+            "node.exe no es compatible con este sistema (arquitectura incorrecta o archivo dañado): reinstala Stacklume."
+        }
+        Some(0xC000_0005) => {
+            "node.exe se cerró por un acceso a memoria inválido; un antivirus puede estar bloqueándolo."
+        }
+        _ => "node.exe se cerró antes de escribir nada: revisa que el antivirus no lo bloquee y reinstala si persiste.",
+    }
+}
+
+/// node.exe salió con un código de imagen inválida: en Windows es casi siempre un
+/// node.exe de otra arquitectura (p. ej. arm64 en un equipo x64).
+#[cfg(not(dev))]
+fn is_wrong_arch_exit(status: std::process::ExitStatus) -> bool {
+    matches!(
+        status.code().map(|c| c as u32),
+        // STATUS_INVALID_IMAGE_FORMAT, STATUS_INVALID_IMAGE_NOT_MZ, STATUS_INVALID_IMAGE_WIN_64
+        Some(0xC000_007B) | Some(0xC000_012F) | Some(0xC000_0359)
+    )
+}
+
+/// El OS rechazó lanzar node.exe por su formato: ERROR_BAD_EXE_FORMAT (193) o
+/// ERROR_EXE_MACHINE_TYPE_MISMATCH (216) en Windows, ENOEXEC en Unix.
+#[cfg(not(dev))]
+fn is_wrong_arch_error(e: &std::io::Error) -> bool {
+    match e.raw_os_error() {
+        #[cfg(windows)]
+        Some(193) | Some(216) => true,
+        #[cfg(unix)]
+        Some(8) => true,
+        _ => false,
+    }
+}
+
+/// Arquitectura de un ejecutable PE según el campo Machine de su cabecera COFF
+/// (None si no es un PE legible, p. ej. el binario ELF de Linux).
+#[cfg(not(dev))]
+fn pe_machine(path: &std::path::Path) -> Option<&'static str> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut dos = [0u8; 64];
+    file.read_exact(&mut dos).ok()?;
+    if &dos[..2] != b"MZ" {
+        return None;
+    }
+    let pe_offset = u32::from_le_bytes([dos[60], dos[61], dos[62], dos[63]]);
+    file.seek(SeekFrom::Start(pe_offset as u64)).ok()?;
+    let mut header = [0u8; 6];
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] != b"PE\0\0" {
+        return None;
+    }
+    Some(match u16::from_le_bytes([header[4], header[5]]) {
+        0x8664 => "x86_64",
+        0xAA64 => "aarch64",
+        0x014C => "x86",
+        _ => "desconocida",
+    })
+}
+
+/// Página para un node.exe de otra arquitectura, con la de ambos ejecutables.
+#[cfg(not(dev))]
+fn show_arch_error(app: &tauri::AppHandle, log_path: &std::path::Path) {
+    let node_exe = app
+        .state::<ServerState>()
+        .launch
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|l| l.node_exe.clone());
+    let node_arch = node_exe.as_deref().and_then(pe_machine).unwrap_or("desconocida");
+    let detail = format!(
+        "El node.exe incluido ({}) no es compatible con la arquitectura de este equipo ({}). \
+         Reinstala Stacklume con el instalador para tu sistema.",
+        node_arch,
+        std::env::consts::ARCH
+    );
+    log_at(log_path, LogLevel::Error, &format!(
+        "ERROR: node.exe incompatible: node {} | stacklume {}",
+        node_arch, std::env::consts::ARCH
+    ));
+    log_json(log_path, LogLevel::Error, &format!("node.exe de arquitectura {} en {}", node_arch, std::env::consts::ARCH));
+    report_fatal_event(app, &format!("node.exe incompatible ({} en {})", node_arch, std::env::consts::ARCH));
+    if let Some(window) = app.get_webview_window("main") {
+        let html = error_page("Node incompatible con la arquitectura de este equipo", &detail, log_path);
+        if let Ok(url) = html.parse::<tauri::Url>() {
+            let _ = window.navigate(url);
+        }
+        let _ = window.show();
+    }
+}
+
+/// Error al lanzar node.exe: la página de arquitectura si el OS rechazó el formato
+/// del ejecutable, la genérica en otro caso.
+#[cfg(not(dev))]
+fn show_node_spawn_error(app: &tauri::AppHandle, log_path: &std::path::Path, e: &std::io::Error) {
+    if is_wrong_arch_error(e) {
+        log_at(log_path, LogLevel::Error, &format!("ERROR spawning: {}", e));
+        show_arch_error(app, log_path);
+    } else {
+        show_spawn_error(app, log_path, &e.to_string());
+    }
+}
+
+/// Reinicios automáticos tras un crash antes de rendirse (STACKLUME_MAX_RESTARTS, por defecto 3).
+#[cfg(not(dev))]
+fn max_restarts() -> u32 {
+    std::env::var("STACKLUME_MAX_RESTARTS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(3)
+}
+
+/// Anota en `SupervisionCounters` un reinicio del servidor y su motivo.
+#[cfg(not(dev))]
+fn note_restart(app: &tauri::AppHandle, reason: &str) {
+    let state = app.state::<ServerState>();
+    let mut counters = state.supervision.lock().unwrap();
+    counters.last_restart_reason = Some(reason.to_string());
+    counters.last_restart_at = Some(chrono::Local::now().to_rfc3339());
+}
+
+/// Tiempo máximo del hook STACKLUME_ON_READY antes de darlo por fallido.
+#[cfg(not(dev))]
+const ON_READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Dispara el hook `on_ready_hook()` en un hilo aparte (no bloquea la navegación)
+/// y deja el resultado en stacklume.log. Lo llama el waiter una vez por arranque
+/// correcto del servidor.
+#[cfg(not(dev))]
+fn run_on_ready_hook(app: &tauri::AppHandle, port: u16, log_path: &std::path::Path) {
+    let Some(hook) = on_ready_hook() else { return };
+    let pid = *app.state::<ServerState>().pid.lock().unwrap();
+    let log_path = log_path.to_path_buf();
+    std::thread::spawn(move || {
+        let pid_str = pid.map(|p| p.to_string()).unwrap_or_default();
+        let result = if hook.starts_with("http://") || hook.starts_with("https://") {
+            let body = serde_json::json!({ "port": port, "pid": pid }).to_string();
+            match ureq::post(&hook)
+                .timeout(ON_READY_TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+            {
+                Ok(resp) => format!("POST {} -> HTTP {}", hook, resp.status()),
+                Err(e) => format!("POST {} falló: {}", hook, e),
+            }
+        } else {
+            #[cfg(windows)]
+            let mut cmd = {
+                use std::os::windows::process::CommandExt;
+                let mut c = std::process::Command::new("cmd");
+                c.arg("/C").arg(&hook);
+                c.creation_flags(0x08000000); // CREATE_NO_WINDOW
+                c
+            };
+            #[cfg(not(windows))]
+            let mut cmd = {
+                let mut c = std::process::Command::new("sh");
+                c.arg("-c").arg(&hook);
+                c
+            };
+            cmd.env("STACKLUME_PORT", port.to_string())
+                .env("STACKLUME_PID", &pid_str)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null());
+            match cmd.spawn() {
+                Ok(mut child) => {
+                    let started = std::time::Instant::now();
+                    loop {
+                        match child.try_wait() {
+                            Ok(Some(status)) => break format!("'{}' terminó: {}", hook, status),
+                            Ok(None) if started.elapsed() < ON_READY_TIMEOUT => {
+                                std::thread::sleep(std::time::Duration::from_millis(100));
+                            }
+                            Ok(None) => {
+                                let _ = child.kill();
+                                let _ = child.wait();
+                                break format!("'{}' superó {} s y se terminó", hook, ON_READY_TIMEOUT.as_secs());
+                            }
+                            Err(e) => break format!("'{}': error esperando el proceso: {}", hook, e),
+                        }
+                    }
+                }
+                Err(e) => format!("'{}' no se pudo lanzar: {}", hook, e),
+            }
+        };
+        log(&log_path, &format!("STACKLUME_ON_READY: {}", result));
+    });
+}
+
+/// Watchdog de node.exe: comprueba cada segundo con `try_wait` si el proceso guardado
+/// en `ServerState` ha terminado. Las paradas intencionadas (`stop_server_child`)
+/// sacan antes el Child del estado, así que cualquier salida vista aquí es un crash:
+/// se registra el código de salida y se relanza con `ensure_server` (que vuelve a
+/// navegar cuando el nuevo servidor responde), hasta `max_restarts()` veces con
+/// backoff exponencial. Si el servidor aguanta 5 min, el contador se reinicia.
+/// Los crashes antes de quedar listo no se relanzan: los informa `start_server_waiter`.
+#[cfg(not(dev))]
+fn start_server_watchdog(app: tauri::AppHandle) {
+    std::thread::spawn(move || {
+        let max = max_restarts();
+        let mut restarts = 0u32;
+        let mut last_restart = std::time::Instant::now();
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            let state = app.state::<ServerState>();
+            let exited = {
+                let mut guard = state.node_child.lock().unwrap();
+                match guard.as_mut().map(|c| (c.id(), c.try_wait())) {
+                    Some((pid, Ok(Some(status)))) => {
+                        guard.take();
+                        Some((pid, status))
+                    }
+                    _ => None,
+                }
+            };
+            let Some((pid, status)) = exited else { continue };
+            release_node_job(&app);
+            let was_ready = state.ready.swap(false, Ordering::SeqCst);
+            *state.pid.lock().unwrap() = None;
+            *state.last_exit.lock().unwrap() = Some(status);
+
+            let app_data = app_data_dir(&app).unwrap_or_default();
+            let log_path = app_data.join("stacklume.log");
+            // server.log se trunca al relanzar: guardar antes su final en stacklume.log
+            let tail = std::fs::read_to_string(app_data.join("server.log")).unwrap_or_default();
+            let tail_last = tail.lines().rev().take(20).collect::<Vec<_>>();
+            log_at(&log_path, LogLevel::Error, &format!(
+                "CRASH: node.exe (PID {}) terminó inesperadamente ({})\nServer.log tail:\n{}",
+                pid,
+                describe_exit(status),
+                tail_last.into_iter().rev().collect::<Vec<_>>().join("\n")
+            ));
+
+            // Si murió antes de responder al health check, el hilo de espera ya muestra
+            // el error con el código de salida: relanzar un server.js roto no lo arregla
+            if !was_ready {
+                log(&log_path, "Watchdog: node.exe cayó durante el arranque, sin reinicio automático");
+                continue;
+            }
+
+            if last_restart.elapsed() > std::time::Duration::from_secs(300) {
+                restarts = 0;
+            }
+            if restarts >= max {
+                log(&log_path, &format!("Watchdog: límite de {} reinicios alcanzado, servidor detenido", max));
+                report_fatal_event(&app, &format!(
+                    "node.exe terminó inesperadamente ({}) y se agotaron los {} reinicios",
+                    describe_exit(status), max
+                ));
+                if let Some(window) = app.get_webview_window("main") {
+                    let mut detail = format!(
+                        "node.exe terminó inesperadamente ({}) y no se pudo recuperar tras {} reinicios.",
+                        describe_exit(status), max
+                    );
+                    if tail.trim().is_empty() {
+                        detail.push(' ');
+                        detail.push_str(crash_hint(status));
+                    }
+                    let html = error_page("El servidor se ha detenido", &detail, &log_path);
+                    if let Ok(url) = html.parse::<tauri::Url>() {
+                        let _ = window.navigate(url);
+                    }
+                    let _ = window.show();
+                }
+                // Seguir vigilando: un reinicio manual posterior vuelve a estar cubierto
+                continue;
+            }
+
+            let backoff = std::time::Duration::from_secs(1 << restarts.min(5));
+            restarts += 1;
+            log(&log_path, &format!(
+                "Watchdog: reinicio {}/{} en {} s",
+                restarts, max, backoff.as_secs()
+            ));
+            std::thread::sleep(backoff);
+            last_restart = std::time::Instant::now();
+            state.supervision.lock().unwrap().crash_restarts += 1;
+            note_restart(&app, &format!("crash: {}", describe_exit(status)));
+            if let Err(e) = ensure_server(&app) {
+                log(&log_path, &format!("Watchdog: no se pudo relanzar node.exe: {}", e));
+            }
+        }
+    });
+}
+
+/// Línea de server.log enviada al frontend en el evento `server-log-line`.
+#[cfg(not(dev))]
+#[derive(Clone, serde::Serialize)]
+struct ServerLogLine {
+    /// Momento en que se leyó la línea (RFC 3339, hora local)
+    timestamp: String,
+    line: String,
+}
+
+/// Sigue server.log y emite las líneas nuevas al frontend como `server-log-line`
+/// (payload: array de `ServerLogLine`). Se agrupan por intervalos de 250 ms y como
+/// mucho `MAX_BATCH` líneas por evento para no saturar el puente IPC. Si el archivo
+/// se trunca (relanzamiento de node), se vuelve a leer desde el principio.
+#[cfg(not(dev))]
+fn start_server_log_stream(app: tauri::AppHandle, slog_path: std::path::PathBuf) {
+    use std::io::{Seek, SeekFrom};
+    const MAX_BATCH: usize = 200;
+    std::thread::spawn(move || {
+        let mut offset = 0u64;
+        let mut partial = String::new();
+        loop {
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            let Ok(mut file) = std::fs::File::open(&slog_path) else { continue };
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            if len < offset {
+                offset = 0;
+                partial.clear();
+            }
+            if len == offset || file.seek(SeekFrom::Start(offset)).is_err() {
+                continue;
+            }
+            let mut buf = Vec::new();
+            let Ok(read) = file.read_to_end(&mut buf) else { continue };
+            offset += read as u64;
+            partial.push_str(&String::from_utf8_lossy(&buf));
+
+            // Solo se emiten líneas completas; el resto espera a la siguiente lectura
+            let Some(end) = partial.rfind('\n') else { continue };
+            let complete: String = partial.drain(..=end).collect();
+            let timestamp = chrono::Local::now().to_rfc3339();
+            let lines: Vec<ServerLogLine> = complete
+                .lines()
+                .map(|l| ServerLogLine { timestamp: timestamp.clone(), line: l.trim_end_matches('\r').to_string() })
+                .collect();
+            for chunk in lines.chunks(MAX_BATCH) {
+                let _ = app.emit("server-log-line", chunk);
+            }
+        }
+    });
+}
+
+/// Tras ocultar la ventana al tray, detiene node.exe pasados `keep_alive_ms()` si la
+/// ventana no se ha vuelto a abrir. Con 0 no hace nada (node sigue vivo en el tray).
+#[cfg(not(dev))]
+fn schedule_keep_alive_stop(app: &tauri::AppHandle) {
+    let ms = keep_alive_ms();
+    if ms == 0 {
+        return;
+    }
+    let seq = app.state::<ServerState>().visibility_seq.fetch_add(1, Ordering::SeqCst) + 1;
+    let app = app.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(ms));
+        let state = app.state::<ServerState>();
+        if state.visibility_seq.load(Ordering::SeqCst) != seq {
+            return; // reabierta (o vuelta a ocultar) entretanto
+        }
+        let visible = app
+            .get_webview_window("main")
+            .and_then(|w| w.is_visible().ok())
+            .unwrap_or(false);
+        if visible {
+            return;
+        }
+        if let Some(pid) = stop_server_child(&app) {
+            state.idle_stopped.store(true, Ordering::SeqCst);
+            app_log(&app, &format!(
+                "keep-alive: node.exe (PID {}) detenido tras {} ms con la ventana oculta",
+                pid, ms
+            ));
+        }
+    });
+}
+
+/// Nivel de detalle del log. `log()` escribe en Info y `log_at` con el nivel que se
+/// le indique; Debug añade detalle extra (p.ej. cada sondeo del health check). Solo
+/// se escribe lo que el nivel efectivo permite.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// Nivel efectivo actual. Parte de `base_log_level()` (LOG_LEVEL_UNSET hasta el
+/// primer uso) y puede elevarse temporalmente con `boost_log_level`.
+static LOG_LEVEL: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(LOG_LEVEL_UNSET);
+const LOG_LEVEL_UNSET: u8 = u8::MAX;
+/// Se incrementa con cada boost: el temporizador solo revierte si sigue siendo el último.
+static LOG_BOOST_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Nivel configurado (STACKLUME_LOG_LEVEL: error, warn, info o debug; por defecto
+/// info), al que vuelve el log cuando termina un boost. Se lee una sola vez.
+fn base_log_level() -> LogLevel {
+    static BASE: std::sync::OnceLock<LogLevel> = std::sync::OnceLock::new();
+    *BASE.get_or_init(|| match std::env::var("STACKLUME_LOG_LEVEL") {
+        Ok(v) if !v.trim().is_empty() => LogLevel::parse(&v).unwrap_or_else(|| {
+            eprintln!("[Stacklume] WARN: STACKLUME_LOG_LEVEL='{}' no válido, usando 'info'", v);
+            LogLevel::Info
+        }),
+        _ => LogLevel::Info,
+    })
+}
+
+fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::SeqCst) {
+        LOG_LEVEL_UNSET => base_log_level(),
+        v => LogLevel::from_u8(v),
+    }
+}
+
+/// Como `log()`, pero solo si el nivel efectivo incluye Debug.
+#[cfg(not(dev))]
+fn log_debug(path: &std::path::Path, msg: &str) {
+    log_at(path, LogLevel::Debug, &format!("[debug] {}", msg));
+}
+
+/// Log estructurado opcional (STACKLUME_JSON_LOG=1): además de stacklume.log se
+/// escribe stacklume.jsonl con una línea JSON por evento del ciclo de vida.
+#[cfg(not(dev))]
+fn json_log_enabled() -> bool {
+    env_flag("STACKLUME_JSON_LOG")
+}
+
+/// Añade `{"timestamp","level","message"}` a stacklume.jsonl, junto a `path`
+/// (la ruta de stacklume.log). No hace nada si el log JSON no está activado.
+#[cfg(not(dev))]
+fn log_json(path: &std::path::Path, level: LogLevel, msg: &str) {
+    use std::io::Write;
+    if !json_log_enabled() {
+        return;
+    }
+    let line = serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "level": level.as_str(),
+        "message": msg,
+    });
+    if let Ok(mut f) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.with_extension("jsonl"))
+    {
+        let _ = writeln!(f, "{}", line);
+    }
+}
+
+/// Se incrementa al programar o cancelar un reinicio: el hilo armado termina en
+/// cuanto deja de ser el último.
+static RESTART_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Parsea una hora diaria "HH:MM" (24 h).
+fn parse_daily_time(s: &str) -> Option<chrono::NaiveTime> {
+    let (h, m) = s.trim().split_once(':')?;
+    if m.len() != 2 {
+        return None;
+    }
+    chrono::NaiveTime::from_hms_opt(h.parse().ok()?, m.parse().ok()?, 0)
+}
+
+/// Próxima ocurrencia de `time` en hora local (hoy si aún no ha pasado, si no mañana).
+#[cfg(not(dev))]
+fn next_daily_occurrence(time: chrono::NaiveTime) -> chrono::DateTime<chrono::Local> {
+    let now = chrono::Local::now();
+    let mut date = now.date_naive();
+    loop {
+        // earliest(): en el cambio de hora una hora local puede no existir o repetirse
+        if let Some(at) = date.and_time(time).and_local_timezone(chrono::Local).earliest() {
+            if at > now {
+                return at;
+            }
+        }
+        date = date.succ_opt().unwrap_or(date);
+    }
+}
+
+/// Aplazamientos de 10 minutos que admite el reinicio programado mientras la UI
+/// esté ocupada; agotados se fuerza, para que un `busy` que nunca se limpia no lo
+/// bloquee para siempre.
+#[cfg(not(dev))]
+const MAX_RESTART_POSTPONES: u32 = 6;
+
+/// Arma el hilo del reinicio diario según `restart_at` en settings.json (y desarma
+/// cualquier hilo anterior). A la hora indicada reinicia node con `ensure_server`,
+/// salvo que la UI esté ocupada (`ServerState::busy`, ver `set_server_busy`): entonces
+/// lo pospone 10 minutos, como mucho `MAX_RESTART_POSTPONES` veces.
+#[cfg(not(dev))]
+fn arm_scheduled_restart(app: &tauri::AppHandle) {
+    let seq = RESTART_SEQ.fetch_add(1, Ordering::SeqCst) + 1;
+    let Some(spec) = load_settings(app).restart_at else { return };
+    let Some(time) = parse_daily_time(&spec) else {
+        app_log_at(app, LogLevel::Warn, &format!("WARN: restart_at '{}' no válido, reinicio programado ignorado", spec));
+        return;
+    };
+    app_log(app, &format!("Reinicio diario programado a las {}", spec));
+
+    let app = app.clone();
+    std::thread::spawn(move || loop {
+        let mut at = next_daily_occurrence(time);
+        let mut postponed = 0;
+        loop {
+            if RESTART_SEQ.load(Ordering::SeqCst) != seq {
+                return;
+            }
+            let remaining = (at - chrono::Local::now()).to_std().unwrap_or_default();
+            if remaining.is_zero() {
+                if !app.state::<ServerState>().busy.load(Ordering::SeqCst) {
+                    break;
+                }
+                if postponed == MAX_RESTART_POSTPONES {
+                    app_log_at(&app, LogLevel::Warn, &format!(
+                        "WARN: Reinicio programado forzado: la UI sigue ocupada tras {} aplazamientos",
+                        postponed
+                    ));
+                    break;
+                }
+                postponed += 1;
+                app_log(&app, &format!(
+                    "Reinicio programado pospuesto 10 min: la UI está ocupada ({}/{})",
+                    postponed, MAX_RESTART_POSTPONES
+                ));
+                at = chrono::Local::now() + chrono::Duration::minutes(10);
+                continue;
+            }
+            // Sondeo en tramos cortos: se entera pronto de una cancelación y no se
+            // desfasa si el equipo se suspende entretanto
+            std::thread::sleep(remaining.min(std::time::Duration::from_secs(60)));
+        }
+        app_log(&app, "Reinicio programado: reiniciando servidor");
+        note_restart(&app, "programado");
+        if let Err(e) = ensure_server(&app) {
+            app_log(&app, &format!("Reinicio programado fallido: {}", e));
+        }
+    });
+}
+
+/// Generaciones anteriores que se conservan de cada log (`.1` es la más reciente).
+#[cfg(not(dev))]
+const LOG_GENERATIONS: u32 = 3;
+
+/// Rota `path` antes de empezar uno nuevo: `path.{keep-1}` → `path.{keep}`, …,
+/// `path` → `path.1`, descartando la más antigua. Un log vacío o inexistente no
+/// se rota, para no desplazar generaciones útiles con ejecuciones sin salida.
+#[cfg(not(dev))]
+fn rotate_log(path: &std::path::Path, keep: u32) {
+    let has_content = std::fs::metadata(path).map(|m| m.len() > 0).unwrap_or(false);
+    if keep == 0 || !has_content {
+        return;
+    }
+    let generation = |n: u32| {
+        let mut p = path.as_os_str().to_owned();
+        p.push(format!(".{}", n));
+        std::path::PathBuf::from(p)
+    };
+    let _ = std::fs::remove_file(generation(keep));
+    for n in (1..keep).rev() {
+        let _ = std::fs::rename(generation(n), generation(n + 1));
+    }
+    let _ = std::fs::rename(path, generation(1));
+}
+
+/// Escribe una línea de nivel Info al archivo de log de la aplicación. Avisos y
+/// errores van con `log_at` y su nivel explícito.
+#[cfg(any(test, not(dev)))]
+fn log(path: &std::path::Path, msg: &str) {
+    log_at(path, LogLevel::Info, msg);
+}
+
+/// Escribe `msg` en el log, precedido de la hora local en RFC 3339 con milisegundos
+/// (para cruzarla con server.log), solo si `level` entra en el nivel efectivo.
+fn log_at(path: &std::path::Path, level: LogLevel, msg: &str) {
+    use std::io::Write;
+    if level > log_level() {
+        return;
+    }
+    if let Ok(mut f) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+    {
+        let ts = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        let _ = writeln!(f, "{} {}", ts, msg);
+    }
+}
+
+/// Escribe en `stacklume.log` desde fuera del setup (comandos, hilos de fondo).
+fn app_log(app: &tauri::AppHandle, msg: &str) {
+    app_log_at(app, LogLevel::Info, msg);
+}
+
+/// `app_log` con nivel explícito (ver `log_at`).
+fn app_log_at(app: &tauri::AppHandle, level: LogLevel, msg: &str) {
+    if let Ok(dir) = app_data_dir(app) {
+        log_at(&dir.join("stacklume.log"), level, msg);
+    }
+}
+
+/// Copia embebida de `pages/recovery.html` (ver `show_recovery_page`).
+#[cfg(not(dev))]
+const RECOVERY_TEMPLATE: &str = concat!(
+    "<html><head><meta charset='utf-8'></head>",
+    "<body style='background:#0d1117;color:#fff;font-family:sans-serif;display:flex;",
+    "align-items:center;justify-content:center;height:100vh;margin:0'>",
+    "<div style='text-align:center;padding:2rem;max-width:600px'>",
+    "<h2 style='color:#ef4444;margin-bottom:1rem'>La base de datos está dañada</h2>",
+    "<p style='color:#aaa;margin-bottom:1rem'>{{detail}}</p>",
+    "<p style='color:#aaa;margin-bottom:1.5rem;font-size:14px'>El archivo dañado se conserva ",
+    "junto a la base de datos con la extensión .corrupt.</p>",
+    "<a href='stacklume://recover/restore' style='display:{{restore_display}};margin:.25rem;",
+    "padding:.6rem 1.2rem;background:#d4a520;color:#0d1117;border-radius:6px;",
+    "text-decoration:none;font-weight:600'>Restaurar copia de seguridad</a>",
+    "<a href='stacklume://recover/fresh' style='display:inline-block;margin:.25rem;",
+    "padding:.6rem 1.2rem;background:#222;color:#fff;border:1px solid #444;",
+    "border-radius:6px;text-decoration:none'>Empezar de cero</a>",
+    "{{footer}}",
+    "</div></body></html>"
+);
+
+/// Copia embebida de `pages/maintenance.html` (ver `show_maintenance_page`): la
+/// marca de la página de carga con el aviso y un botón que relanza el servidor.
+const MAINTENANCE_TEMPLATE: &str = concat!(
+    "<html><head><meta charset='utf-8'><style>",
+    "*{margin:0;padding:0;box-sizing:border-box}",
+    "html,body{height:100%;background:{{bg}};overflow:hidden}",
+    "body{display:flex;align-items:center;justify-content:center;",
+    "font-family:system-ui,-apple-system,sans-serif}",
+    ".wrap{display:flex;flex-direction:column;align-items:center;gap:32px;",
+    "max-width:520px;padding:2rem;text-align:center}",
+    ".brand{display:flex;align-items:center;gap:12px}",
+    ".logo{width:48px;height:48px;border-radius:12px;",
+    "background:linear-gradient(135deg,#d4a520,#b8860b);",
+    "display:flex;align-items:center;justify-content:center;",
+    "box-shadow:0 4px 24px rgba(212,165,32,0.40)}",
+    ".name{font-size:24px;font-weight:600;color:{{fg}};letter-spacing:-.3px}",
+    ".msg{font-size:14px;line-height:1.5;color:{{muted}};white-space:pre-wrap}",
+    ".retry{padding:.6rem 1.4rem;background:#d4a520;color:#0d1117;border-radius:6px;",
+    "text-decoration:none;font-weight:600;font-size:14px}",
+    "</style></head><body><div class='wrap'>",
+    "<div class='brand'>",
+    "<div class='logo'>",
+    "<svg width='28' height='28' viewBox='0 0 24 24' fill='none'",
+    " stroke='white' stroke-width='2'",
+    " stroke-linecap='round' stroke-linejoin='round'>",
+    "<rect x='3' y='3' width='7' height='7' rx='1'/>",
+    "<rect x='14' y='3' width='7' height='7' rx='1'/>",
+    "<rect x='3' y='14' width='7' height='7' rx='1'/>",
+    "<rect x='14' y='14' width='7' height='7' rx='1'/>",
+    "</svg></div>",
+    "<span class='name'>Stacklume</span>",
+    "</div>",
+    "<p class='msg'>{{message}}</p>",
+    "<a class='retry' href='stacklume://maintenance/retry'>Reintentar</a>",
+    "</div></body></html>"
+);
+
+/// Plantilla embebida de la página de carga. Replica el LoadingScreen de la app:
+/// logo bento + "Stacklume" + tres puntos. Se usa en el arranque y cuando el
+/// frontend pide volver a mostrar el splash (`show_loading_screen`), si no está
+/// empaquetada `pages/loading.html`. Los colores de fondo y texto son marcadores
+/// que rellena `loading_page` según el tema del sistema.
+const LOADING_TEMPLATE: &str = concat!(
+    "<html><head><meta charset='utf-8'><style>",
+    "*{margin:0;padding:0;box-sizing:border-box}",
+    "html,body{height:100%;background:{{bg}};overflow:hidden}",
+    "body{display:flex;align-items:center;justify-content:center;",
+    "font-family:system-ui,-apple-system,sans-serif}",
+    ".wrap{display:flex;flex-direction:column;align-items:center;",
+    "gap:32px;position:relative}",
+    ".brand{display:flex;align-items:center;gap:12px}",
+    ".logo-wrap{position:relative;width:48px;height:48px}",
+    ".glow{position:absolute;inset:-4px;border-radius:14px;",
+    "background:rgba(212,165,32,0.22);filter:blur(16px);",
+    "animation:pulse 2s ease-in-out infinite}",
+    ".logo{position:relative;width:48px;height:48px;border-radius:12px;",
+    "background:linear-gradient(135deg,#d4a520,#b8860b);",
+    "display:flex;align-items:center;justify-content:center;",
+    "box-shadow:0 4px 24px rgba(212,165,32,0.40)}",
+    ".name{font-size:24px;font-weight:600;color:{{fg}};letter-spacing:-.3px}",
+    ".dots{display:flex;gap:8px;align-items:center}",
+    ".dot{width:10px;height:10px;border-radius:50%;background:#d4a520}",
+    ".d1{animation:bounce .8s ease-in-out 0s infinite}",
+    ".d2{animation:bounce .8s ease-in-out .15s infinite}",
+    ".d3{animation:bounce .8s ease-in-out .3s infinite}",
+    ".lbl{font-size:13px;color:{{muted}}}",
+    ".c{position:absolute;width:64px;height:64px;",
+    "border-color:rgba(212,165,32,0.18);border-style:solid}",
+    ".tl{top:-88px;left:-88px;border-width:2px 0 0 2px;border-radius:12px 0 0 0}",
+    ".tr{top:-88px;right:-88px;border-width:2px 2px 0 0;border-radius:0 12px 0 0}",
+    ".bl{bottom:-88px;left:-88px;border-width:0 0 2px 2px;border-radius:0 0 0 12px}",
+    ".br{bottom:-88px;right:-88px;border-width:0 2px 2px 0;border-radius:0 0 12px 0}",
+    "@keyframes bounce{",
+    "0%,100%{transform:translateY(0);opacity:.4;transform:translateY(0) scale(.8)}",
+    "50%{transform:translateY(-8px) scale(1);opacity:1}}",
+    "@keyframes pulse{0%,100%{opacity:.5;transform:scale(1)}",
+    "50%{opacity:.85;transform:scale(1.25)}}",
+    "</style></head><body><div class='wrap'>",
+    "<div class='c tl'></div><div class='c tr'></div>",
+    "<div class='brand'>",
+    "<div class='logo-wrap'>",
+    "<div class='glow'></div>",
+    "<div class='logo'>",
+    "<svg width='28' height='28' viewBox='0 0 24 24' fill='none'",
+    " stroke='white' stroke-width='2'",
+    " stroke-linecap='round' stroke-linejoin='round'>",
+    "<rect x='3' y='3' width='7' height='7' rx='1'/>",
+    "<rect x='14' y='3' width='7' height='7' rx='1'/>",
+    "<rect x='3' y='14' width='7' height='7' rx='1'/>",
+    "<rect x='14' y='14' width='7' height='7' rx='1'/>",
+    "</svg></div></div>",
+    "<span class='name'>Stacklume</span>",
+    "</div>",
+    "<div class='dots'>",
+    "<div class='dot d1'></div>",
+    "<div class='dot d2'></div>",
+    "<div class='dot d3'></div>",
+    "</div>",
+    "<span class='lbl' id='status'>Iniciando Stacklume...</span>",
+    "<div class='c bl'></div><div class='c br'></div>",
+    "</div></body></html>"
+);
+
+// ─── Comandos Tauri ───────────────────────────────────────────────────────────
+
+/// Descarga el instalador de actualización desde GitHub y lo ejecuta.
+/// No usa tauri-plugin-updater (evita ACL plugin:updater|check).
+/// Funciona desde cualquier versión de la app que tenga este comando.
+#[tauri::command]
+async fn download_and_run_update(app: tauri::AppHandle, url: String) -> Result<(), String> {
+    // Validar URL con parsing estricto — previene path traversal y esquemas maliciosos
+    let parsed = url::Url::parse(&url).map_err(|e| format!("URL mal formada: {}", e))?;
+
+    // Solo HTTPS desde nuestro repo exacto
+    if parsed.scheme() != "https" {
+        return Err("Solo se permiten URLs HTTPS".to_string());
+    }
+    if parsed.host_str() != Some("github.com") {
+        return Err("Solo se permiten descargas desde github.com".to_string());
+    }
+
+    // Verificar path exacto: /SwonDev/Stacklume/releases/...
+    let path = parsed.path();
+    if !path.starts_with("/SwonDev/Stacklume/releases/") {
+        return Err("URL de actualización no válida".to_string());
+    }
+    // Bloquear path traversal (.. en cualquier segmento)
+    if path.split('/').any(|seg| seg == "..") {
+        return Err("Path traversal detectado".to_string());
+    }
+    // Debe terminar en .exe
+    if !path.to_lowercase().ends_with(".exe") {
+        return Err("Solo se permiten archivos .exe".to_string());
+    }
+
+    let installer_path = std::env::temp_dir().join("StacklumeUpdate.exe");
+    let installer_path_clone = installer_path.clone();
+
+    // Límite de descarga: 1 GB (el instalador con CUDA DLLs puede superar 500 MB)
+    const MAX_DOWNLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+    // Tamaño mínimo esperado: un instalador NSIS válido siempre supera 1 MB.
+    const MIN_INSTALLER_SIZE: u64 = 1024 * 1024;
+
+    // Descargar via curl con progreso — spawn + monitoreo del archivo por tamaño.
+    // curl -# escribe progreso a stderr pero CREATE_NO_WINDOW lo suprime.
+    // Alternativa: poll del tamaño del archivo cada segundo.
+    let app_clone = app.clone();
+    tokio::task::spawn_blocking(move || {
+        // Borrar archivo previo si existe
+        let _ = std::fs::remove_file(&installer_path_clone);
+        let out_path = installer_path_clone.to_string_lossy().to_string();
+
+        // Descargar con reintentos y resume automático.
+        // --retry 5 + -C - = si curl falla a mitad, reintenta continuando desde donde quedó.
+        // --retry-delay 3 = espera 3s entre reintentos.
+        // Sin --max-time (la descarga puede tardar >10 min con conexiones lentas).
+        let mut cmd = std::process::Command::new("C:\\Windows\\System32\\curl.exe");
+        cmd.arg("-L").arg("--fail")
+            .arg("--connect-timeout").arg("30")
+            .arg("--retry").arg("5")
+            .arg("--retry-delay").arg("3")
+            .arg("-C").arg("-") // Resume descargas parciales
+            .arg("-o").arg(&out_path)
+            .arg("-s")
+            .arg("--user-agent").arg("Stacklume/0.3 (desktop)")
+            .arg(&url);
+        #[cfg(windows)]
+        { use std::os::windows::process::CommandExt; cmd.creation_flags(0x08000000); }
+
+        // Spawn curl (no bloquear con output())
+        let mut child = cmd.spawn().map_err(|e| format!("Error ejecutando curl: {}", e))?;
+
+        // Monitor de progreso: poll tamaño del archivo cada 2 segundos
+        // Estimación: instalador ~625 MB (con CUDA DLLs)
+        const ESTIMATED_SIZE: u64 = 650_000_000;
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
+                        return Err(format!(
+                            "Descarga falló (código {}). Descargados: {:.0} MB. Verifica tu conexión a internet.",
+                            status.code().unwrap_or(-1),
+                            file_size as f64 / 1_048_576.0
+                        ));
+                    }
+                    break; // curl terminó exitosamente
+                }
+                Ok(None) => {
+                    // curl aún corriendo — emitir progreso
+                    let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
+                    let percent = std::cmp::min((file_size as f64 / ESTIMATED_SIZE as f64 * 100.0) as u64, 99);
+                    let _ = app_clone.emit("update:progress", percent);
+                }
+                Err(e) => {
+                    return Err(format!("Error monitoreando curl: {}", e));
+                }
+            }
+        }
+
+        // Emitir 100%
+        let _ = app_clone.emit("update:progress", 100u64);
+
+        // Verificar tamaño
+        let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
+        if file_size < MIN_INSTALLER_SIZE {
+            let _ = std::fs::remove_file(&installer_path_clone);
+            return Err(format!("Instalador inválido: {} bytes (descarga incompleta?)", file_size));
+        }
+
+        // Verificar no excede el máximo
+        if file_size > MAX_DOWNLOAD_SIZE {
+            let _ = std::fs::remove_file(&installer_path_clone);
+            return Err("Instalador demasiado grande".to_string());
+        }
+
+        // Matar llama-server ANTES del instalador — sus DLLs de CUDA bloquean la escritura.
+        {
+            let mut kill_cmd = std::process::Command::new("taskkill");
+            kill_cmd.args(["/f", "/im", "llama-server.exe"]);
+            #[cfg(windows)]
+            { use std::os::windows::process::CommandExt; kill_cmd.creation_flags(0x08000000); }
+            let _ = kill_cmd.output();
+        }
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        // Spawn directo del instalador. Los hooks NSIS usan "taskkill /F /IM stacklume.exe"
+        // SIN la bandera /T, por lo que no matan al instalador aunque sea hijo de la app.
+        std::process::Command::new(&installer_path_clone)
+            .spawn()
+            .map_err(|e| format!("Error ejecutando instalador: {}", e))?;
+
+        Ok::<(), String>(())
+    })
+    .await
+    .map_err(|e| format!("Error interno: {}", e))?
+}
+
+/// Abre una URL externa en el navegador por defecto del sistema.
+/// Valida la URL con el crate `url` y usa ShellExecuteW en Windows
+/// para evitar inyección de comandos (no pasa por cmd.exe).
+#[tauri::command]
+fn open_url(url: String) -> Result<(), String> {
+    // Validar la URL con parsing estricto
+    let parsed = url::Url::parse(&url).map_err(|e| format!("URL mal formada: {}", e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err("Protocolo no permitido: solo http/https".into());
+    }
+
+    #[cfg(windows)]
+    {
+        use std::ffi::OsStr;
+        use std::os::windows::ffi::OsStrExt;
+
+        // ShellExecuteW abre la URL directamente sin pasar por cmd.exe,
+        // eliminando el vector de inyección de comandos shell.
+        let wide_url: Vec<u16> = OsStr::new(url.as_str())
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let wide_open: Vec<u16> = OsStr::new("open")
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+
+        let result = unsafe {
+            windows_sys::Win32::UI::Shell::ShellExecuteW(
+                std::ptr::null_mut(),  // hwnd
+                wide_open.as_ptr(),    // lpOperation = "open"
+                wide_url.as_ptr(),     // lpFile = URL
+                std::ptr::null(),      // lpParameters
+                std::ptr::null(),      // lpDirectory
+                1,                     // nShowCmd = SW_SHOWNORMAL
+            )
+        };
+        if (result as isize) <= 32 {
+            return Err("Error al abrir la URL en el navegador".into());
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        std::process::Command::new("open")
+            .arg(&url)
+            .spawn()
+            .map_err(|e| e.to_string())?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        std::process::Command::new("xdg-open")
+            .arg(&url)
+            .spawn()
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Abre una ruta local (archivo o carpeta) con la aplicación por defecto del sistema.
+/// Usa ShellExecuteW en Windows para evitar inyección de comandos.
+#[tauri::command]
+fn open_local_path(path: String) -> Result<(), String> {
+    // Seguridad: rechazar bytes nulos y caracteres de escape
+    if path.contains('\0') || path.contains('\n') || path.contains('\r') {
+        return Err("Ruta inválida: contiene caracteres no permitidos".to_string());
+    }
+
+    #[cfg(windows)]
+    {
+        use std::ffi::OsStr;
+        use std::os::windows::ffi::OsStrExt;
+
+        let wide_path: Vec<u16> = OsStr::new(path.as_str())
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let wide_open: Vec<u16> = OsStr::new("open")
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+
+        let result = unsafe {
+            windows_sys::Win32::UI::Shell::ShellExecuteW(
+                std::ptr::null_mut(),
+                wide_open.as_ptr(),
+                wide_path.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+                1, // SW_SHOWNORMAL
+            )
+        };
+        if (result as isize) <= 32 {
+            return Err("No se pudo abrir la ruta".to_string());
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        std::process::Command::new("open")
+            .arg(&path)
+            .spawn()
+            .map_err(|e| e.to_string())?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        std::process::Command::new("xdg-open")
+            .arg(&path)
+            .spawn()
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Abre una ruta en VS Code (si está instalado en el PATH).
+#[tauri::command]
+fn open_in_vscode(path: String) -> Result<(), String> {
+    if path.contains('\0') || path.contains('\n') || path.contains('\r') {
+        return Err("Ruta inválida".to_string());
+    }
+    std::process::Command::new("code")
+        .arg(&path)
+        .spawn()
+        .map_err(|_| "VS Code no encontrado. Asegúrate de que 'code' está en el PATH.".to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn get_server_port(state: State<'_, ServerState>) -> u16 {
+    *state.port.lock().unwrap()
+}
+
+#[derive(serde::Serialize)]
+struct HealthConfigDto {
+    url: String,
+    #[serde(flatten)]
+    config: HealthConfig,
+}
+
+/// Configuración de health check en vigor (ruta, timeout, intervalo, esquema, host),
+/// para que el panel de diagnóstico muestre cómo se decide que el servidor está listo.
+#[tauri::command]
+fn health_config(state: State<'_, ServerState>) -> HealthConfigDto {
+    let config = HealthConfig::resolve();
+    HealthConfigDto {
+        url: config.url(*state.port.lock().unwrap()),
+        config,
+    }
+}
+
+/// Máximo del cuerpo que devuelve `check_health` (lo que sobra se descarta).
+const MAX_HEALTH_BODY_BYTES: u64 = 4 * 1024;
+
+#[derive(serde::Serialize)]
+struct HealthResponse {
+    status: u16,
+    /// Cuerpo de la respuesta, recortado a MAX_HEALTH_BODY_BYTES
+    body: String,
+    latency_ms: u64,
+}
+
+/// Sondeo puntual del endpoint de salud en el puerto actual (botón "Probar conexión"):
+/// un único GET, con la misma URL y cliente que el arranque y un timeout de 5 s.
+/// Un 4xx/5xx no es error: se devuelve su estado y cuerpo; solo falla si no conecta.
+#[tauri::command]
+async fn check_health(state: State<'_, ServerState>) -> Result<HealthResponse, String> {
+    use std::io::Read;
+    let config = HealthConfig::resolve();
+    let url = config.url(*state.port.lock().unwrap());
+    tokio::task::spawn_blocking(move || {
+        let started = std::time::Instant::now();
+        let resp = match config.agent().get(&url).timeout(std::time::Duration::from_secs(5)).call() {
+            Ok(resp) | Err(ureq::Error::Status(_, resp)) => resp,
+            Err(e) => return Err(format!("No se pudo conectar con {}: {}", url, e)),
+        };
+        let status = resp.status();
+        let mut body = Vec::new();
+        let _ = resp.into_reader().take(MAX_HEALTH_BODY_BYTES).read_to_end(&mut body);
+        Ok(HealthResponse {
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            latency_ms: started.elapsed().as_millis() as u64,
+        })
+    })
+    .await
+    .map_err(|e| format!("Error interno: {}", e))?
+}
+
+/// URL base del servidor Next.js (en dev lo sirve beforeDevCommand en localhost).
+fn server_url(port: u16) -> String {
+    if cfg!(dev) {
+        format!("http://localhost:{}", port)
+    } else {
+        let scheme = if use_tls() { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, connect_host(), port)
+    }
+}
+
+/// Vuelve a mostrar el splash nativo (p.ej. antes de una recarga pesada que
+/// rompería temporalmente la UI). Usa la misma página que el arranque.
+#[tauri::command]
+fn show_loading_screen(app: tauri::AppHandle) -> Result<(), String> {
+    let window = app
+        .get_webview_window("main")
+        .ok_or_else(|| "Ventana 'main' no encontrada".to_string())?;
+    let url = loading_page(&app).parse::<tauri::Url>().map_err(|e| e.to_string())?;
+    window.navigate(url).map_err(|e| e.to_string())
+}
+
+/// Muestra la página de mantenimiento con `message` (servidor detenido a propósito,
+/// p. ej. con `kill_server`) en lugar de un error del navegador. Su botón
+/// "Reintentar" relanza el servidor con `restart_server`.
+#[tauri::command]
+fn show_maintenance_page(app: tauri::AppHandle, message: String) -> Result<(), String> {
+    let window = app
+        .get_webview_window("main")
+        .ok_or_else(|| "Ventana 'main' no encontrada".to_string())?;
+    let url = maintenance_page(&app, &message).parse::<tauri::Url>().map_err(|e| e.to_string())?;
+    app.state::<ServerState>().maintenance_pending.store(true, Ordering::SeqCst);
+    window.navigate(url).map_err(|e| e.to_string())
+}
+
+/// Botón "Reintentar" de la página de mantenimiento (stacklume://maintenance/retry).
+/// Solo con la página a la vista; si el reinicio falla se vuelve a mostrar con el error.
+fn handle_maintenance_retry(app: &tauri::AppHandle) {
+    if !app.state::<ServerState>().maintenance_pending.swap(false, Ordering::SeqCst) {
+        app_log(app, "maintenance: reintento ignorado (página no activa)");
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<ServerState>();
+        if let Err(e) = restart_server(app.clone(), state).await {
+            app_log(&app, &format!("maintenance: reinicio fallido: {}", e));
+            let _ = show_maintenance_page(app.clone(), format!("No se pudo reiniciar el servidor: {}", e));
+        }
+    });
+}
+
+/// Retira el splash mostrado con `show_loading_screen` navegando de vuelta al servidor.
+#[tauri::command]
+fn hide_loading_screen(app: tauri::AppHandle, state: State<'_, ServerState>) -> Result<(), String> {
+    let window = app
+        .get_webview_window("main")
+        .ok_or_else(|| "Ventana 'main' no encontrada".to_string())?;
+    let port = *state.port.lock().unwrap();
+    let url = server_url(port).parse::<tauri::Url>().map_err(|e| e.to_string())?;
+    window.navigate(url).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_app_data_dir(app: tauri::AppHandle) -> String {
+    app_data_dir(&app)
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Abre la carpeta de datos (DB, logs, settings.json) en el explorador de archivos,
+/// para el botón "Abrir carpeta de datos". La crea si todavía no existe.
+#[tauri::command]
+fn open_app_data_dir(app: tauri::AppHandle) -> Result<(), String> {
+    let dir = app_data_dir(&app).map_err(|e| format!("No se pudo obtener app_data_dir: {}", e))?;
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("No se pudo crear {}: {}", dir.display(), e))?;
+    open_local_path(dir.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+fn minimize_window(window: tauri::WebviewWindow) {
+    let _ = window.minimize();
+}
+
+#[tauri::command]
+fn toggle_maximize_window(window: tauri::WebviewWindow) {
+    if window.is_maximized().unwrap_or(false) {
+        let _ = window.unmaximize();
+    } else {
+        let _ = window.maximize();
+    }
+}
+
+#[tauri::command]
+fn close_window(window: tauri::WebviewWindow) {
+    let _ = window.close();
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize)]
+struct WindowStateDto {
+    maximized: bool,
+    minimized: bool,
+    focused: bool,
+}
+
+fn window_state_of(window: &tauri::WebviewWindow) -> WindowStateDto {
+    WindowStateDto {
+        maximized: window.is_maximized().unwrap_or(false),
+        minimized: window.is_minimized().unwrap_or(false),
+        focused: window.is_focused().unwrap_or(false),
+    }
+}
+
+/// Estado de la ventana para que la titlebar propia muestre el icono correcto
+/// (maximizar/restaurar). Los cambios llegan también con `window-state-changed`.
+#[tauri::command]
+fn get_window_state(window: tauri::WebviewWindow) -> WindowStateDto {
+    window_state_of(&window)
+}
+
+/// Último estado emitido en `window-state-changed`: Resized llega muchas veces
+/// durante un arrastre y solo interesa avisar cuando algo cambia de verdad.
+static LAST_WINDOW_STATE: Mutex<Option<WindowStateDto>> = Mutex::new(None);
+
+/// Emite `window-state-changed` con el estado actual si difiere del último emitido.
+fn emit_window_state(window: &tauri::WebviewWindow) {
+    let current = window_state_of(window);
+    let mut last = LAST_WINDOW_STATE.lock().unwrap();
+    if *last != Some(current) {
+        *last = Some(current);
+        let _ = window.emit("window-state-changed", current);
+    }
+}
+
+// ─── Tema ─────────────────────────────────────────────────────────────────────
+
+/// Temas que aceptan `set_theme`/theme.txt; "system" sigue el tema del OS.
+const THEME_CHOICES: &[&str] = &["dark", "light", "system"];
+
+/// Ruta del archivo con el tema elegido por el usuario.
+fn theme_path(app: &tauri::AppHandle) -> std::path::PathBuf {
+    app_data_dir(app).unwrap_or_default().join("theme.txt")
+}
+
+/// Tema guardado en theme.txt ("dark" | "light" | "system"); "system" si no hay
+/// ninguno o no es válido.
+fn load_theme(app: &tauri::AppHandle) -> String {
+    std::fs::read_to_string(theme_path(app))
+        .ok()
+        .map(|s| s.trim().to_ascii_lowercase())
+        .filter(|t| THEME_CHOICES.contains(&t.as_str()))
+        .unwrap_or_else(|| "system".to_string())
+}
+
+/// Tema que se fuerza en la ventana para una elección (None = el del sistema).
+fn window_theme(choice: &str) -> Option<tauri::Theme> {
+    match choice {
+        "dark" => Some(tauri::Theme::Dark),
+        "light" => Some(tauri::Theme::Light),
+        _ => None,
+    }
+}
+
+/// Cambia el tema de la ventana y lo guarda en theme.txt para los próximos
+/// arranques (también decide la variante de la página de carga).
+#[tauri::command]
+fn set_theme(app: tauri::AppHandle, theme: String) -> Result<(), String> {
+    let choice = theme.trim().to_ascii_lowercase();
+    if !THEME_CHOICES.contains(&choice.as_str()) {
+        return Err(format!("Tema no válido: '{}' (usa dark, light o system)", theme));
+    }
+    let path = theme_path(&app);
+    std::fs::write(&path, &choice).map_err(|e| format!("No se pudo guardar {}: {}", path.display(), e))?;
+    if let Some(window) = app.get_webview_window("main") {
+        window
+            .set_theme(window_theme(&choice))
+            .map_err(|e| format!("No se pudo aplicar el tema: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Tema guardado por `set_theme` ("dark" | "light" | "system").
+#[tauri::command]
+fn get_theme(app: tauri::AppHandle) -> String {
+    load_theme(&app)
+}
+
+// ─── Geometría de ventana ─────────────────────────────────────────────────────
+
+/// Tamaño por defecto de la ventana principal (mismo que tauri.conf.json).
+const DEFAULT_WINDOW_WIDTH: f64 = 1280.0;
+const DEFAULT_WINDOW_HEIGHT: f64 = 820.0;
+
+/// Ruta del archivo con la geometría persistida de la ventana.
+fn window_state_path(app: &tauri::AppHandle) -> std::path::PathBuf {
+    app_data_dir(app).unwrap_or_default().join("window.json")
+}
+
+/// Devuelve true si al menos una franja usable de la ventana (100×40 px físicos
+/// de la esquina superior, donde está la titlebar) cae dentro de algún monitor.
+fn rect_is_visible(monitors: &[(i32, i32, u32, u32)], x: i32, y: i32, w: u32) -> bool {
+    const MIN_VISIBLE_W: i32 = 100;
+    const MIN_VISIBLE_H: i32 = 40;
+    let grab_w = (w as i32).min(MIN_VISIBLE_W);
+    monitors.iter().any(|&(mx, my, mw, mh)| {
+        let overlap_w = (x + grab_w).min(mx + mw as i32) - x.max(mx);
+        let overlap_h = (y + MIN_VISIBLE_H).min(my + mh as i32) - y.max(my);
+        overlap_w >= grab_w && overlap_h >= MIN_VISIBLE_H
+    })
+}
+
+/// Centra la ventana en el monitor principal (o el actual si no hay principal).
+fn center_on_primary_monitor(window: &tauri::WebviewWindow) {
+    let monitor = window
+        .primary_monitor()
+        .ok()
+        .flatten()
+        .or_else(|| window.current_monitor().ok().flatten());
+    let (Some(monitor), Ok(size)) = (monitor, window.outer_size()) else {
+        let _ = window.center();
+        return;
+    };
+    let mpos = monitor.position();
+    let msize = monitor.size();
+    let x = mpos.x + (msize.width as i32 - size.width as i32).max(0) / 2;
+    let y = mpos.y + (msize.height as i32 - size.height as i32).max(0) / 2;
+    let _ = window.set_position(tauri::PhysicalPosition::new(x, y));
+}
+
+/// Si la ventana ha quedado fuera de cualquier monitor visible (p.ej. se desconectó
+/// el monitor donde estaba), la recoloca centrada en el monitor principal.
+/// Devuelve true si hubo que moverla.
+fn clamp_window_to_monitors(window: &tauri::WebviewWindow) -> bool {
+    let monitors: Vec<(i32, i32, u32, u32)> = window
+        .available_monitors()
+        .unwrap_or_default()
+        .iter()
+        .map(|m| (m.position().x, m.position().y, m.size().width, m.size().height))
+        .collect();
+    let (Ok(pos), Ok(size)) = (window.outer_position(), window.outer_size()) else {
+        return false;
+    };
+    if monitors.is_empty() || rect_is_visible(&monitors, pos.x, pos.y, size.width) {
+        return false;
+    }
+    center_on_primary_monitor(window);
+    true
+}
+
+/// Geometría persistida en window.json (píxeles físicos). Con `maximized` se
+/// conservan los límites de la ventana sin maximizar, para restaurarlos al salir
+/// del modo maximizado.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct WindowGeometry {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    maximized: bool,
+}
+
+fn load_window_geometry(app: &tauri::AppHandle) -> Option<WindowGeometry> {
+    let bytes = std::fs::read(window_state_path(app)).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Guarda la geometría actual en window.json. Minimizada no se guarda (la posición
+/// es ficticia); maximizada solo se anota el flag y se mantienen los límites previos.
+fn save_window_geometry(window: &tauri::WebviewWindow) {
+    if window.is_minimized().unwrap_or(false) {
+        return;
+    }
+    let app = window.app_handle();
+    let maximized = window.is_maximized().unwrap_or(false);
+    let geometry = match (maximized, load_window_geometry(app)) {
+        (true, Some(prev)) => WindowGeometry { maximized: true, ..prev },
+        _ => {
+            let (Ok(pos), Ok(size)) = (window.outer_position(), window.inner_size()) else {
+                return;
+            };
+            WindowGeometry { x: pos.x, y: pos.y, width: size.width, height: size.height, maximized }
+        }
+    };
+    if let Ok(json) = serde_json::to_vec_pretty(&geometry) {
+        if let Err(e) = std::fs::write(window_state_path(app), json) {
+            app_log_at(app, LogLevel::Warn, &format!("WARN: No se pudo guardar window.json: {}", e));
+        }
+    }
+}
+
+/// Aplica la geometría de window.json (si existe) antes de mostrar la ventana. Si la
+/// posición guardada cae fuera de los monitores actuales (monitor desconectado,
+/// cambio de resolución) `clamp_window_to_monitors` la recoloca.
+fn restore_window_geometry(window: &tauri::WebviewWindow) {
+    /// Por debajo de esto la geometría guardada se considera corrupta
+    const MIN_SIZE: u32 = 200;
+    let Some(g) = load_window_geometry(window.app_handle()) else { return };
+    if g.width >= MIN_SIZE && g.height >= MIN_SIZE {
+        let _ = window.set_size(tauri::PhysicalSize::new(g.width, g.height));
+        let _ = window.set_position(tauri::PhysicalPosition::new(g.x, g.y));
+        if clamp_window_to_monitors(window) {
+            app_log(window.app_handle(), "window.json: posición fuera de pantalla, ventana recentrada");
+        }
+    }
+    if g.maximized {
+        let _ = window.maximize();
+    }
+}
+
+/// Vía de escape cuando la geometría guardada es inutilizable: borra window.json,
+/// restaura el tamaño por defecto y centra la ventana en el monitor principal.
+#[tauri::command]
+fn reset_window_geometry(window: tauri::WebviewWindow) -> Result<(), String> {
+    let path = window_state_path(window.app_handle());
+    if path.exists() {
+        std::fs::remove_file(&path).map_err(|e| format!("No se pudo borrar window.json: {}", e))?;
+    }
+    let _ = window.unmaximize();
+    window
+        .set_size(tauri::LogicalSize::new(DEFAULT_WINDOW_WIDTH, DEFAULT_WINDOW_HEIGHT))
+        .map_err(|e| e.to_string())?;
+    center_on_primary_monitor(&window);
+    clamp_window_to_monitors(&window);
+    let _ = window.show();
+    let _ = window.set_focus();
+    Ok(())
+}
+
+/// Fija (o suelta) la ventana por encima de las demás, para usar Stacklume como
+/// referencia junto a otras apps. Se persiste en settings.json para el próximo arranque.
+#[tauri::command]
+fn set_always_on_top(window: tauri::WebviewWindow, enabled: bool) -> Result<(), String> {
+    window.set_always_on_top(enabled).map_err(|e| e.to_string())?;
+    let app = window.app_handle();
+    let mut settings = load_settings(app);
+    settings.always_on_top = enabled;
+    save_settings(app, &settings)?;
+    app_log(app, &format!("set_always_on_top: {}", enabled));
+    Ok(())
+}
+
+/// Actualiza el icono del system tray con un frame RGBA enviado desde el frontend.
+/// Se llama ~30 veces por segundo desde TrayIconUpdater.tsx.
+#[tauri::command]
+fn update_tray_icon(
+    app: tauri::AppHandle,
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    // Validar dimensiones: máximo 256x256 (suficiente para cualquier icono de tray)
+    if width == 0 || height == 0 || width > 256 || height > 256 {
+        return Err(format!("Dimensiones de icono inválidas: {}x{} (máx 256x256)", width, height));
+    }
+    // Validar que el buffer RGBA tiene exactamente el tamaño esperado
+    let expected_len = (width as usize) * (height as usize) * 4;
+    if rgba.len() != expected_len {
+        return Err(format!(
+            "Tamaño de buffer RGBA incorrecto: {} bytes (esperados {} para {}x{})",
+            rgba.len(), expected_len, width, height
+        ));
+    }
+    if let Some(tray) = app.tray_by_id("main") {
+        let icon = tauri::image::Image::new_owned(rgba, width, height);
+        tray.set_icon(Some(icon)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// ─── Ciclo de vida del servidor ───────────────────────────────────────────────
+
+/// Aborta un arranque en curso: activa la cancelación del hilo de espera y mata
+/// el node.exe lanzado por este intento, dejando el servidor parado y limpio.
+#[tauri::command]
+fn cancel_startup(app: tauri::AppHandle, state: State<'_, ServerState>) -> Result<(), String> {
+    if state.ready.load(Ordering::SeqCst) {
+        return Err("El servidor ya está arrancado; no hay arranque que cancelar".to_string());
+    }
+    state.cancel.store(true, Ordering::SeqCst);
+    app_log(&app, "cancel_startup: cancelando arranque en curso");
+
+    #[cfg(not(dev))]
+    if let Some(pid) = stop_server_child(&app) {
+        app_log(&app, &format!("cancel_startup: node.exe (PID {}) detenido", pid));
+    }
+    Ok(())
+}
+
+/// Reinicia el servidor sin cerrar la app: mata node.exe, lo relanza (en el mismo
+/// puerto si sigue libre, si no en uno nuevo), espera al health check y vuelve a
+/// navegar la ventana. Devuelve el puerto en el que quedó el servidor (el que tenga
+/// al responder: si node cae con EADDRINUSE, el hilo de espera lo mueve a otro).
+#[tauri::command]
+async fn restart_server(app: tauri::AppHandle, state: State<'_, ServerState>) -> Result<u16, String> {
+    #[cfg(dev)]
+    {
+        let _ = (app, state);
+        Err("Solo disponible en producción (en dev el servidor lo lanza beforeDevCommand)".to_string())
+    }
+
+    #[cfg(not(dev))]
+    {
+        // Parar, elegir puerto y relanzar bloquea (kill + wait, bind): fuera del runtime
+        let app2 = app.clone();
+        let mut pid = tauri::async_runtime::spawn_blocking(move || -> Result<u32, String> {
+            app_log(&app2, "restart_server: reiniciando servidor");
+            note_restart(&app2, "manual");
+
+            // Reutilizar el puerto si está libre o lo ocupa nuestro propio node.exe
+            // (ensure_server lo detiene antes de relanzar); si lo tiene otro proceso, uno
+            // nuevo. Si aun así sigue ocupado al relanzar, el EADDRINUSE lo resuelve
+            // el hilo de espera con `retry_on_new_port`.
+            let state = app2.state::<ServerState>();
+            let old_port = *state.port.lock().unwrap();
+            let own_child = state.node_child.lock().unwrap().is_some();
+            let port = if own_child || port_is_free(old_port) {
+                old_port
+            } else {
+                let port = find_free_port(None)
+                    .ok_or_else(|| format!("Puerto {} ocupado y sin puerto alternativo", old_port))?;
+                app_log(&app2, &format!("restart_server: puerto {} ocupado, usando {}", old_port, port));
+                port
+            };
+            set_server_port(&app2, port);
+
+            ensure_server(&app2)
+        })
+        .await
+        .map_err(|e| format!("Error interno: {}", e))??;
+
+        // El hilo de espera de ensure_server marca `ready` y navega; aquí solo esperamos.
+        // Si node cae sin más no tiene sentido agotar el timeout; con EADDRINUSE el hilo
+        // de espera lo relanza una vez en otro puerto y se sigue al nuevo proceso.
+        let slog_path = app_data_dir(&app).unwrap_or_default().join("server.log");
+        let mut retried = false;
+        let deadline = std::time::Instant::now()
+            + std::time::Duration::from_millis(HealthConfig::resolve().timeout_ms + 1_000);
+        while std::time::Instant::now() < deadline {
+            if state.ready.load(Ordering::SeqCst) {
+                let port = *state.port.lock().unwrap();
+                app_log(&app, &format!("restart_server: servidor listo en el puerto {}", port));
+                return Ok(port);
+            }
+            if state.cancel.load(Ordering::SeqCst) {
+                return Err("Reinicio cancelado".to_string());
+            }
+            match child_outcome(&app, Some(pid)) {
+                ChildOutcome::Exited(status) if retried || !server_log_addr_in_use(&slog_path) => {
+                    return Err(format!("node.exe terminó al reiniciar ({})", describe_exit(status)));
+                }
+                ChildOutcome::Gone => {
+                    if let Some(new_pid) = *state.pid.lock().unwrap() {
+                        retried |= new_pid != pid;
+                        pid = new_pid;
+                    }
+                }
+                _ => {}
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        }
+        Err(format!("El servidor no respondió tras reiniciar (puerto {})", *state.port.lock().unwrap()))
+    }
+}
+
+/// Mata node.exe sin cerrar la app, para cuando se queda colgado: a diferencia de
+/// `restart_server` no lo relanza (eso queda para una llamada posterior) ni da margen
+/// de cierre limpio. Por lo demás es la parada de `stop_server_child` (grupo de
+/// procesos, Job Object, hilo de espera superado).
+#[tauri::command]
+fn kill_server(app: tauri::AppHandle) -> Result<(), String> {
+    #[cfg(dev)]
+    {
+        let _ = app;
+        Err("Solo disponible en producción (en dev el servidor lo lanza beforeDevCommand)".to_string())
+    }
+
+    #[cfg(not(dev))]
+    {
+        let mut child = take_server_child(&app).ok_or_else(|| "No hay ningún servidor en marcha".to_string())?;
+        let pid = child.id();
+        force_kill_server_child(&app, &mut child, false)
+            .map_err(|e| format!("No se pudo matar node.exe (PID {}): {}", pid, e))?;
+        app_log(&app, &format!("kill_server: node.exe (PID {}) detenido", pid));
+        Ok(())
+    }
+}
+
+/// Token de sesión que node recibe en STACKLUME_SESSION_TOKEN, para que el webview
+/// lo adjunte como cabecera y el servidor rechace llamadas de otras apps locales.
+#[tauri::command]
+fn get_session_token(state: State<'_, ServerState>) -> String {
+    state.session_token.clone()
+}
+
+/// PID del node.exe actual, para que el usuario sepa qué proceso inspeccionar o
+/// a cuál adjuntar un profiler. None si no hay servidor vivo (o en dev).
+#[tauri::command]
+fn get_server_pid(state: State<'_, ServerState>) -> Option<u32> {
+    *state.pid.lock().unwrap()
+}
+
+/// Si `port` está libre (en la dirección de STACKLUME_BIND_HOST), para avisar
+/// antes de arrancar de que otro programa lo ocupa.
+#[tauri::command]
+fn is_port_free(port: u16) -> bool {
+    port_is_free(port)
+}
+
+#[derive(serde::Serialize)]
+struct HealthStatus {
+    /// true cuando el health check ha respondido y la UI está navegando al servidor
+    ready: bool,
+    port: u16,
+    /// PID de node.exe (None en dev o si no hay proceso vivo)
+    pid: Option<u32>,
+    /// Segundos desde que se lanzó el node.exe actual (0 si no hay)
+    uptime_secs: u64,
+}
+
+/// Estado actual del servidor para el indicador de la barra de título. Pensado
+/// para sondearse: solo lee `ServerState`, sin tocar la red.
+#[tauri::command]
+fn get_health_status(state: State<'_, ServerState>) -> HealthStatus {
+    let pid = *state.pid.lock().unwrap();
+    #[cfg(not(dev))]
+    let uptime_secs = match (pid, *state.spawned_at.lock().unwrap()) {
+        (Some(_), Some(at)) => at.elapsed().as_secs(),
+        _ => 0,
+    };
+    #[cfg(dev)]
+    let uptime_secs = 0;
+
+    HealthStatus {
+        ready: state.ready.load(Ordering::SeqCst),
+        port: *state.port.lock().unwrap(),
+        pid,
+        uptime_secs,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct StartupMetrics {
+    /// Spawn de node.exe → health check correcto en el último arranque (None si aún
+    /// no ha arrancado ninguno o en dev)
+    last_startup_ms: Option<u64>,
+    /// Versión de la app, para comparar arranques entre versiones
+    app_version: String,
+}
+
+/// Duración del último arranque del servidor, para seguir regresiones del arranque
+/// en frío desde la telemetría del frontend.
+#[tauri::command]
+fn get_startup_metrics(app: tauri::AppHandle) -> StartupMetrics {
+    StartupMetrics {
+        last_startup_ms: *app.state::<ServerState>().last_startup_ms.lock().unwrap(),
+        app_version: app.package_info().version.to_string(),
+    }
+}
+
+/// Rutas que resolvió el arranque y si existían, para "Copiar info de soporte".
+#[derive(Clone, Default, serde::Serialize)]
+struct DiagnosticsDto {
+    resource_dir: String,
+    node_path: String,
+    node_exists: bool,
+    server_path: String,
+    server_exists: bool,
+    db_path: String,
+    app_data_dir: String,
+    port: u16,
+}
+
+/// Rutas de recursos, base de datos y app_data tal como las resolvió el arranque
+/// (no se re-resuelven: interesa lo que se usó de verdad). El puerto es el actual.
+/// Antes de resolver los recursos, o en dev, solo rellena app_data_dir y el puerto.
+#[tauri::command]
+fn get_diagnostics(app: tauri::AppHandle) -> DiagnosticsDto {
+    let state = app.state::<ServerState>();
+    let mut dto = state.diagnostics.lock().unwrap().clone().unwrap_or_else(|| DiagnosticsDto {
+        app_data_dir: app_data_dir(&app)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        ..Default::default()
+    });
+    dto.port = *state.port.lock().unwrap();
+    dto
+}
+
+#[derive(serde::Serialize)]
+struct ChildSupervisionInfo {
+    platform: String,
+    /// "job_object" | "process_group" | "none"
+    mechanism: &'static str,
+    /// true si el mecanismo está realmente activo para el node.exe actual
+    handle_valid: bool,
+    child_pid: Option<u32>,
+}
+
+/// Estado del mecanismo que garantiza que node.exe muere con la app. En Windows
+/// es el Job Object (`node_job`); en macOS/Linux, el grupo de procesos de node
+/// (pgid = PID del hijo). Solo lectura, sale de `ServerState`.
+#[tauri::command]
+fn child_supervision_info(state: State<'_, ServerState>) -> ChildSupervisionInfo {
+    #[cfg(not(dev))]
+    let child_pid = state.node_child.lock().unwrap().as_ref().map(|c| c.id());
+    #[cfg(dev)]
+    let child_pid = None;
+
+    #[cfg(windows)]
+    let (mechanism, handle_valid) = ("job_object", *state.node_job.lock().unwrap() != 0);
+    // Unix (producción): cada node se lanza como líder de su propio grupo de procesos
+    #[cfg(all(not(windows), not(dev)))]
+    let (mechanism, handle_valid) = (
+        "process_group",
+        state
+            .node_child
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|c| matches!(c.try_wait(), Ok(None)))
+            .unwrap_or(false),
+    );
+    #[cfg(all(not(windows), dev))]
+    let (mechanism, handle_valid) = {
+        let _ = &state;
+        ("none", false)
+    };
+
+    ChildSupervisionInfo {
+        platform: std::env::consts::OS.to_string(),
+        mechanism,
+        handle_valid,
+        child_pid,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ResourceStatus {
+    path: String,
+    exists: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ResourceReport {
+    resource_dir: String,
+    node: ResourceStatus,
+    server: ResourceStatus,
+    /// Elementos obligatorios que faltan, relativos al directorio de recursos
+    missing: Vec<String>,
+}
+
+/// Recorre el árbol de recursos que necesita el servidor (binario de node, server.js
+/// y los directorios del build standalone que carga) y devuelve el estado de cada
+/// pieza, para diagnosticar instalaciones dañadas. Usa las mismas rutas que el arranque.
+#[tauri::command]
+fn verify_resources(app: tauri::AppHandle) -> Result<ResourceReport, String> {
+    #[cfg(dev)]
+    {
+        let _ = app;
+        Err("Solo disponible en producción (en dev no hay recursos empaquetados)".to_string())
+    }
+
+    #[cfg(not(dev))]
+    {
+        let paths = resolve_server_paths(&app);
+        let server_dir = paths
+            .server_js
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .to_path_buf();
+        let status = |p: &std::path::Path| ResourceStatus {
+            path: p.to_string_lossy().to_string(),
+            exists: p.exists(),
+        };
+
+        // Hermanos de server.js sin los que el standalone de Next.js no arranca
+        let required = [
+            paths.node_exe.clone(),
+            paths.server_js.clone(),
+            server_dir.join("package.json"),
+            server_dir.join(".next"),
+            server_dir.join("node_modules"),
+        ];
+        let missing = required
+            .iter()
+            .filter(|p| !p.exists())
+            .map(|p| {
+                p.strip_prefix(&paths.resource_dir)
+                    .unwrap_or(p)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            app_log(&app, &format!("verify_resources: faltan {:?}", missing));
+        }
+
+        Ok(ResourceReport {
+            resource_dir: paths.resource_dir.to_string_lossy().to_string(),
+            node: status(&paths.node_exe),
+            server: status(&paths.server_js),
+            missing,
+        })
+    }
+}
+
+// ─── Base de datos ────────────────────────────────────────────────────────────
+
+/// Ruta de la base de datos SQLite que usa el servidor: la que se pasó en
+/// DATABASE_PATH si node ya se lanzó, o la ubicación por defecto en app_data.
+fn database_path(app: &tauri::AppHandle) -> std::path::PathBuf {
+    #[cfg(not(dev))]
+    if let Some(launch) = app.state::<ServerState>().launch.lock().unwrap().as_ref() {
+        return launch.db_path.clone();
+    }
+    app_data_dir(app).unwrap_or_default().join("stacklume.db")
+}
+
+/// `path` con `suffix` añadido al nombre (stacklume.db → stacklume.db-wal, .bak…).
+fn path_with_suffix(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(suffix);
+    std::path::PathBuf::from(p)
+}
+
+#[derive(serde::Serialize)]
+struct DatabaseStats {
+    size_bytes: u64,
+    wal_bytes: u64,
+    shm_bytes: u64,
+    path: String,
+}
+
+/// Tamaño en disco de stacklume.db y sus ficheros -wal/-shm (0 si no existen).
+/// Permite mostrar la huella total de la DB y sugerir un VACUUM si el WAL crece.
+#[tauri::command]
+fn database_stats(app: tauri::AppHandle) -> DatabaseStats {
+    let db = database_path(&app);
+    let size_of = |suffix: &str| {
+        std::fs::metadata(path_with_suffix(&db, suffix)).map(|m| m.len()).unwrap_or(0)
+    };
+    DatabaseStats {
+        size_bytes: size_of(""),
+        wal_bytes: size_of("-wal"),
+        shm_bytes: size_of("-shm"),
+        path: db.to_string_lossy().to_string(),
+    }
+}
+
+/// Tamaño máximo de la DB que `read_database_bytes` carga en memoria. El resultado
+/// viaja por IPC como JSON (un número por byte), así que por encima de esto el coste
+/// en memoria del webview se dispara.
+const MAX_DB_BYTES_IN_MEMORY: u64 = 64 * 1024 * 1024;
+
+/// Vuelca el WAL en stacklume.db (`PRAGMA wal_checkpoint(TRUNCATE)`) con el mismo
+/// node y entorno que el servidor, usando el @libsql/client que trae el standalone.
+#[cfg(not(dev))]
+fn checkpoint_database(app: &tauri::AppHandle) -> Result<(), String> {
+    const SCRIPT: &str = concat!(
+        "const { createClient } = require('@libsql/client');",
+        "const c = createClient({ url: 'file:' + process.env.DATABASE_PATH });",
+        "c.execute('PRAGMA wal_checkpoint(TRUNCATE)')",
+        ".then(() => process.exit(0))",
+        ".catch((e) => { console.error(e && e.message); process.exit(1); });"
+    );
+    let launch = refresh_server_launch(app)?;
+    let log_path = app_data_dir(app).unwrap_or_default().join("stacklume.log");
+    let output = launch
+        .node_command(&log_path)
+        .arg("-e")
+        .arg(SCRIPT)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Error ejecutando node: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Devuelve el contenido de stacklume.db para subirlo como copia de seguridad
+/// (p.ej. a la nube) sin pasar por un archivo intermedio. Antes hace un checkpoint
+/// del WAL para que el archivo principal sea una instantánea consistente; si no se
+/// puede y el WAL tiene cambios pendientes, falla en lugar de devolver datos a medias.
+#[tauri::command]
+async fn read_database_bytes(app: tauri::AppHandle) -> Result<Vec<u8>, String> {
+    tokio::task::spawn_blocking(move || {
+        let db = database_path(&app);
+        let size = std::fs::metadata(&db)
+            .map_err(|e| format!("No se pudo leer la base de datos: {}", e))?
+            .len();
+        if size > MAX_DB_BYTES_IN_MEMORY {
+            return Err(format!(
+                "La base de datos es demasiado grande para leerla en memoria ({} MB, máximo {} MB). \
+                 Haz una copia de seguridad a archivo en su lugar.",
+                size / (1024 * 1024),
+                MAX_DB_BYTES_IN_MEMORY / (1024 * 1024)
+            ));
+        }
+
+        #[cfg(not(dev))]
+        let checkpoint = checkpoint_database(&app);
+        #[cfg(dev)]
+        let checkpoint: Result<(), String> =
+            Err("checkpoint no disponible en dev".to_string());
+
+        if let Err(e) = checkpoint {
+            let wal_bytes = std::fs::metadata(path_with_suffix(&db, "-wal")).map(|m| m.len()).unwrap_or(0);
+            if wal_bytes > 0 {
+                app_log(&app, &format!("read_database_bytes: checkpoint fallido: {}", e));
+                return Err(format!(
+                    "No se pudo consolidar el WAL ({} bytes pendientes): {}",
+                    wal_bytes, e
+                ));
+            }
+        }
+
+        let bytes = std::fs::read(&db).map_err(|e| format!("No se pudo leer la base de datos: {}", e))?;
+        app_log(&app, &format!("read_database_bytes: {} bytes", bytes.len()));
+        Ok(bytes)
+    })
+    .await
+    .map_err(|e| format!("Error interno: {}", e))?
+}
+
+/// Quita los restos de bloqueo que deja un cierre brusco junto a la DB: el índice
+/// compartido `-shm` (SQLite lo regenera) y `<db>.lock` (archivo o directorio). Solo
+/// con el lock de instancia en nuestro poder y node parado: entonces nadie más puede
+/// tener la DB abierta. Nunca toca `-wal` ni `-journal`, que guardan transacciones
+/// que SQLite recupera al abrir.
+#[cfg(not(dev))]
+fn clear_stale_db_locks(
+    app: &tauri::AppHandle,
+    db: &std::path::Path,
+    log_path: &std::path::Path,
+) {
+    if !app.state::<InstanceLock>().is_held() {
+        log(log_path, "Restos de bloqueo de la DB no revisados: sin el lock de instancia");
+        return;
+    }
+    for suffix in ["-shm", ".lock"] {
+        let path = path_with_suffix(db, suffix);
+        let result = match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(&path),
+            Ok(_) => std::fs::remove_file(&path),
+            Err(_) => continue,
+        };
+        match result {
+            Ok(()) => log(log_path, &format!("Resto de bloqueo eliminado: {}", path.display())),
+            Err(e) => log_at(log_path, LogLevel::Warn, &format!("WARN: No se pudo eliminar {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Copia de seguridad previa al arranque: stacklume.db (y su -wal, que puede tener
+/// transacciones aún no consolidadas) → stacklume.db.bak, conservando la copia
+/// anterior como stacklume.db.bak.1. Se llama con node parado; nunca bloquea el arranque.
+#[cfg(not(dev))]
+fn backup_database(db: &std::path::Path, log_path: &std::path::Path) {
+    if std::fs::metadata(db).map(|m| m.len()).unwrap_or(0) == 0 {
+        log(log_path, "Copia de la DB omitida: todavía no existe o está vacía");
+        return;
+    }
+    let bak = path_with_suffix(db, ".bak");
+    let prev = path_with_suffix(db, ".bak.1");
+    if bak.is_file() {
+        for suffix in ["", "-wal"] {
+            let _ = std::fs::remove_file(path_with_suffix(&prev, suffix));
+            let _ = std::fs::rename(path_with_suffix(&bak, suffix), path_with_suffix(&prev, suffix));
+        }
+    }
+    // Copiar a un temporal y renombrar: un corte a mitad no deja un .bak truncado
+    let tmp = path_with_suffix(db, ".bak.tmp");
+    match std::fs::copy(db, &tmp).and_then(|_| std::fs::rename(&tmp, &bak)) {
+        Ok(()) => log(log_path, &format!("Copia de la DB: {}", bak.display())),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            log_at(log_path, LogLevel::Warn, &format!("WARN: No se pudo copiar la DB a {}: {}", bak.display(), e));
+            return;
+        }
+    }
+    let wal = path_with_suffix(db, "-wal");
+    if std::fs::metadata(&wal).map(|m| m.len()).unwrap_or(0) > 0 {
+        if let Err(e) = std::fs::copy(&wal, path_with_suffix(&bak, "-wal")) {
+            log_at(log_path, LogLevel::Warn, &format!("WARN: No se pudo copiar el WAL de la DB: {}", e));
+        }
+    }
+}
 
-        // Emitir 100%
-        let _ = app_clone.emit("update:progress", 100u64);
+/// Comprueba que stacklume.db sea una base SQLite antes de lanzar node: con un
+/// archivo vacío o sin la cabecera `SQLite format 3\0` el servidor fallaría y el
+/// usuario solo vería el timeout. Que no exista es normal (primer arranque).
+#[cfg(not(dev))]
+fn check_database_file(db: &std::path::Path) -> Result<(), String> {
+    use std::io::Read;
+    const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+    let mut file = match std::fs::File::open(db) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("No se pudo abrir {}: {}", db.display(), e)),
+    };
+    let mut header = [0u8; 16];
+    let mut read = 0;
+    while read < header.len() {
+        match file.read(&mut header[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) => return Err(format!("No se pudo leer {}: {}", db.display(), e)),
+        }
+    }
+    match read {
+        0 => Err(format!("{} está vacía (0 bytes)", db.display())),
+        16 if &header == SQLITE_MAGIC => Ok(()),
+        _ => Err(format!("{} no es una base de datos SQLite válida (cabecera dañada)", db.display())),
+    }
+}
 
-        // Verificar tamaño
-        let file_size = std::fs::metadata(&installer_path_clone).map(|m| m.len()).unwrap_or(0);
-        if file_size < MIN_INSTALLER_SIZE {
-            let _ = std::fs::remove_file(&installer_path_clone);
-            return Err(format!("Instalador inválido: {} bytes (descarga incompleta?)", file_size));
+/// Aparta stacklume.db (y su -wal/-shm) como `stacklume.db.corrupt.<ts>`: nunca se
+/// borra, para poder recuperarla a mano. Devuelve la ruta nueva. Con node parado.
+#[cfg(not(dev))]
+fn set_aside_database(db: &std::path::Path) -> Result<std::path::PathBuf, String> {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let corrupt = path_with_suffix(db, &format!(".corrupt.{}", ts));
+    for suffix in ["", "-wal", "-shm"] {
+        let from = path_with_suffix(db, suffix);
+        if from.exists() {
+            std::fs::rename(&from, path_with_suffix(&corrupt, suffix))
+                .map_err(|e| format!("No se pudo apartar {}: {}", from.display(), e))?;
         }
+    }
+    Ok(corrupt)
+}
 
-        // Verificar no excede el máximo
-        if file_size > MAX_DOWNLOAD_SIZE {
-            let _ = std::fs::remove_file(&installer_path_clone);
-            return Err("Instalador demasiado grande".to_string());
+/// Para node.exe, aparta la DB actual (`set_aside_database`), copia encima
+/// stacklume.db.bak (con su -wal) y relanza el servidor. El .bak se conserva.
+#[cfg(not(dev))]
+fn restore_database_backup(app: &tauri::AppHandle) -> Result<(), String> {
+    let db = database_path(app);
+    let bak = path_with_suffix(&db, ".bak");
+    if !bak.is_file() {
+        return Err("No hay copia de seguridad de la base de datos (stacklume.db.bak)".to_string());
+    }
+    app_log(app, &format!("restore_db_backup: restaurando {}", bak.display()));
+    if let Some(pid) = stop_server_child(app) {
+        app_log(app, &format!("restore_db_backup: node.exe (PID {}) detenido", pid));
+    }
+    let corrupt = set_aside_database(&db)?;
+    std::fs::copy(&bak, &db).map_err(|e| format!("No se pudo restaurar la copia: {}", e))?;
+    let bak_wal = path_with_suffix(&bak, "-wal");
+    if bak_wal.is_file() {
+        std::fs::copy(&bak_wal, path_with_suffix(&db, "-wal"))
+            .map_err(|e| format!("No se pudo restaurar el WAL de la copia: {}", e))?;
+    }
+    app_log(app, &format!("restore_db_backup: DB dañada apartada en {}", corrupt.display()));
+    ensure_server(app)?;
+    Ok(())
+}
+
+/// Para node.exe, aparta la DB dañada y relanza el servidor, que crea una vacía.
+#[cfg(not(dev))]
+fn start_fresh_database(app: &tauri::AppHandle) -> Result<(), String> {
+    if let Some(pid) = stop_server_child(app) {
+        app_log(app, &format!("recover: node.exe (PID {}) detenido", pid));
+    }
+    let corrupt = set_aside_database(&database_path(app))?;
+    app_log(app, &format!("recover: DB dañada apartada en {}, empezando de cero", corrupt.display()));
+    ensure_server(app)?;
+    Ok(())
+}
+
+/// Muestra la página de recuperación (`pages/recovery.html` o la embebida) en lugar
+/// de lanzar node con una DB dañada, y habilita sus acciones stacklume://recover/...
+#[cfg(not(dev))]
+fn show_recovery_page(app: &tauri::AppHandle, problem: &str, log_path: &std::path::Path) {
+    const TITLE: &str = "La base de datos está dañada";
+    log_at(log_path, LogLevel::Error, &format!("ERROR: {} — mostrando página de recuperación", problem));
+    log_json(log_path, LogLevel::Error, &format!("Base de datos dañada: {}", problem));
+    let has_backup = path_with_suffix(&database_path(app), ".bak").is_file();
+    app.state::<ServerState>().db_recovery_pending.store(true, Ordering::SeqCst);
+    if let Some(window) = app.get_webview_window("main") {
+        let report = support_report(TITLE, problem, log_path, None);
+        let template = page_template(app, "recovery").unwrap_or_else(|| RECOVERY_TEMPLATE.to_string());
+        let html = render_page(&template, &[
+            ("detail", data_url_text(problem)),
+            ("restore_display", if has_backup { "inline-block" } else { "none" }.to_string()),
+            ("footer", error_page_footer(&report, log_path)),
+        ]);
+        if let Ok(url) = html.parse::<tauri::Url>() {
+            let _ = window.navigate(url);
         }
+        // Con arranque en caliente la ventana aún no se ha mostrado
+        let _ = window.show();
+    }
+}
 
-        // Matar llama-server ANTES del instalador — sus DLLs de CUDA bloquean la escritura.
-        {
-            let mut kill_cmd = std::process::Command::new("taskkill");
-            kill_cmd.args(["/f", "/im", "llama-server.exe"]);
-            #[cfg(windows)]
-            { use std::os::windows::process::CommandExt; kill_cmd.creation_flags(0x08000000); }
-            let _ = kill_cmd.output();
+/// Botones de la página de recuperación (stacklume://recover/restore | fresh). Solo
+/// se atienden con la página a la vista: ninguna otra página puede apartar la DB.
+/// Si la acción falla se vuelve a mostrar la página con el error.
+#[cfg(not(dev))]
+fn handle_recovery_action(app: &tauri::AppHandle, action: &str) {
+    if !app.state::<ServerState>().db_recovery_pending.swap(false, Ordering::SeqCst) {
+        app_log(app, &format!("recover: acción '{}' ignorada (sin recuperación pendiente)", action));
+        return;
+    }
+    let app = app.clone();
+    let action = action.to_string();
+    std::thread::spawn(move || {
+        let result = match action.as_str() {
+            "restore" => restore_database_backup(&app),
+            "fresh" => start_fresh_database(&app),
+            other => Err(format!("Acción de recuperación desconocida: '{}'", other)),
+        };
+        if let Err(e) = result {
+            let log_path = app_data_dir(&app).unwrap_or_default().join("stacklume.log");
+            show_recovery_page(&app, &e, &log_path);
         }
-        std::thread::sleep(std::time::Duration::from_secs(2));
+    });
+}
 
-        // Spawn directo del instalador. Los hooks NSIS usan "taskkill /F /IM stacklume.exe"
-        // SIN la bandera /T, por lo que no matan al instalador aunque sea hijo de la app.
-        std::process::Command::new(&installer_path_clone)
-            .spawn()
-            .map_err(|e| format!("Error ejecutando instalador: {}", e))?;
+/// Recuperación en un clic cuando stacklume.db está dañada: restaura stacklume.db.bak
+/// y relanza el servidor (ver `restore_database_backup`). La DB dañada se conserva.
+#[tauri::command]
+async fn restore_db_backup(app: tauri::AppHandle) -> Result<(), String> {
+    #[cfg(dev)]
+    {
+        let _ = app;
+        Err("Solo disponible en producción (en dev la DB la gestiona el servidor de desarrollo)".to_string())
+    }
 
-        Ok::<(), String>(())
-    })
-    .await
-    .map_err(|e| format!("Error interno: {}", e))?
+    #[cfg(not(dev))]
+    tokio::task::spawn_blocking(move || restore_database_backup(&app))
+        .await
+        .map_err(|e| format!("Error interno: {}", e))?
 }
 
-/// Abre una URL externa en el navegador por defecto del sistema.
-/// Valida la URL con el crate `url` y usa ShellExecuteW en Windows
-/// para evitar inyección de comandos (no pasa por cmd.exe).
+/// Elige qué streams de node.exe se guardan en server.log: "both", "stderr",
+/// "stdout" o "none". Se persiste en settings.json y se aplica en el próximo arranque.
 #[tauri::command]
-fn open_url(url: String) -> Result<(), String> {
-    // Validar la URL con parsing estricto
-    let parsed = url::Url::parse(&url).map_err(|e| format!("URL mal formada: {}", e))?;
-    if parsed.scheme() != "http" && parsed.scheme() != "https" {
-        return Err("Protocolo no permitido: solo http/https".into());
+fn set_log_capture(app: tauri::AppHandle, mode: String) -> Result<(), String> {
+    let capture = LogCapture::parse(mode.trim()).ok_or_else(|| {
+        format!("Modo de captura no válido: '{}' (usa both, stderr, stdout o none)", mode)
+    })?;
+    let mut settings = load_settings(&app);
+    settings.log_capture = capture;
+    save_settings(&app, &settings)?;
+    app_log(&app, &format!("set_log_capture: {} (se aplica en el próximo arranque)", capture.as_str()));
+    Ok(())
+}
+
+/// Si la app pide confirmación antes de salir (settings.json, por defecto false).
+#[tauri::command]
+fn get_confirm_quit(app: tauri::AppHandle) -> bool {
+    load_settings(&app).confirm_quit
+}
+
+#[tauri::command]
+fn set_confirm_quit(app: tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    let mut settings = load_settings(&app);
+    settings.confirm_quit = enabled;
+    save_settings(&app, &settings)?;
+    app_log(&app, &format!("set_confirm_quit: {}", enabled));
+    Ok(())
+}
+
+/// Programa un reinicio diario de node.exe a la hora local "HH:MM" (p.ej. "04:30").
+/// Se persiste en settings.json y se vuelve a armar en cada arranque.
+#[tauri::command]
+fn schedule_restart(app: tauri::AppHandle, cron_or_time: String) -> Result<(), String> {
+    let spec = cron_or_time.trim().to_string();
+    if parse_daily_time(&spec).is_none() {
+        return Err(format!("Hora no válida: '{}' (formato diario HH:MM, p.ej. 04:30)", spec));
     }
+    let mut settings = load_settings(&app);
+    settings.restart_at = Some(spec.clone());
+    save_settings(&app, &settings)?;
+    app_log(&app, &format!("schedule_restart: {}", spec));
+    #[cfg(not(dev))]
+    arm_scheduled_restart(&app);
+    Ok(())
+}
 
-    #[cfg(windows)]
-    {
-        use std::ffi::OsStr;
-        use std::os::windows::ffi::OsStrExt;
+/// Cancela el reinicio diario programado.
+#[tauri::command]
+fn clear_scheduled_restart(app: tauri::AppHandle) -> Result<(), String> {
+    let mut settings = load_settings(&app);
+    settings.restart_at = None;
+    save_settings(&app, &settings)?;
+    RESTART_SEQ.fetch_add(1, Ordering::SeqCst);
+    app_log(&app, "clear_scheduled_restart: reinicio programado cancelado");
+    Ok(())
+}
 
-        // ShellExecuteW abre la URL directamente sin pasar por cmd.exe,
-        // eliminando el vector de inyección de comandos shell.
-        let wide_url: Vec<u16> = OsStr::new(url.as_str())
-            .encode_wide()
-            .chain(std::iter::once(0))
-            .collect();
-        let wide_open: Vec<u16> = OsStr::new("open")
-            .encode_wide()
-            .chain(std::iter::once(0))
-            .collect();
+/// Marca si la UI tiene trabajo en curso que un reinicio programado no debe
+/// interrumpir. El frontend lo activa al empezar y lo desactiva al terminar.
+#[tauri::command]
+fn set_server_busy(app: tauri::AppHandle, state: State<ServerState>, busy: bool) {
+    if state.busy.swap(busy, Ordering::SeqCst) != busy {
+        app_log(&app, &format!("set_server_busy: {}", busy));
+    }
+}
 
-        let result = unsafe {
-            windows_sys::Win32::UI::Shell::ShellExecuteW(
-                std::ptr::null_mut(),  // hwnd
-                wide_open.as_ptr(),    // lpOperation = "open"
-                wide_url.as_ptr(),     // lpFile = URL
-                std::ptr::null(),      // lpParameters
-                std::ptr::null(),      // lpDirectory
-                1,                     // nShowCmd = SW_SHOWNORMAL
-            )
-        };
-        if (result as isize) <= 32 {
-            return Err("Error al abrir la URL en el navegador".into());
+/// Cómo queda la ventana al arrancar: "normal", "minimized" o "hidden" (solo tray).
+/// Se persiste en settings.json; los flags --minimized/--hidden tienen prioridad.
+#[tauri::command]
+fn set_launch_visibility(app: tauri::AppHandle, mode: String) -> Result<(), String> {
+    let visibility = LaunchVisibility::parse(mode.trim()).ok_or_else(|| {
+        format!("Modo no válido: '{}' (usa normal, minimized o hidden)", mode)
+    })?;
+    let mut settings = load_settings(&app);
+    settings.launch_visibility = visibility;
+    save_settings(&app, &settings)?;
+    app_log(&app, &format!("set_launch_visibility: {}", visibility.as_str()));
+    Ok(())
+}
+
+/// Contadores de autorreparación de la sesión (reinicios por crash, arranques
+/// fallidos y último reinicio), para diagnosticar informes de inestabilidad.
+/// `hang_restarts` está reservado y vale 0 hasta que exista un watchdog de cuelgues.
+#[tauri::command]
+fn supervision_counters(state: State<ServerState>) -> SupervisionCounters {
+    state.supervision.lock().unwrap().clone()
+}
+
+/// Respuesta afirmativa de la UI al evento `confirm-quit`: sale de la app.
+#[tauri::command]
+fn confirm_quit(app: tauri::AppHandle) {
+    app_log(&app, "confirm_quit: salida confirmada por el usuario");
+    quit_app(app);
+}
+
+/// Sale de la app parando antes node.exe y llama-server de forma ordenada, sin
+/// depender de que llegue `WindowEvent::Destroyed` (botón de cierre propio, tray).
+#[tauri::command]
+fn quit_app(app: tauri::AppHandle) {
+    app_log(&app, "quit_app: cerrando");
+    if let Some(w) = app.get_webview_window("main") {
+        if w.is_visible().unwrap_or(false) {
+            save_window_geometry(&w);
         }
     }
-    #[cfg(target_os = "macos")]
-    {
-        std::process::Command::new("open")
-            .arg(&url)
-            .spawn()
-            .map_err(|e| e.to_string())?;
-    }
-    #[cfg(target_os = "linux")]
-    {
-        std::process::Command::new("xdg-open")
-            .arg(&url)
-            .spawn()
-            .map_err(|e| e.to_string())?;
+    #[cfg(not(dev))]
+    shutdown_children(&app);
+    app.exit(0);
+}
+
+/// Relanza la app entera (p. ej. tras cambiar un ajuste que solo se lee en setup).
+/// Antes para node.exe y llama-server y suelta el lock de instancia: si no, la nueva
+/// instancia podría arrancar mientras esta aún lo tiene, creerse secundaria y salir,
+/// o chocar con node.exe en el puerto.
+#[tauri::command]
+fn relaunch_app(app: tauri::AppHandle) {
+    app_log(&app, "relaunch_app: reiniciando la aplicación");
+    if let Some(w) = app.get_webview_window("main") {
+        if w.is_visible().unwrap_or(false) {
+            save_window_geometry(&w);
+        }
     }
-    Ok(())
+    #[cfg(not(dev))]
+    shutdown_children(&app);
+    app.state::<InstanceLock>().release();
+    app.restart();
 }
 
-/// Abre una ruta local (archivo o carpeta) con la aplicación por defecto del sistema.
-/// Usa ShellExecuteW en Windows para evitar inyección de comandos.
+// ─── Comandos de soporte ──────────────────────────────────────────────────────
+
+/// Abre un log de app_data con la aplicación por defecto del sistema. `which` solo
+/// admite "stacklume" (stacklume.log) o "server" (server.log): nunca rutas arbitrarias.
 #[tauri::command]
-fn open_local_path(path: String) -> Result<(), String> {
-    // Seguridad: rechazar bytes nulos y caracteres de escape
-    if path.contains('\0') || path.contains('\n') || path.contains('\r') {
-        return Err("Ruta inválida: contiene caracteres no permitidos".to_string());
+fn open_log_file(app: tauri::AppHandle, which: String) -> Result<(), String> {
+    let file = match which.trim().to_ascii_lowercase().as_str() {
+        "stacklume" | "stacklume.log" => "stacklume.log",
+        "server" | "server.log" => "server.log",
+        _ => return Err(format!("Log desconocido: '{}' (usa stacklume o server)", which)),
+    };
+    let path = app_data_dir(&app)
+        .map_err(|e| format!("No se pudo obtener app_data_dir: {}", e))?
+        .join(file);
+    if !path.is_file() {
+        return Err(format!("{} todavía no existe", file));
     }
+    open_local_path(path.to_string_lossy().to_string())
+}
 
-    #[cfg(windows)]
-    {
-        use std::ffi::OsStr;
-        use std::os::windows::ffi::OsStrExt;
+/// Últimas `lines` líneas de server.log (stdout/stderr de node), para el botón
+/// "Copiar diagnóstico". Si el servidor aún no ha escrito el archivo devuelve vacío.
+#[tauri::command]
+fn get_server_logs(app: tauri::AppHandle, lines: usize) -> Result<Vec<String>, String> {
+    let path = app_data_dir(&app)
+        .map_err(|e| format!("No se pudo obtener app_data_dir: {}", e))?
+        .join("server.log");
+    let content = match std::fs::read(&path) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("No se pudo leer server.log: {}", e)),
+    };
+    let mut tail: Vec<String> = content.lines().rev().take(lines).map(str::to_string).collect();
+    tail.reverse();
+    Ok(tail)
+}
 
-        let wide_path: Vec<u16> = OsStr::new(path.as_str())
-            .encode_wide()
-            .chain(std::iter::once(0))
-            .collect();
-        let wide_open: Vec<u16> = OsStr::new("open")
-            .encode_wide()
-            .chain(std::iter::once(0))
-            .collect();
+#[derive(serde::Serialize)]
+struct DebugFeatures {
+    devtools: bool,
+    debug_commands: bool,
+    dev_overrides: bool,
+}
 
-        let result = unsafe {
-            windows_sys::Win32::UI::Shell::ShellExecuteW(
-                std::ptr::null_mut(),
-                wide_open.as_ptr(),
-                wide_path.as_ptr(),
-                std::ptr::null(),
-                std::ptr::null(),
-                1, // SW_SHOWNORMAL
-            )
-        };
-        if (result as isize) <= 32 {
-            return Err("No se pudo abrir la ruta".to_string());
-        }
+/// Estado resuelto de las opciones de depuración, para que la UI muestre solo los
+/// controles avanzados cuyos comandos están realmente disponibles.
+#[tauri::command]
+fn debug_features() -> DebugFeatures {
+    DebugFeatures {
+        devtools: devtools_enabled(),
+        debug_commands: debug_commands_enabled(),
+        dev_overrides: dev_overrides_enabled(),
     }
-    #[cfg(target_os = "macos")]
+}
+
+/// Ejecuta un script .js de mantenimiento con el mismo node, current_dir y entorno
+/// (incluido DATABASE_PATH) que el servidor, y devuelve su stdout/stderr.
+/// Requiere STACKLUME_ENABLE_DEBUG_CMDS=1; cada invocación queda en stacklume.log.
+#[tauri::command]
+async fn run_server_script(
+    app: tauri::AppHandle,
+    script_path: String,
+) -> Result<String, String> {
+    if !debug_commands_enabled() {
+        return Err("Comando deshabilitado (requiere STACKLUME_ENABLE_DEBUG_CMDS=1)".to_string());
+    }
+    app_log(&app, &format!("run_server_script: {}", script_path));
+
+    let script = std::path::Path::new(&script_path)
+        .canonicalize()
+        .map_err(|e| format!("Script no encontrado: {}", e))?;
+    let is_js = script
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| matches!(e.to_ascii_lowercase().as_str(), "js" | "mjs" | "cjs"))
+        .unwrap_or(false);
+    if !script.is_file() || !is_js {
+        return Err("Solo se permiten archivos .js".to_string());
+    }
+
+    #[cfg(dev)]
     {
-        std::process::Command::new("open")
-            .arg(&path)
-            .spawn()
-            .map_err(|e| e.to_string())?;
+        let _ = script;
+        Err("Solo disponible en producción (en dev el servidor lo lanza beforeDevCommand)".to_string())
     }
-    #[cfg(target_os = "linux")]
+
+    #[cfg(not(dev))]
     {
-        std::process::Command::new("xdg-open")
-            .arg(&path)
-            .spawn()
-            .map_err(|e| e.to_string())?;
+        let launch = refresh_server_launch(&app)?;
+        let log_path = app_data_dir(&app).unwrap_or_default().join("stacklume.log");
+
+        let output = tokio::task::spawn_blocking(move || {
+            launch
+                .node_command(&log_path)
+                .arg(&script)
+                .stdin(std::process::Stdio::null())
+                .output()
+        })
+        .await
+        .map_err(|e| format!("Error interno: {}", e))?
+        .map_err(|e| format!("Error ejecutando node: {}", e))?;
+
+        let code = output.status.code().unwrap_or(-1);
+        app_log(&app, &format!("run_server_script terminó (código {})", code));
+        let report = format!(
+            "exit code: {}\n--- stdout ---\n{}\n--- stderr ---\n{}",
+            code,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        if output.status.success() { Ok(report) } else { Err(report) }
     }
-    Ok(())
 }
 
-/// Abre una ruta en VS Code (si está instalado en el PATH).
+/// Eleva temporalmente el nivel de log (p.ej. "debug", que incluye cada sondeo del
+/// health check) durante `duration_secs` y después lo devuelve al nivel base.
+/// Ambas transiciones quedan en stacklume.log.
 #[tauri::command]
-fn open_in_vscode(path: String) -> Result<(), String> {
-    if path.contains('\0') || path.contains('\n') || path.contains('\r') {
-        return Err("Ruta inválida".to_string());
+fn boost_log_level(app: tauri::AppHandle, level: String, duration_secs: u64) -> Result<(), String> {
+    let level = LogLevel::parse(&level)
+        .ok_or_else(|| format!("Nivel de log no válido: '{}' (usa error, warn, info o debug)", level))?;
+    if !(1..=3600).contains(&duration_secs) {
+        return Err("La duración debe estar entre 1 y 3600 segundos".to_string());
     }
-    std::process::Command::new("code")
-        .arg(&path)
-        .spawn()
-        .map_err(|_| "VS Code no encontrado. Asegúrate de que 'code' está en el PATH.".to_string())?;
+    let base = base_log_level();
+    if level <= base {
+        return Err(format!("El nivel base ya es '{}'; no hay nada que elevar", base.as_str()));
+    }
+
+    let seq = LOG_BOOST_SEQ.fetch_add(1, Ordering::SeqCst) + 1;
+    let previous = log_level();
+    LOG_LEVEL.store(level as u8, Ordering::SeqCst);
+    app_log(&app, &format!(
+        "boost_log_level: '{}' -> '{}' durante {} s (base '{}')",
+        previous.as_str(), level.as_str(), duration_secs, base.as_str()
+    ));
+
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(duration_secs));
+        // Un boost posterior reemplaza a este y trae su propio temporizador
+        if LOG_BOOST_SEQ.load(Ordering::SeqCst) == seq {
+            // Anotarlo antes de bajar el nivel, que podría filtrar la propia línea
+            app_log(&app, &format!("boost_log_level: fin, nivel '{}' restaurado", base_log_level().as_str()));
+            LOG_LEVEL.store(base_log_level() as u8, Ordering::SeqCst);
+        }
+    });
     Ok(())
 }
 
-#[tauri::command]
-fn get_server_port(state: State<'_, ServerState>) -> u16 {
-    *state.port.lock().unwrap()
+// ─── Procesos descendientes ───────────────────────────────────────────────────
+
+/// Tabla PID → PID padre de todos los procesos del sistema.
+#[cfg(windows)]
+fn process_parents() -> std::collections::HashMap<u32, u32> {
+    use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+        TH32CS_SNAPPROCESS,
+    };
+
+    let mut parents = std::collections::HashMap::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return parents;
+        }
+        let mut entry: PROCESSENTRY32W = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+        if Process32FirstW(snapshot, &mut entry) != 0 {
+            loop {
+                parents.insert(entry.th32ProcessID, entry.th32ParentProcessID);
+                if Process32NextW(snapshot, &mut entry) == 0 {
+                    break;
+                }
+            }
+        }
+        CloseHandle(snapshot);
+    }
+    parents
+}
+
+/// Tabla PID → PID padre de todos los procesos del sistema (campo 4 de /proc/<pid>/stat).
+#[cfg(target_os = "linux")]
+fn process_parents() -> std::collections::HashMap<u32, u32> {
+    let mut parents = std::collections::HashMap::new();
+    let Ok(entries) = std::fs::read_dir("/proc") else { return parents };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let Some(pid) = entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else { continue };
+        // El nombre (campo 2) va entre paréntesis y puede contener espacios
+        let ppid = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|v| v.parse::<u32>().ok());
+        if let Some(ppid) = ppid {
+            parents.insert(pid, ppid);
+        }
+    }
+    parents
 }
 
-#[tauri::command]
-fn get_app_data_dir(app: tauri::AppHandle) -> String {
-    app.path()
-        .app_data_dir()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string()
+/// Tabla PID → PID padre de todos los procesos del sistema (vía `ps`).
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_parents() -> std::collections::HashMap<u32, u32> {
+    let output = silent_command("ps").args(["-A", "-o", "pid=,ppid="]).output();
+    let stdout = output.map(|o| o.stdout).unwrap_or_default();
+    String::from_utf8_lossy(&stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut it = line.split_whitespace();
+            Some((it.next()?.parse().ok()?, it.next()?.parse().ok()?))
+        })
+        .collect()
 }
 
-#[tauri::command]
-fn minimize_window(window: tauri::WebviewWindow) {
-    let _ = window.minimize();
+/// Cadena de ancestros desde `pid` hasta este proceso (ambos incluidos), o None si
+/// `pid` no desciende de Stacklume. Se limita la profundidad por si el OS ha
+/// reciclado algún PID y la tabla contiene un ciclo.
+fn descendant_chain(parents: &std::collections::HashMap<u32, u32>, pid: u32) -> Option<Vec<u32>> {
+    let me = std::process::id();
+    let mut chain = vec![pid];
+    let mut current = pid;
+    for _ in 0..32 {
+        let parent = *parents.get(&current)?;
+        if parent == 0 || parent == current {
+            return None;
+        }
+        chain.push(parent);
+        if parent == me {
+            return Some(chain);
+        }
+        current = parent;
+    }
+    None
 }
 
-#[tauri::command]
-fn toggle_maximize_window(window: tauri::WebviewWindow) {
-    if window.is_maximized().unwrap_or(false) {
-        let _ = window.unmaximize();
-    } else {
-        let _ = window.maximize();
+/// Termina un proceso por PID (TerminateProcess en Windows, SIGKILL en Unix).
+fn terminate_pid(pid: u32) -> Result<(), String> {
+    #[cfg(windows)]
+    unsafe {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::Threading::{OpenProcess, TerminateProcess};
+        const PROCESS_TERMINATE: u32 = 0x0001;
+
+        let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if process.is_null() {
+            return Err(format!("No se pudo abrir el proceso {}: {}", pid, std::io::Error::last_os_error()));
+        }
+        let ok = TerminateProcess(process, 1);
+        let err = std::io::Error::last_os_error();
+        CloseHandle(process);
+        if ok == 0 {
+            return Err(format!("TerminateProcess({}) falló: {}", pid, err));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    {
+        let status = silent_command("kill")
+            .args(["-KILL", &pid.to_string()])
+            .status()
+            .map_err(|e| format!("Error ejecutando kill: {}", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("kill -KILL {} falló ({})", pid, status))
+        }
     }
 }
 
+/// PIDs de todos los procesos que descienden de Stacklume (node.exe, sus workers,
+/// llama-server...). Requiere STACKLUME_ENABLE_DEBUG_CMDS=1.
 #[tauri::command]
-fn close_window(window: tauri::WebviewWindow) {
-    let _ = window.close();
+fn list_child_pids() -> Result<Vec<u32>, String> {
+    if !debug_commands_enabled() {
+        return Err("Comando deshabilitado (requiere STACKLUME_ENABLE_DEBUG_CMDS=1)".to_string());
+    }
+    let parents = process_parents();
+    let mut pids: Vec<u32> = parents
+        .keys()
+        .copied()
+        .filter(|pid| descendant_chain(&parents, *pid).is_some())
+        .collect();
+    pids.sort_unstable();
+    Ok(pids)
 }
 
-/// Actualiza el icono del system tray con un frame RGBA enviado desde el frontend.
-/// Se llama ~30 veces por segundo desde TrayIconUpdater.tsx.
+/// Mata un proceso concreto, solo si desciende de Stacklume (verificado recorriendo
+/// los PID padre), para limpiar un worker colgado sin tocar nada ajeno.
+/// Requiere STACKLUME_ENABLE_DEBUG_CMDS=1; cada kill queda en stacklume.log con su ascendencia.
 #[tauri::command]
-fn update_tray_icon(
-    app: tauri::AppHandle,
-    rgba: Vec<u8>,
-    width: u32,
-    height: u32,
-) -> Result<(), String> {
-    // Validar dimensiones: máximo 256x256 (suficiente para cualquier icono de tray)
-    if width == 0 || height == 0 || width > 256 || height > 256 {
-        return Err(format!("Dimensiones de icono inválidas: {}x{} (máx 256x256)", width, height));
-    }
-    // Validar que el buffer RGBA tiene exactamente el tamaño esperado
-    let expected_len = (width as usize) * (height as usize) * 4;
-    if rgba.len() != expected_len {
-        return Err(format!(
-            "Tamaño de buffer RGBA incorrecto: {} bytes (esperados {} para {}x{})",
-            rgba.len(), expected_len, width, height
-        ));
+fn kill_pid(app: tauri::AppHandle, pid: u32) -> Result<(), String> {
+    if !debug_commands_enabled() {
+        return Err("Comando deshabilitado (requiere STACKLUME_ENABLE_DEBUG_CMDS=1)".to_string());
     }
-    if let Some(tray) = app.tray_by_id("main") {
-        let icon = tauri::image::Image::new_owned(rgba, width, height);
-        tray.set_icon(Some(icon)).map_err(|e| e.to_string())?;
+    if pid == std::process::id() {
+        return Err("No se puede matar el propio proceso de Stacklume".to_string());
     }
-    Ok(())
+    let chain = descendant_chain(&process_parents(), pid).ok_or_else(|| {
+        app_log(&app, &format!("kill_pid: rechazado, {} no desciende de Stacklume", pid));
+        format!("El proceso {} no es descendiente de Stacklume", pid)
+    })?;
+    let chain_str = chain.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(" <- ");
+    let result = terminate_pid(pid);
+    app_log(&app, &format!(
+        "kill_pid: {} (ascendencia {}) → {}",
+        pid,
+        chain_str,
+        match &result { Ok(()) => "terminado".to_string(), Err(e) => e.clone() }
+    ));
+    result
 }
 
 // ─── Comandos LLM local ───────────────────────────────────────────────────────
@@ -842,6 +5375,116 @@ fn get_app_version(app: tauri::AppHandle) -> String {
     app.package_info().version.to_string()
 }
 
+#[derive(serde::Serialize)]
+struct VersionDto {
+    /// Versión de la app (tauri.conf.json), la misma que `get_app_version`
+    app_version: String,
+    /// `node --version` del node.exe empaquetado (None si aún no se leyó o en dev)
+    node_version: Option<String>,
+    /// BUILD_ID del build de Next.js empaquetado (None en dev o si no está)
+    server_build_id: Option<String>,
+}
+
+/// Versiones de la app, de node.exe y del build del servidor, para el diálogo
+/// "Acerca de" y para asociar un informe de error a su build.
+#[tauri::command]
+fn get_version_info(app: tauri::AppHandle) -> VersionDto {
+    VersionDto {
+        app_version: app.package_info().version.to_string(),
+        node_version: app.state::<ServerState>().node_version.lock().unwrap().clone(),
+        server_build_id: server_build_id(&app),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct BuildInfo {
+    /// Commit corto del que se compiló ("unknown" fuera de un repo git)
+    git_sha: &'static str,
+    /// Perfil de cargo: "debug" o "release"
+    profile: &'static str,
+    /// Target triple (p.ej. "x86_64-pc-windows-msvc")
+    target: &'static str,
+    /// Salida de `rustc --version` del compilador usado
+    rustc_version: &'static str,
+}
+
+/// Metadatos fijados al compilar (los captura build.rs), para que un informe de
+/// error diga exactamente de qué build sale.
+#[tauri::command]
+fn get_build_info() -> BuildInfo {
+    BuildInfo {
+        git_sha: env!("STACKLUME_GIT_SHA"),
+        profile: env!("STACKLUME_BUILD_PROFILE"),
+        target: env!("TARGET"),
+        rustc_version: env!("STACKLUME_RUSTC_VERSION"),
+    }
+}
+
+/// BUILD_ID del servidor: `server/BUILD_ID` si el empaquetado lo copia ahí o, si no,
+/// el que Next.js deja en `server/.next/BUILD_ID`.
+fn server_build_id(app: &tauri::AppHandle) -> Option<String> {
+    if cfg!(dev) {
+        return None;
+    }
+    let resource_dir = app.path().resource_dir().ok()?;
+    ["server/BUILD_ID", "server/.next/BUILD_ID"]
+        .iter()
+        .filter_map(|rel| std::fs::read_to_string(resolve_resource(&resource_dir, rel)).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+/// Nombre del paquete que crea `export_diagnostics` en Descargas.
+const DIAGNOSTICS_ZIP_NAME: &str = "stacklume-diagnostics.zip";
+
+/// Empaqueta stacklume.log, server.log, diagnósticos, versiones y las variables de
+/// entorno (solo los nombres: los valores pueden contener claves) en
+/// `stacklume-diagnostics.zip` dentro de Descargas. Devuelve la ruta del zip.
+#[tauri::command]
+async fn export_diagnostics(app: tauri::AppHandle) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let diagnostics = serde_json::to_vec_pretty(&get_diagnostics(app.clone())).map_err(|e| e.to_string())?;
+        let versions = serde_json::to_vec_pretty(&get_version_info(app.clone())).map_err(|e| e.to_string())?;
+        let mut keys: Vec<String> = std::env::vars_os()
+            .map(|(k, _)| format!("{}=<redacted>", k.to_string_lossy()))
+            .collect();
+        keys.sort();
+        let environment = keys.join("\n");
+
+        let app_data = app_data_dir(&app).map_err(|e| e.to_string())?;
+        let downloads = app.path().download_dir().unwrap_or_else(|_| app_data.clone());
+        std::fs::create_dir_all(&downloads)
+            .map_err(|e| format!("No se pudo crear {}: {}", downloads.display(), e))?;
+        let zip_path = downloads.join(DIAGNOSTICS_ZIP_NAME);
+
+        let file = std::fs::File::create(&zip_path)
+            .map_err(|e| format!("No se pudo crear {}: {}", zip_path.display(), e))?;
+        let mut zip = zip::ZipWriter::new(file);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        let mut add = |name: &str, bytes: &[u8]| -> Result<(), String> {
+            use std::io::Write;
+            zip.start_file(name, options).map_err(|e| format!("Error escribiendo el zip: {}", e))?;
+            zip.write_all(bytes).map_err(|e| format!("Error escribiendo el zip: {}", e))
+        };
+        // Un log que aún no existe no es un error: se deja fuera del paquete
+        for name in ["stacklume.log", "server.log"] {
+            if let Ok(bytes) = std::fs::read(app_data.join(name)) {
+                add(name, &bytes)?;
+            }
+        }
+        add("diagnostics.json", &diagnostics)?;
+        add("version.json", &versions)?;
+        add("environment.txt", environment.as_bytes())?;
+        zip.finish().map_err(|e| format!("Error escribiendo el zip: {}", e))?;
+
+        app_log(&app, &format!("Diagnósticos exportados a {}", zip_path.display()));
+        Ok(zip_path.to_string_lossy().to_string())
+    })
+    .await
+    .map_err(|e| format!("Error interno: {}", e))?
+}
+
 /// Estado del LLM local: "no_binary" | "no_model" | "starting" | "ready" | "error"
 #[tauri::command]
 fn get_llm_status(state: State<'_, LlamaState>) -> String {
@@ -909,7 +5552,7 @@ fn download_with_curl(
     };
 
     // Log para debug
-    let log_dir = app.path().app_data_dir().unwrap_or_default();
+    let log_dir = app_data_dir(app).unwrap_or_default();
     let log_path = log_dir.join("download.log");
     let log = |msg: &str| {
         use std::io::Write;
@@ -1088,9 +5731,7 @@ async fn download_llm_model_impl(
         }
     }
 
-    let app_data = app
-        .path()
-        .app_data_dir()
+    let app_data = app_data_dir(&app)
         .map_err(|e| format!("Error app_data: {}", e))?;
     let models_dir = app_data.join("models");
     let _ = std::fs::create_dir_all(&models_dir);
@@ -1174,7 +5815,7 @@ async fn download_llm_model_impl(
 /// Devuelve true si el soporte de visión está disponible.
 #[tauri::command]
 fn check_vision_status(app: tauri::AppHandle) -> bool {
-    let Ok(app_data) = app.path().app_data_dir() else { return false };
+    let Ok(app_data) = app_data_dir(&app) else { return false };
     app_data.join("models").join("mmproj-F16.gguf").exists()
 }
 
@@ -1204,9 +5845,7 @@ async fn download_mmproj(app: tauri::AppHandle) -> Result<(), String> {
     const MMPROJ_URL: &str =
         "https://huggingface.co/unsloth/Qwen3.5-2B-GGUF/resolve/main/mmproj-F16.gguf";
 
-    let app_data = app
-        .path()
-        .app_data_dir()
+    let app_data = app_data_dir(&app)
         .map_err(|e| format!("Error app_data: {}", e))?;
     let models_dir = app_data.join("models");
     let _ = std::fs::create_dir_all(&models_dir);
@@ -1240,7 +5879,7 @@ struct ModelPreferences {
 }
 
 fn model_prefs_path(app: &tauri::AppHandle) -> std::path::PathBuf {
-    let app_data = app.path().app_data_dir().unwrap_or_default();
+    let app_data = app_data_dir(app).unwrap_or_default();
     app_data.join("models.json")
 }
 
@@ -1259,6 +5898,188 @@ fn save_model_prefs(app: &tauri::AppHandle, prefs: &ModelPreferences) {
     }
 }
 
+// ─── Ajustes de la app ────────────────────────────────────────────────────────
+
+/// Ajustes persistentes del shell nativo.
+/// Se persiste en `%APPDATA%/com.stacklume.app/settings.json`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+struct AppSettings {
+    /// Escribir también los fallos fatales de arranque en el Visor de eventos de
+    /// Windows (log Application), para monitorización con herramientas estándar
+    #[serde(default)]
+    windows_event_log: bool,
+    /// Qué streams de node.exe se redirigen a server.log (se aplica en el próximo arranque)
+    #[serde(default)]
+    log_capture: LogCapture,
+    /// Pedir confirmación a la UI antes de salir de la app (evento `confirm-quit`)
+    #[serde(default)]
+    confirm_quit: bool,
+    /// Hora local "HH:MM" del reinicio diario programado de node.exe (None = desactivado)
+    #[serde(default)]
+    restart_at: Option<String>,
+    /// Cómo queda la ventana al terminar el arranque (--minimized/--hidden tienen prioridad)
+    #[serde(default)]
+    launch_visibility: LaunchVisibility,
+    /// Locale elegido por el usuario; None = el del sistema
+    #[serde(default)]
+    locale: Option<String>,
+    /// Ventana "siempre encima" (ver `set_always_on_top`)
+    #[serde(default)]
+    always_on_top: bool,
+    /// Claves desconocidas (p.ej. escritas por una versión más nueva): se conservan al guardar
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Streams de node.exe que se capturan en server.log; el resto va a null.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogCapture {
+    #[default]
+    Both,
+    Stderr,
+    Stdout,
+    None,
+}
+
+impl LogCapture {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "both" => Some(Self::Both),
+            "stderr" => Some(Self::Stderr),
+            "stdout" => Some(Self::Stdout),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::Stderr => "stderr",
+            Self::Stdout => "stdout",
+            Self::None => "none",
+        }
+    }
+}
+
+/// Estado de la ventana principal tras el arranque. Minimized/Hidden sirven para el
+/// autoarranque al iniciar sesión: el servidor arranca igual, pero la ventana no salta.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LaunchVisibility {
+    #[default]
+    Normal,
+    Minimized,
+    Hidden,
+}
+
+impl LaunchVisibility {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "normal" => Some(Self::Normal),
+            "minimized" => Some(Self::Minimized),
+            "hidden" => Some(Self::Hidden),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Minimized => "minimized",
+            Self::Hidden => "hidden",
+        }
+    }
+}
+
+/// Visibilidad de arranque efectiva: flags de línea de comandos (--hidden gana a
+/// --minimized) y, si no hay ninguno, `launch_visibility` de settings.json.
+fn launch_visibility(app: &tauri::AppHandle) -> LaunchVisibility {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--hidden") {
+        LaunchVisibility::Hidden
+    } else if args.iter().any(|a| a == "--minimized") {
+        LaunchVisibility::Minimized
+    } else {
+        load_settings(app).launch_visibility
+    }
+}
+
+/// Muestra la ventana principal según `visibility` (Hidden la deja en el tray).
+/// Las páginas de error NO pasan por aquí: se muestran siempre.
+fn present_main_window(window: &tauri::WebviewWindow, visibility: LaunchVisibility) -> tauri::Result<()> {
+    match visibility {
+        LaunchVisibility::Normal => window.show(),
+        LaunchVisibility::Minimized => window.minimize(),
+        LaunchVisibility::Hidden => Ok(()),
+    }
+}
+
+fn settings_path(app: &tauri::AppHandle) -> std::path::PathBuf {
+    app_data_dir(app).unwrap_or_default().join("settings.json")
+}
+
+/// Carga settings.json. Nunca falla: si no existe devuelve los valores por defecto,
+/// y si está corrupto (escritura parcial, edición manual) lo aparta con
+/// `backup_corrupt_settings` antes de volver a los valores por defecto.
+fn load_settings(app: &tauri::AppHandle) -> AppSettings {
+    let path = settings_path(app);
+    let bytes = match std::fs::read(&path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return AppSettings::default(),
+        Err(e) => {
+            app_log_at(app, LogLevel::Warn, &format!("WARN: No se pudo leer settings.json: {}", e));
+            return AppSettings::default();
+        }
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(settings) => settings,
+        Err(e) => {
+            backup_corrupt_settings(app, &path, &e.to_string());
+            AppSettings::default()
+        }
+    }
+}
+
+fn save_settings(app: &tauri::AppHandle, settings: &AppSettings) -> Result<(), String> {
+    let path = settings_path(app);
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json).map_err(|e| format!("No se pudo guardar settings.json: {}", e))
+}
+
+/// Mueve un settings.json ilegible a `settings.json.corrupt.<ts>` (nunca se borra,
+/// para poder recuperarlo a mano) y emite `settings:corrupt` para que la UI avise.
+fn backup_corrupt_settings(app: &tauri::AppHandle, path: &std::path::Path, error: &str) {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let backup = path.with_file_name(format!("settings.json.corrupt.{}", ts));
+    // rename es atómico; si falla (p.ej. otro volumen/permiso) intentar copiar
+    let moved = std::fs::rename(path, &backup).is_ok()
+        || (std::fs::copy(path, &backup).is_ok() && std::fs::remove_file(path).is_ok());
+    if moved {
+        app_log_at(app, LogLevel::Warn, &format!(
+            "WARN: settings.json corrupto ({}) — respaldado en {} y usando valores por defecto",
+            error,
+            backup.display()
+        ));
+    } else {
+        app_log_at(app, LogLevel::Warn, &format!(
+            "WARN: settings.json corrupto ({}) y no se pudo respaldar — usando valores por defecto",
+            error
+        ));
+    }
+    let _ = app.emit(
+        "settings:corrupt",
+        serde_json::json!({
+            "error": error,
+            "backupPath": if moved { Some(backup.to_string_lossy().to_string()) } else { None },
+        }),
+    );
+}
+
 /// Detecta la familia del modelo por su nombre de archivo para auto-configurar parámetros.
 fn detect_model_family(filename: &str) -> &'static str {
     let lower = filename.to_lowercase();
@@ -1291,7 +6112,7 @@ fn display_name_from_filename(filename: &str) -> String {
 /// Lista todos los modelos .gguf descargados e indica cuál está activo.
 #[tauri::command]
 fn list_models(app: tauri::AppHandle) -> Result<serde_json::Value, String> {
-    let app_data = app.path().app_data_dir().map_err(|e| format!("{}", e))?;
+    let app_data = app_data_dir(&app).map_err(|e| format!("{}", e))?;
     let models_dir = app_data.join("models");
     let prefs = load_model_prefs(&app);
     let active = prefs.active_model.clone();
@@ -1494,7 +6315,7 @@ async fn switch_model(app: tauri::AppHandle, filename: String) -> Result<(), Str
         return Err("Nombre de modelo inválido".to_string());
     }
 
-    let app_data = app.path().app_data_dir().map_err(|e| format!("{}", e))?;
+    let app_data = app_data_dir(&app).map_err(|e| format!("{}", e))?;
     let model_path = app_data.join("models").join(&filename);
     if !model_path.exists() {
         return Err(format!("Modelo no encontrado: {}", filename));
@@ -1548,90 +6369,426 @@ async fn switch_model(app: tauri::AppHandle, filename: String) -> Result<(), Str
     Ok(())
 }
 
-/// Obtiene el token de HuggingFace configurado (vacío si no hay).
-#[tauri::command]
-fn get_hf_token(app: tauri::AppHandle) -> String {
-    load_model_prefs(&app).hf_token.unwrap_or_default()
+/// Obtiene el token de HuggingFace configurado (vacío si no hay).
+#[tauri::command]
+fn get_hf_token(app: tauri::AppHandle) -> String {
+    load_model_prefs(&app).hf_token.unwrap_or_default()
+}
+
+/// Guarda el token de HuggingFace para descargas de modelos gated/privados.
+#[tauri::command]
+fn set_hf_token(app: tauri::AppHandle, token: String) -> Result<(), String> {
+    let mut prefs = load_model_prefs(&app);
+    prefs.hf_token = if token.trim().is_empty() { None } else { Some(token.trim().to_string()) };
+    save_model_prefs(&app, &prefs);
+    Ok(())
+}
+
+/// Elimina un modelo .gguf descargado. Si era el activo, para llama-server.
+#[tauri::command]
+async fn delete_model(app: tauri::AppHandle, filename: String) -> Result<(), String> {
+    // Validar nombre
+    if filename.is_empty() || filename.contains('/') || filename.contains('\\') || filename.contains("..") {
+        return Err("Nombre de modelo inválido".to_string());
+    }
+    if !filename.to_lowercase().ends_with(".gguf") {
+        return Err("Solo se pueden eliminar archivos .gguf".to_string());
+    }
+    // No permitir eliminar mmproj
+    if filename.to_lowercase().contains("mmproj") {
+        return Err("El proyector de visión no se puede eliminar desde aquí".to_string());
+    }
+
+    let app_data = app_data_dir(&app).map_err(|e| format!("{}", e))?;
+    let model_path = app_data.join("models").join(&filename);
+    if !model_path.exists() {
+        return Err("Modelo no encontrado".to_string());
+    }
+
+    // Si es el modelo activo, parar llama-server primero
+    let is_active = {
+        let state = app.state::<LlamaState>();
+        let current = state.model_path.lock().unwrap().clone();
+        current.as_deref() == Some(model_path.to_string_lossy().as_ref())
+    };
+
+    if is_active {
+        #[cfg(not(dev))]
+        {
+            let state = app.state::<LlamaState>();
+            let child_opt = state.llama_child.lock().unwrap().take();
+            if let Some(mut child) = child_opt {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+        let state = app.state::<LlamaState>();
+        *state.model_path.lock().unwrap() = None;
+        *state.status.lock().unwrap() = "no_model".to_string();
+        let _ = app.emit("llm:status-changed", "no_model");
+    }
+
+    // Eliminar archivo
+    std::fs::remove_file(&model_path)
+        .map_err(|e| format!("Error al eliminar: {}", e))?;
+
+    // Actualizar preferencias
+    let mut prefs = load_model_prefs(&app);
+    if prefs.active_model.as_deref() == Some(&filename) {
+        prefs.active_model = None;
+    }
+    save_model_prefs(&app, &prefs);
+
+    Ok(())
+}
+
+// ─── Instancia única ──────────────────────────────────────────────────────────
+
+/// Lock de instancia única: `stacklume.lock` en app_data, bloqueado en exclusiva
+/// mientras vive el proceso. El OS libera el lock si el proceso muere, así que
+/// nunca queda un lock huérfano tras un crash.
+struct InstanceLock {
+    /// true si este proceso posee el lock (primera instancia)
+    is_primary: bool,
+    path: std::path::PathBuf,
+    /// Mantener el archivo abierto es lo que mantiene el lock
+    _file: Option<std::fs::File>,
+}
+
+impl InstanceLock {
+    /// Suelta el lock antes de tiempo (relanzamiento): el archivo sigue abierto
+    /// hasta salir, pero otra instancia ya puede tomarlo.
+    fn release(&self) {
+        if let Some(file) = &self._file {
+            let _ = file.unlock();
+        }
+    }
+
+    /// true si el lock está realmente tomado (no solo asumido porque no se pudo abrir)
+    #[cfg(not(dev))]
+    fn is_held(&self) -> bool {
+        self.is_primary && self._file.is_some()
+    }
+}
+
+fn acquire_instance_lock(app_data: &std::path::Path) -> InstanceLock {
+    use std::io::Write;
+
+    let path = app_data.join("stacklume.lock");
+    let _ = std::fs::create_dir_all(app_data);
+    let file = match std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            // Sin poder abrir el lock no podemos saber si hay otra instancia:
+            // seguir como primaria antes que bloquear el arranque.
+            eprintln!("[Stacklume] WARN: No se pudo abrir {}: {}", path.display(), e);
+            return InstanceLock { is_primary: true, path, _file: None };
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => {
+            // Guardar el PID del propietario (solo informativo, para diagnóstico)
+            let _ = file.set_len(0);
+            let _ = write!(&file, "{}", std::process::id());
+            InstanceLock { is_primary: true, path, _file: Some(file) }
+        }
+        Err(_) => InstanceLock { is_primary: false, path, _file: None },
+    }
+}
+
+/// Archivo de app_data con el puerto loopback donde la instancia primaria atiende
+/// las peticiones de foco de los arranques posteriores.
+fn instance_port_path(app_data: &std::path::Path) -> std::path::PathBuf {
+    app_data.join("instance.port")
+}
+
+/// Instancia primaria: escucha en 127.0.0.1 (puerto efímero, anotado en
+/// instance.port) y, cuando otro arranque se conecta, muestra, restaura y enfoca
+/// la ventana principal. No toca el servidor ni el Job Object.
+fn start_instance_listener(app: &tauri::AppHandle, app_data: &std::path::Path) {
+    let listener = match std::net::TcpListener::bind("127.0.0.1:0") {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[Stacklume] WARN: No se pudo abrir el canal de instancia única: {}", e);
+            return;
+        }
+    };
+    let Ok(addr) = listener.local_addr() else { return };
+    if let Err(e) = std::fs::write(instance_port_path(app_data), addr.port().to_string()) {
+        eprintln!("[Stacklume] WARN: No se pudo escribir instance.port: {}", e);
+        return;
+    }
+    let app = app.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(2)));
+            let mut msg = String::new();
+            let _ = (&mut stream).take(64).read_to_string(&mut msg);
+            if msg.trim() != "focus" {
+                continue;
+            }
+            app_log(&app, "Segunda instancia detectada: enfocando la ventana existente");
+            if let Some(w) = app.get_webview_window("main") {
+                let _ = w.show();
+                let _ = w.unminimize();
+                let _ = w.set_focus();
+            }
+        }
+    });
+}
+
+/// Segunda instancia: pide a la primaria que enfoque su ventana. Reintenta unos
+/// segundos por si la primaria aún está arrancando. Devuelve true si la avisó.
+fn signal_primary_instance(app_data: &std::path::Path) -> bool {
+    use std::io::Write;
+    for _ in 0..20 {
+        let port = std::fs::read_to_string(instance_port_path(app_data))
+            .ok()
+            .and_then(|s| s.trim().parse::<u16>().ok());
+        if let Some(port) = port {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            if let Ok(mut stream) =
+                std::net::TcpStream::connect_timeout(&addr, std::time::Duration::from_millis(500))
+            {
+                return stream.write_all(b"focus\n").is_ok();
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    false
+}
+
+#[derive(serde::Serialize)]
+struct InstanceLockInfo {
+    is_primary: bool,
+    lock_path: String,
 }
 
-/// Guarda el token de HuggingFace para descargas de modelos gated/privados.
+/// Indica si este proceso posee el lock de instancia única y dónde vive el lock.
+/// Útil para explicar por qué un segundo arranque solo "enfoca la ventana vieja".
 #[tauri::command]
-fn set_hf_token(app: tauri::AppHandle, token: String) -> Result<(), String> {
-    let mut prefs = load_model_prefs(&app);
-    prefs.hf_token = if token.trim().is_empty() { None } else { Some(token.trim().to_string()) };
-    save_model_prefs(&app, &prefs);
-    Ok(())
+fn instance_lock_info(lock: State<'_, InstanceLock>) -> InstanceLockInfo {
+    InstanceLockInfo {
+        is_primary: lock.is_primary,
+        lock_path: lock.path.to_string_lossy().to_string(),
+    }
 }
 
-/// Elimina un modelo .gguf descargado. Si era el activo, para llama-server.
-#[tauri::command]
-async fn delete_model(app: tauri::AppHandle, filename: String) -> Result<(), String> {
-    // Validar nombre
-    if filename.is_empty() || filename.contains('/') || filename.contains('\\') || filename.contains("..") {
-        return Err("Nombre de modelo inválido".to_string());
+// ─── Idioma ────────────────────────────────────────────────────────────────────
+
+/// Normaliza y valida una etiqueta de idioma estilo BCP-47 ("es", "es-ES",
+/// "zh-Hant-TW"; también acepta "es_ES.UTF-8" como viene de LANG en Unix).
+/// Devuelve None si no tiene forma de etiqueta válida.
+fn normalize_locale(tag: &str) -> Option<String> {
+    // Quitar codificación y modificador POSIX ("es_ES.UTF-8@euro")
+    let tag = tag.split(['.', '@']).next()?.trim().replace('_', "-");
+    if tag.is_empty() || tag.len() > 35 {
+        return None;
     }
-    if !filename.to_lowercase().ends_with(".gguf") {
-        return Err("Solo se pueden eliminar archivos .gguf".to_string());
+    let mut parts = tag.split('-');
+    let lang = parts.next()?;
+    if !(2..=3).contains(&lang.len()) || !lang.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
     }
-    // No permitir eliminar mmproj
-    if filename.to_lowercase().contains("mmproj") {
-        return Err("El proyector de visión no se puede eliminar desde aquí".to_string());
+    let mut out = lang.to_ascii_lowercase();
+    for part in parts {
+        if !(2..=8).contains(&part.len()) || !part.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        out.push('-');
+        // Convención de mayúsculas: región en mayúsculas, script capitalizado
+        match part.len() {
+            2 => out.push_str(&part.to_ascii_uppercase()),
+            4 => {
+                out.push_str(&part[..1].to_ascii_uppercase());
+                out.push_str(&part[1..].to_ascii_lowercase());
+            }
+            _ => out.push_str(&part.to_ascii_lowercase()),
+        }
     }
+    Some(out)
+}
 
-    let app_data = app.path().app_data_dir().map_err(|e| format!("{}", e))?;
-    let model_path = app_data.join("models").join(&filename);
-    if !model_path.exists() {
-        return Err("Modelo no encontrado".to_string());
+/// Locale del sistema operativo (GetUserDefaultLocaleName en Windows, LC_ALL /
+/// LC_MESSAGES / LANG en Unix).
+fn system_locale() -> Option<String> {
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Globalization::GetUserDefaultLocaleName;
+        let mut buf = [0u16; 85]; // LOCALE_NAME_MAX_LENGTH
+        let len = unsafe { GetUserDefaultLocaleName(buf.as_mut_ptr(), buf.len() as i32) };
+        if len <= 1 {
+            return None;
+        }
+        normalize_locale(&String::from_utf16_lossy(&buf[..(len - 1) as usize]))
     }
 
-    // Si es el modelo activo, parar llama-server primero
-    let is_active = {
-        let state = app.state::<LlamaState>();
-        let current = state.model_path.lock().unwrap().clone();
-        current.as_deref() == Some(model_path.to_string_lossy().as_ref())
+    #[cfg(not(windows))]
+    {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|k| std::env::var(k).ok())
+            .find(|v| !v.is_empty() && v != "C" && v != "POSIX")
+            .and_then(|v| normalize_locale(&v))
+    }
+}
+
+/// Locale efectivo: el elegido en settings.json o, si no hay, el del sistema
+/// (por defecto "es", el idioma base de la app).
+fn effective_locale(app: &tauri::AppHandle) -> String {
+    load_settings(app)
+        .locale
+        .and_then(|l| normalize_locale(&l))
+        .or_else(system_locale)
+        .unwrap_or_else(|| "es".to_string())
+}
+
+#[tauri::command]
+fn get_locale(app: tauri::AppHandle) -> String {
+    effective_locale(&app)
+}
+
+/// Fija el idioma que se pasa al servidor (STACKLUME_LOCALE). Con `tag` vacío vuelve
+/// al del sistema. Se aplica en el próximo arranque de node; se emite
+/// `locale-changed` para que la UI cambie de idioma o llame a `restart_server`.
+#[tauri::command]
+fn set_locale(app: tauri::AppHandle, tag: String) -> Result<String, String> {
+    let locale = if tag.trim().is_empty() {
+        None
+    } else {
+        Some(normalize_locale(&tag).ok_or_else(|| format!("Etiqueta de idioma no válida: '{}'", tag))?)
     };
+    let mut settings = load_settings(&app);
+    settings.locale = locale;
+    save_settings(&app, &settings)?;
 
-    if is_active {
-        #[cfg(not(dev))]
-        {
-            let state = app.state::<LlamaState>();
-            let child_opt = state.llama_child.lock().unwrap().take();
-            if let Some(mut child) = child_opt {
-                let _ = child.kill();
-                let _ = child.wait();
-            }
+    let effective = effective_locale(&app);
+    #[cfg(not(dev))]
+    if let Some(launch) = app.state::<ServerState>().launch.lock().unwrap().as_mut() {
+        launch.locale = effective.clone();
+    }
+    app_log(&app, &format!("set_locale: {}", effective));
+    let _ = app.emit("locale-changed", serde_json::json!({ "locale": effective }));
+    Ok(effective)
+}
+
+// ─── Atajos de teclado ────────────────────────────────────────────────────────
+
+/// Atajo global del Quick Launcher (registrado en setup).
+const QUICK_LAUNCHER_SHORTCUT: &str = "Ctrl+Shift+Space";
+
+#[derive(serde::Serialize)]
+struct ShortcutHints {
+    quit: &'static str,
+    reload: &'static str,
+    fullscreen: &'static str,
+    devtools: &'static str,
+    zoom_in: &'static str,
+    zoom_out: &'static str,
+    quick_launcher: &'static str,
+}
+
+/// Aceleradores propios de la plataforma (Cmd en macOS, Ctrl en el resto) para que
+/// la barra de título y los menús muestren los atajos correctos sin adivinar el OS en JS.
+#[tauri::command]
+fn shortcut_hints() -> ShortcutHints {
+    if cfg!(target_os = "macos") {
+        ShortcutHints {
+            quit: "Cmd+Q",
+            reload: "Cmd+R",
+            fullscreen: "Ctrl+Cmd+F",
+            devtools: "Cmd+Alt+I",
+            zoom_in: "Cmd+=",
+            zoom_out: "Cmd+-",
+            quick_launcher: QUICK_LAUNCHER_SHORTCUT,
+        }
+    } else {
+        ShortcutHints {
+            quit: if cfg!(windows) { "Alt+F4" } else { "Ctrl+Q" },
+            reload: "Ctrl+R",
+            fullscreen: "F11",
+            devtools: "Ctrl+Shift+I",
+            zoom_in: "Ctrl+=",
+            zoom_out: "Ctrl+-",
+            quick_launcher: QUICK_LAUNCHER_SHORTCUT,
         }
-        let state = app.state::<LlamaState>();
-        *state.model_path.lock().unwrap() = None;
-        *state.status.lock().unwrap() = "no_model".to_string();
-        let _ = app.emit("llm:status-changed", "no_model");
     }
+}
 
-    // Eliminar archivo
-    std::fs::remove_file(&model_path)
-        .map_err(|e| format!("Error al eliminar: {}", e))?;
+// ─── System Tray ──────────────────────────────────────────────────────────────
 
-    // Actualizar preferencias
-    let mut prefs = load_model_prefs(&app);
-    if prefs.active_model.as_deref() == Some(&filename) {
-        prefs.active_model = None;
+/// Salida de la app (menú del tray → "Cerrar"). Con `confirm_quit` activado no sale:
+/// muestra la ventana y emite `confirm-quit`; la UI llama a `confirm_quit` si el
+/// usuario acepta. El botón X no pasa por aquí porque solo oculta al tray.
+fn request_quit(app: &tauri::AppHandle) {
+    if !load_settings(app).confirm_quit {
+        quit_app(app.clone());
+        return;
     }
-    save_model_prefs(&app, &prefs);
+    if let Some(w) = app.get_webview_window("main") {
+        let _ = w.show();
+        let _ = w.set_focus();
+    }
+    let _ = app.emit("confirm-quit", ());
+}
 
-    Ok(())
+/// Clic izquierdo en el icono del tray: oculta la ventana si está a la vista y,
+/// si no (oculta o minimizada), la muestra y le da el foco.
+fn toggle_main_window(app: &tauri::AppHandle) {
+    let Some(w) = app.get_webview_window("main") else { return };
+    if w.is_visible().unwrap_or(false) && !w.is_minimized().unwrap_or(false) {
+        save_window_geometry(&w);
+        let _ = w.hide();
+    } else {
+        let _ = w.unminimize();
+        let _ = w.show();
+        let _ = w.set_focus();
+    }
 }
 
-// ─── System Tray ──────────────────────────────────────────────────────────────
+/// Menú del tray → "Reiniciar servidor": mismo camino que el comando `restart_server`,
+/// en segundo plano para no bloquear el bucle de eventos.
+#[cfg(not(dev))]
+fn tray_restart_server(app: &tauri::AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let state = app.state::<ServerState>();
+        if let Err(e) = restart_server(app.clone(), state).await {
+            app_log(&app, &format!("tray: reinicio fallido: {}", e));
+        }
+    });
+}
+
+/// Menú del tray → "Abrir logs": server.log si node ya escribió algo, si no stacklume.log.
+fn tray_open_logs(app: &tauri::AppHandle) {
+    let result = open_log_file(app.clone(), "server".to_string())
+        .or_else(|_| open_log_file(app.clone(), "stacklume".to_string()));
+    if let Err(e) = result {
+        app_log(app, &format!("tray: no se pudo abrir el log: {}", e));
+    }
+}
 
 fn setup_tray(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error>> {
     use tauri::menu::{MenuBuilder, MenuItemBuilder};
     use tauri::tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent};
 
     let show_item = MenuItemBuilder::with_id("show", "Abrir Stacklume").build(app)?;
+    let logs_item = MenuItemBuilder::with_id("logs", "Abrir logs").build(app)?;
     let quit_item = MenuItemBuilder::with_id("quit", "Cerrar").build(app)?;
-    let menu = MenuBuilder::new(app)
-        .items(&[&show_item, &quit_item])
-        .build()?;
+    // En dev el servidor lo lanza beforeDevCommand: no hay nada que reiniciar
+    #[cfg(not(dev))]
+    let restart_item = MenuItemBuilder::with_id("restart", "Reiniciar servidor").build(app)?;
+    let menu = MenuBuilder::new(app).item(&show_item);
+    #[cfg(not(dev))]
+    let menu = menu.item(&restart_item);
+    let menu = menu.items(&[&logs_item]).separator().item(&quit_item).build()?;
 
     TrayIconBuilder::with_id("main")
         .icon(app.default_window_icon().unwrap().clone())
@@ -1640,11 +6797,15 @@ fn setup_tray(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error>> {
         .on_menu_event(|app, event| match event.id.as_ref() {
             "show" => {
                 if let Some(w) = app.get_webview_window("main") {
+                    let _ = w.unminimize();
                     let _ = w.show();
                     let _ = w.set_focus();
                 }
             }
-            "quit" => app.exit(0),
+            #[cfg(not(dev))]
+            "restart" => tray_restart_server(app),
+            "logs" => tray_open_logs(app),
+            "quit" => request_quit(app),
             _ => {}
         })
         .on_tray_icon_event(|tray, event| {
@@ -1654,11 +6815,7 @@ fn setup_tray(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error>> {
                 ..
             } = event
             {
-                let app = tray.app_handle();
-                if let Some(w) = app.get_webview_window("main") {
-                    let _ = w.show();
-                    let _ = w.set_focus();
-                }
+                toggle_main_window(tray.app_handle());
             }
         })
         .build(app)?;
@@ -1679,7 +6836,63 @@ pub fn run() {
             node_child: Mutex::new(None),
             #[cfg(windows)]
             node_job: Mutex::new(0),
+            #[cfg(not(dev))]
+            launch: Mutex::new(None),
+            ready: AtomicBool::new(false),
+            cancel: AtomicBool::new(false),
+            #[cfg(not(dev))]
+            visibility_seq: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(not(dev))]
+            spawn_generation: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(not(dev))]
+            idle_stopped: AtomicBool::new(false),
+            #[cfg(not(dev))]
+            db_recovery_pending: AtomicBool::new(false),
+            maintenance_pending: AtomicBool::new(false),
+            busy: AtomicBool::new(false),
+            #[cfg(not(dev))]
+            last_exit: Mutex::new(None),
+            pid: Mutex::new(None),
+            #[cfg(not(dev))]
+            spawned_at: Mutex::new(None),
+            supervision: Mutex::new(SupervisionCounters::default()),
+            session_token: generate_session_token(),
+            diagnostics: Mutex::new(None),
+            node_version: Mutex::new(None),
+            last_startup_ms: Mutex::new(None),
         })
+        // Guard de navegación durante el arranque: entre window.show() con la página de
+        // carga y el health check OK, una acción rápida del usuario o una URL cacheada
+        // podría cargar un servidor a medio arrancar. Se levanta al marcar `ready`.
+        .plugin(
+            tauri::plugin::Builder::<tauri::Wry>::new("startup-nav-guard")
+                .on_navigation(|webview, url| {
+                    // Botones de la página de recuperación de la DB: nunca se navega
+                    #[cfg(not(dev))]
+                    if url.scheme() == "stacklume" && url.host_str() == Some("recover") {
+                        handle_recovery_action(webview.app_handle(), url.path().trim_matches('/'));
+                        return false;
+                    }
+                    // Botón "Reintentar" de la página de mantenimiento
+                    if url.scheme() == "stacklume" && url.host_str() == Some("maintenance") {
+                        if url.path().trim_matches('/') == "retry" {
+                            handle_maintenance_retry(webview.app_handle());
+                        }
+                        return false;
+                    }
+                    let state = webview.state::<ServerState>();
+                    if state.ready.load(Ordering::SeqCst) {
+                        return true;
+                    }
+                    let port = *state.port.lock().unwrap();
+                    if is_server_origin(url, port) {
+                        eprintln!("[Stacklume] Navegación bloqueada (servidor no listo): {}", url);
+                        return false;
+                    }
+                    true
+                })
+                .build(),
+        )
         .manage(LlamaState {
             port: Mutex::new(0),
             status: Mutex::new("no_binary".to_string()),
@@ -1696,14 +6909,44 @@ pub fn run() {
             // llama-server spawneados después la heredan.
             // AllocConsole removido del setup — se hace en spawn_llama_server_blocking
 
+            // Lock de instancia única (dev y prod)
+            let instance_data = app_data_dir(app.handle()).unwrap_or_default();
+            let instance_lock = acquire_instance_lock(&instance_data);
+            if !instance_lock.is_primary {
+                eprintln!(
+                    "[Stacklume] Otra instancia posee el lock: {}",
+                    instance_lock.path.display()
+                );
+                // Enfocar la ventana de la instancia existente y salir antes de lanzar
+                // node o tocar nada compartido
+                if !signal_primary_instance(&instance_data) {
+                    eprintln!("[Stacklume] WARN: No se pudo avisar a la instancia existente");
+                }
+                std::process::exit(0);
+            }
+            start_instance_listener(app.handle(), &instance_data);
+            app.manage(instance_lock);
+
             // Crear system tray (dev y prod)
             setup_tray(app)?;
 
+            // Geometría (window.json), tema (theme.txt) y "siempre encima"
+            // (settings.json) guardados, antes de que se muestre la ventana
+            if let Some(w) = app.get_webview_window("main") {
+                restore_window_geometry(&w);
+                if load_settings(app.handle()).always_on_top {
+                    let _ = w.set_always_on_top(true);
+                }
+                if let Some(theme) = window_theme(&load_theme(app.handle())) {
+                    let _ = w.set_theme(Some(theme));
+                }
+            }
+
             // ── Global Quick Launcher — Ctrl+Shift+Space ─────────────────────
             // Registra un atajo de sistema global para mostrar Stacklume desde
             // cualquier aplicación y abrir el lanzador rápido en el frontend.
             if let Err(e) = app.global_shortcut().on_shortcut(
-                "Ctrl+Shift+Space",
+                QUICK_LAUNCHER_SHORTCUT,
                 |app_handle, _shortcut, event| {
                     if event.state() == ShortcutState::Pressed {
                         if let Some(w) = app_handle.get_webview_window("main") {
@@ -1725,15 +6968,21 @@ pub fn run() {
             #[cfg(dev)]
             {
                 println!("[Stacklume] Modo desarrollo — Next.js via beforeDevCommand");
+                let _ = load_settings(app.handle());
                 let app_handle = app.handle().clone();
                 std::thread::spawn(move || {
                     std::thread::sleep(std::time::Duration::from_millis(600));
+                    // En dev el servidor lo levanta beforeDevCommand: levantar el guard
+                    app_handle.state::<ServerState>().ready.store(true, Ordering::SeqCst);
                     if let Some(w) = app_handle.get_webview_window("main") {
                         if let Ok(url) = "http://localhost:7878".parse::<tauri::Url>() {
                             let _ = w.navigate(url);
                         }
-                        let _ = w.show();
-                        let _ = w.set_focus();
+                        let visibility = launch_visibility(&app_handle);
+                        let _ = present_main_window(&w, visibility);
+                        if visibility == LaunchVisibility::Normal {
+                            let _ = w.set_focus();
+                        }
                     }
                 });
                 // ── Inicializar llama-server en modo dev ─────────────────────────
@@ -1749,7 +6998,7 @@ pub fn run() {
                             Some(llama_exe.to_string_lossy().to_string());
                         // Comprobar si ya hay un modelo descargado
                         // Mismo scan que producción: busca cualquier .gguf que NO sea mmproj
-                        if let Ok(app_data) = app.path().app_data_dir() {
+                        if let Ok(app_data) = app_data_dir(app.handle()) {
                             let models_dir = app_data.join("models");
                             let model_opt = std::fs::read_dir(&models_dir)
                                 .ok()
@@ -1806,163 +7055,210 @@ pub fn run() {
             // ── MODO PRODUCCIÓN ─────────────────────────────────────────────────
             #[cfg(not(dev))]
             {
-                use std::process::{Command, Stdio};
-
                 // ── 1. Directorios y archivos de log ────────────────────────────
-                let app_data = app
-                    .path()
-                    .app_data_dir()
-                    .unwrap_or_else(|_| std::path::PathBuf::from("."));
-                let _ = std::fs::create_dir_all(&app_data);
+                // Sin directorio escribible no hay dónde dejar la DB ni los logs:
+                // mejor explicarlo que lanzar node en un entorno roto
+                let app_data = match app_data_dir(app.handle()) {
+                    Ok(dir) => dir,
+                    Err(e) => {
+                        eprintln!("[Stacklume] FATAL: {}", e);
+                        report_fatal_event(app.handle(), &e.to_string());
+                        if let Some(window) = app.get_webview_window("main") {
+                            let detail = format!(
+                                "Stacklume no encontró ninguna carpeta donde guardar sus datos. \
+                                 Comprueba los permisos de tu perfil de usuario o arranca con \
+                                 --data-dir apuntando a una carpeta con permiso de escritura. ({})",
+                                e
+                            );
+                            let log_path = std::env::temp_dir().join("stacklume.log");
+                            let html = error_page("Sin carpeta de datos", &detail, &log_path);
+                            if let Ok(url) = html.parse::<tauri::Url>() {
+                                let _ = window.navigate(url);
+                            }
+                            let _ = window.show();
+                        }
+                        return Ok(());
+                    }
+                };
 
-                let db_path = app_data.join("stacklume.db");
                 let log_path = app_data.join("stacklume.log");
                 let slog_path = app_data.join("server.log");
 
-                // Iniciar log (truncar el anterior)
+                // Iniciar log (rotar el anterior a stacklume.log.1)
+                rotate_log(&log_path, LOG_GENERATIONS);
+                rotate_log(&log_path.with_extension("jsonl"), LOG_GENERATIONS);
                 let _ = std::fs::write(
                     &log_path,
                     format!("=== Stacklume Log ===\nVersion: 0.1.0\n"),
                 );
+                if json_log_enabled() {
+                    let _ = std::fs::write(log_path.with_extension("jsonl"), "");
+                }
                 log(&log_path, "Iniciando aplicacion...");
                 log(&log_path, &format!("app_data: {}", app_data.display()));
+                for reason in &resolve_data_dir(app.handle()).rejected {
+                    log_at(&log_path, LogLevel::Warn, &format!("WARN: directorio de datos descartado: {}", reason));
+                }
+                if let Some(id) = app_id_override() {
+                    log(&log_path, &format!("STACKLUME_APP_ID override activo: {}", id));
+                }
+                let db_path = match db_path_override() {
+                    Ok(Some(path)) => {
+                        log(&log_path, &format!("STACKLUME_DB_PATH override activo: {}", path.display()));
+                        path
+                    }
+                    Ok(None) => app_data.join("stacklume.db"),
+                    Err(e) => {
+                        log_at(&log_path, LogLevel::Warn, &format!(
+                            "WARN: STACKLUME_DB_PATH ignorado ({}), usando app_data",
+                            e
+                        ));
+                        app_data.join("stacklume.db")
+                    }
+                };
+                log(&log_path, &format!(
+                    "Instancia primaria: {}",
+                    app.state::<InstanceLock>().is_primary
+                ));
 
-                // ── 2. Resolver rutas de recursos ────────────────────────────────
-                let resource_dir = app
-                    .path()
-                    .resource_dir()
-                    .unwrap_or_else(|_| {
-                        std::env::current_exe()
-                            .unwrap_or_default()
-                            .parent()
-                            .unwrap_or(std::path::Path::new("."))
-                            .to_path_buf()
-                    });
+                // Validar settings.json: si está corrupto se respalda y se sigue con
+                // valores por defecto (nunca debe impedir el arranque).
+                let _ = load_settings(app.handle());
 
-                let node_exe = resolve_resource(&resource_dir, "node/node.exe");
-                let server_js = resolve_resource(&resource_dir, "server/server.js");
-                let llama_exe = resolve_resource(&resource_dir, "llama/llama-server.exe");
+                // ── 2. Resolver rutas de recursos ────────────────────────────────
+                let ServerPaths { resource_dir, node_exe, server_js, llama_exe, node_override } =
+                    resolve_server_paths(app.handle());
 
                 let node_ok = node_exe.exists();
                 let server_ok = server_js.exists();
                 let llama_ok = llama_exe.exists();
 
                 log(&log_path, &format!("resource_dir : {}", resource_dir.display()));
+                match &node_override {
+                    Ok(Some(path)) => log(&log_path, &format!("STACKLUME_NODE_PATH override activo: {}", path.display())),
+                    Ok(None) => {}
+                    Err(e) => log_at(&log_path, LogLevel::Warn, &format!(
+                        "WARN: STACKLUME_NODE_PATH ignorado ({}), usando el node incluido",
+                        e
+                    )),
+                }
                 log(&log_path, &format!("node.exe     : {} [{}]", node_exe.display(), if node_ok { "OK" } else { "FALTA" }));
+                log(&log_path, &format!(
+                    "arquitectura : stacklume {} | node.exe {}",
+                    std::env::consts::ARCH,
+                    pe_machine(&node_exe).unwrap_or("no PE")
+                ));
                 log(&log_path, &format!("server.js    : {} [{}]", server_js.display(), if server_ok { "OK" } else { "FALTA" }));
                 log(&log_path, &format!("llama-server : {} [{}]", llama_exe.display(), if llama_ok { "OK" } else { "NO" }));
                 log(&log_path, &format!("db_path      : {}", db_path.display()));
+                log_json(&log_path, LogLevel::Info, &format!(
+                    "Recursos: node={} server={} llama={} db={}",
+                    node_ok, server_ok, llama_ok, db_path.display()
+                ));
+                *app.state::<ServerState>().diagnostics.lock().unwrap() = Some(DiagnosticsDto {
+                    resource_dir: resource_dir.to_string_lossy().to_string(),
+                    node_path: node_exe.to_string_lossy().to_string(),
+                    node_exists: node_ok,
+                    server_path: server_js.to_string_lossy().to_string(),
+                    server_exists: server_ok,
+                    db_path: db_path.to_string_lossy().to_string(),
+                    app_data_dir: app_data.to_string_lossy().to_string(),
+                    port: 0,
+                });
 
                 // ── 3. Mostrar ventana INMEDIATAMENTE con página de carga ────────
-                // Replica el LoadingScreen de la app: logo bento + "Stacklume" + tres puntos.
-                // La ventana siempre se muestra — el usuario nunca ve una ventana invisible.
-                let loading_page = concat!(
-                    "data:text/html,<html><head><meta charset='utf-8'><style>",
-                    "*{margin:0;padding:0;box-sizing:border-box}",
-                    "html,body{height:100%;background:%230d1117;overflow:hidden}",
-                    "body{display:flex;align-items:center;justify-content:center;",
-                    "font-family:system-ui,-apple-system,sans-serif}",
-                    ".wrap{display:flex;flex-direction:column;align-items:center;",
-                    "gap:32px;position:relative}",
-                    ".brand{display:flex;align-items:center;gap:12px}",
-                    ".logo-wrap{position:relative;width:48px;height:48px}",
-                    ".glow{position:absolute;inset:-4px;border-radius:14px;",
-                    "background:rgba(212,165,32,0.22);filter:blur(16px);",
-                    "animation:pulse 2s ease-in-out infinite}",
-                    ".logo{position:relative;width:48px;height:48px;border-radius:12px;",
-                    "background:linear-gradient(135deg,%23d4a520,%23b8860b);",
-                    "display:flex;align-items:center;justify-content:center;",
-                    "box-shadow:0 4px 24px rgba(212,165,32,0.40)}",
-                    ".name{font-size:24px;font-weight:600;color:%23e2e8f0;letter-spacing:-.3px}",
-                    ".dots{display:flex;gap:8px;align-items:center}",
-                    ".dot{width:10px;height:10px;border-radius:50%;background:%23d4a520}",
-                    ".d1{animation:bounce .8s ease-in-out 0s infinite}",
-                    ".d2{animation:bounce .8s ease-in-out .15s infinite}",
-                    ".d3{animation:bounce .8s ease-in-out .3s infinite}",
-                    ".lbl{font-size:13px;color:%23666}",
-                    ".c{position:absolute;width:64px;height:64px;",
-                    "border-color:rgba(212,165,32,0.18);border-style:solid}",
-                    ".tl{top:-88px;left:-88px;border-width:2px 0 0 2px;border-radius:12px 0 0 0}",
-                    ".tr{top:-88px;right:-88px;border-width:2px 2px 0 0;border-radius:0 12px 0 0}",
-                    ".bl{bottom:-88px;left:-88px;border-width:0 0 2px 2px;border-radius:0 0 0 12px}",
-                    ".br{bottom:-88px;right:-88px;border-width:0 2px 2px 0;border-radius:0 0 12px 0}",
-                    "@keyframes bounce{",
-                    "0%,100%{transform:translateY(0);opacity:.4;transform:translateY(0) scale(.8)}",
-                    "50%{transform:translateY(-8px) scale(1);opacity:1}}",
-                    "@keyframes pulse{0%,100%{opacity:.5;transform:scale(1)}",
-                    "50%{opacity:.85;transform:scale(1.25)}}",
-                    "</style></head><body><div class='wrap'>",
-                    "<div class='c tl'></div><div class='c tr'></div>",
-                    "<div class='brand'>",
-                    "<div class='logo-wrap'>",
-                    "<div class='glow'></div>",
-                    "<div class='logo'>",
-                    "<svg width='28' height='28' viewBox='0 0 24 24' fill='none'",
-                    " stroke='white' stroke-width='2'",
-                    " stroke-linecap='round' stroke-linejoin='round'>",
-                    "<rect x='3' y='3' width='7' height='7' rx='1'/>",
-                    "<rect x='14' y='3' width='7' height='7' rx='1'/>",
-                    "<rect x='3' y='14' width='7' height='7' rx='1'/>",
-                    "<rect x='14' y='14' width='7' height='7' rx='1'/>",
-                    "</svg></div></div>",
-                    "<span class='name'>Stacklume</span>",
-                    "</div>",
-                    "<div class='dots'>",
-                    "<div class='dot d1'></div>",
-                    "<div class='dot d2'></div>",
-                    "<div class='dot d3'></div>",
-                    "</div>",
-                    "<span class='lbl'>Iniciando Stacklume...</span>",
-                    "<div class='c bl'></div><div class='c br'></div>",
-                    "</div></body></html>"
-                );
-
-                if let Some(window) = app.get_webview_window("main") {
-                    if let Ok(url) = loading_page.parse::<tauri::Url>() {
+                // La ventana siempre se muestra — el usuario nunca ve una ventana invisible —
+                // salvo con --minimized/--hidden (autoarranque). Excepción: con STACKLUME_WARM_START=1 se omite el splash y la ventana
+                // (oculta por tauri.conf.json) se muestra ya navegada al servidor cuando
+                // /api/health responde, o con la página de error si el arranque falla.
+                let Some(window) = require_main_window(app.handle(), &log_path) else {
+                    return Ok(());
+                };
+                if warm_start_enabled() {
+                    log(&log_path, "Arranque en caliente: ventana oculta hasta que el servidor responda");
+                } else {
+                    if let Ok(url) = loading_page(app.handle()).parse::<tauri::Url>() {
                         let r = window.navigate(url);
                         log(&log_path, &format!("navigate(loading): {:?}", r));
                     }
-                    let r = window.show();
-                    log(&log_path, &format!("window.show(): {:?}", r));
-                } else {
-                    log(&log_path, "ERROR: No se encontro la ventana 'main'");
+                    let visibility = launch_visibility(app.handle());
+                    let r = present_main_window(&window, visibility);
+                    log(&log_path, &format!("window.show() [{}]: {:?}", visibility.as_str(), r));
                 }
 
                 // ── 4. Verificar que los recursos existen ────────────────────────
                 if !node_ok || !server_ok {
-                    log(&log_path, "FATAL: Recursos no encontrados — abortando");
+                    log_at(&log_path, LogLevel::Error, "FATAL: Recursos no encontrados — abortando");
+                    log_json(&log_path, LogLevel::Error, "Recursos no encontrados");
+                    report_fatal_event(app.handle(), &format!(
+                        "recursos no encontrados (node.exe: {}, server.js: {})",
+                        node_ok, server_ok
+                    ));
                     if let Some(window) = app.get_webview_window("main") {
-                        let html = format!(
-                            concat!(
-                                "data:text/html,<html><body style='background:%230d1117;color:%23fff;",
-                                "font-family:sans-serif;display:flex;align-items:center;",
-                                "justify-content:center;height:100vh;margin:0'>",
-                                "<div style='text-align:center;padding:2rem;max-width:600px'>",
-                                "<h2 style='color:%23ef4444;margin-bottom:1rem'>",
-                                "Recursos no encontrados</h2>",
-                                "<p style='color:%23aaa;margin-bottom:1rem'>",
-                                "node.exe: {node_ok} | server.js: {server_ok}</p>",
-                                "<p style='font-size:12px;color:%23666'>",
-                                "Log: {log}</p></div></body></html>"
-                            ),
-                            node_ok = node_ok,
-                            server_ok = server_ok,
-                            log = log_path.file_name().unwrap_or_default().to_string_lossy()
-                        );
+                        let detail = format!("node.exe: {} | server.js: {}", node_ok, server_ok);
+                        let html = missing_page(app.handle(), &detail, &log_path);
                         if let Ok(url) = html.parse::<tauri::Url>() {
                             let _ = window.navigate(url);
                         }
+                        // Con arranque en caliente la ventana aún no se ha mostrado
+                        let _ = window.show();
                     }
                     return Ok(());
                 }
 
+                probe_node_version(app.handle(), &node_exe);
+
                 // ── 5. Asignar puerto Next.js ────────────────────────────────────
-                let port = find_free_port();
+                let Some(port) = find_free_port(load_last_port(&app_data)) else {
+                    log_at(&log_path, LogLevel::Error, &format!(
+                        "ERROR: Puertos {} ocupados y sin alternativa (STACKLUME_PORT_EXHAUSTED)",
+                        PortRange::from_env()
+                    ));
+                    report_fatal_event(app.handle(), "no hay ningún puerto libre para el servidor");
+                    if let Some(window) = app.get_webview_window("main") {
+                        let detail = format!(
+                            "Los puertos {} están ocupados y no se encontró ningún puerto alternativo. \
+                             Cierra la aplicación que los usa o cambia STACKLUME_PORT_START, \
+                             STACKLUME_PORT_COUNT o STACKLUME_PORT_EXHAUSTED.",
+                            PortRange::from_env()
+                        );
+                        let html = error_page("Sin puerto disponible", &detail, &log_path);
+                        if let Ok(url) = html.parse::<tauri::Url>() {
+                            let _ = window.navigate(url);
+                        }
+                        // Con arranque en caliente la ventana aún no se ha mostrado
+                        let _ = window.show();
+                    }
+                    return Ok(());
+                };
                 {
                     let srv = app.state::<ServerState>();
                     *srv.port.lock().unwrap() = port;
                 }
                 log(&log_path, &format!("Puerto asignado: {}", port));
+                let (data_dir_source, port_source) = config_sources();
+                log(&log_path, &format!(
+                    "Origen de la config: datos ← {} | puerto ← {}",
+                    data_dir_source, port_source
+                ));
+                log_json(&log_path, LogLevel::Info, &format!("Puerto asignado: {}", port));
+                if server_exposed() {
+                    let msg = format!(
+                        "WARN: SEGURIDAD — node escucha en {}:{} (STACKLUME_BIND_HOST), accesible desde otras \
+                         máquinas de la red. Token de sesión obligatorio. Úsalo solo en redes de confianza.",
+                        bind_host(),
+                        port
+                    );
+                    log_at(&log_path, LogLevel::Warn, &msg);
+                    log_json(&log_path, LogLevel::Warn, &msg);
+                    eprintln!("[Stacklume] {}", msg);
+                }
+                // Un puerto aleatorio no se recuerda: el siguiente arranque sortea otro
+                if random_port_enabled() {
+                    log(&log_path, "STACKLUME_RANDOM_PORT activo: puerto efímero no persistido");
+                } else {
+                    save_last_port(&app_data, port);
+                }
 
                 // ── 5b. Configurar LLM local (llama-server) ───────────────────────
                 // Pre-asignamos el puerto aunque el modelo no esté descargado todavía,
@@ -2018,17 +7314,6 @@ pub fn run() {
                 log(&log_path, &format!("llama_port: {}", llama_port));
 
                 // ── 6. Lanzar servidor Next.js ───────────────────────────────────
-                // Redirigimos stdout y stderr al archivo server.log para diagnóstico.
-                let slog_out = std::fs::OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(&slog_path)
-                    .ok();
-                let slog_err = slog_out
-                    .as_ref()
-                    .and_then(|f| f.try_clone().ok());
-
                 // El servidor Next.js standalone debe ejecutarse desde su propio directorio.
                 // Pasamos "server.js" como ruta RELATIVA con current_dir apuntando al
                 // directorio del servidor — esto evita el error EISDIR al pasar rutas
@@ -2040,236 +7325,69 @@ pub fn run() {
 
                 log(&log_path, &format!("server_dir: {}", server_dir.display()));
 
-                let mut cmd = Command::new(&node_exe);
-                cmd.env_clear()
-                    .current_dir(&server_dir)
-                    .arg("server.js")
-                    // Variables de la aplicación
-                    .env("PORT", port.to_string())
-                    .env("HOSTNAME", "127.0.0.1")
-                    .env("DESKTOP_MODE", "true")
-                    .env("DATABASE_PATH", db_path.to_str().unwrap_or("stacklume.db"))
-                    .env("NODE_ENV", "production")
-                    // Variables del sistema Windows necesarias para Node.js
-                    .env("SystemRoot", std::env::var("SystemRoot").unwrap_or_default())
-                    .env("SystemDrive", std::env::var("SystemDrive").unwrap_or_default())
-                    .env("APPDATA", std::env::var("APPDATA").unwrap_or_default())
-                    .env("LOCALAPPDATA", std::env::var("LOCALAPPDATA").unwrap_or_default())
-                    .env("TEMP", std::env::var("TEMP").unwrap_or_default())
-                    .env("TMP", std::env::var("TMP").unwrap_or_default())
-                    .env("PATH", std::env::var("PATH").unwrap_or_default())
-                    .env("USERPROFILE", std::env::var("USERPROFILE").unwrap_or_default())
-                    .env("COMPUTERNAME", std::env::var("COMPUTERNAME").unwrap_or_default())
-                    .env("USERNAME", std::env::var("USERNAME").unwrap_or_default())
-                    .env("ProgramData", std::env::var("ProgramData").unwrap_or_default())
-                    .env("windir", std::env::var("windir").unwrap_or_default())
-                    // Puerto de llama-server para que la API route /api/llm/* lo use
-                    .env("LLAMA_PORT", llama_port.to_string());
-
-                // Cargar claves privadas desde .env.keys (generado por build-desktop.mjs).
-                // Este archivo solo existe en builds privadas del propietario — no en el repo público.
-                // Whitelist de variables de entorno permitidas desde .env.keys
-                // para evitar que un archivo .env.keys comprometido inyecte
-                // variables arbitrarias (PATH, LD_PRELOAD, NODE_OPTIONS, etc.)
-                const ALLOWED_ENV_KEYS: &[&str] = &[
-                    "AUTH_SECRET",
-                    "AUTH_USERNAME",
-                    "AUTH_PASSWORD_HASH",
-                    "SENTRY_DSN",
-                    "NEXT_PUBLIC_SENTRY_DSN",
-                    "UPSTASH_REDIS_REST_URL",
-                    "UPSTASH_REDIS_REST_TOKEN",
-                    "DATABASE_URL",
-                ];
-
-                let env_keys_path = server_dir.join(".env.keys");
-                if env_keys_path.exists() {
-                    if let Ok(contents) = std::fs::read_to_string(&env_keys_path) {
-                        let mut loaded = 0u32;
-                        let mut skipped = 0u32;
-                        for line in contents.lines() {
-                            let line = line.trim();
-                            if line.starts_with('#') || line.is_empty() { continue; }
-                            if let Some((k, v)) = line.split_once('=') {
-                                let k = k.trim();
-                                let v = v.trim().trim_matches('"').trim_matches('\'');
-                                if !k.is_empty() && !v.is_empty() {
-                                    if ALLOWED_ENV_KEYS.contains(&k) {
-                                        cmd.env(k, v);
-                                        loaded += 1;
-                                    } else {
-                                        skipped += 1;
-                                    }
-                                }
+                let launch = ServerLaunch {
+                    node_exe: node_exe.clone(),
+                    server_dir: server_dir.clone(),
+                    db_path: db_path.clone(),
+                    port,
+                    llama_port,
+                    locale: effective_locale(app.handle()),
+                    session_token: app.state::<ServerState>().session_token.clone(),
+                };
+                log(&log_path, &format!("Locale: {}", launch.locale));
+                *app.state::<ServerState>().launch.lock().unwrap() = Some(launch.clone());
+
+                // Con la DB dañada node fallaría tras agotar el timeout: ofrecer la
+                // recuperación ya. Sus acciones relanzan node con `ensure_server`.
+                let spawned = match check_database_file(&db_path) {
+                    Ok(()) => {
+                        clear_stale_db_locks(app.handle(), &db_path, &log_path);
+                        backup_database(&db_path, &log_path);
+                        match spawn_server(app.handle(), &launch, &log_path, &slog_path) {
+                            Ok(spawned) => Some(spawned),
+                            Err(e) => {
+                                show_node_spawn_error(app.handle(), &log_path, &e);
+                                return Ok(());
                             }
                         }
-                        log(&log_path, &format!(".env.keys: {} variables cargadas, {} ignoradas (no en whitelist)", loaded, skipped));
-                    }
-                }
-
-                // Evitar que node.exe abra una ventana de consola en Windows
-                #[cfg(windows)]
-                {
-                    use std::os::windows::process::CommandExt;
-                    cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
-                }
-
-                match (slog_out, slog_err) {
-                    (Some(out), Some(err)) => {
-                        cmd.stdout(Stdio::from(out)).stderr(Stdio::from(err));
-                    }
-                    _ => {
-                        cmd.stdout(Stdio::null()).stderr(Stdio::null());
                     }
-                }
-
-                log(&log_path, &format!("Spawning: {} {}", node_exe.display(), server_js.display()));
-
-                match cmd.spawn() {
-                    Ok(child) => {
-                        let pid = child.id();
-                        log(&log_path, &format!("Servidor iniciado (PID: {})", pid));
-
-                        // Job Object: node.exe muere automáticamente cuando Stacklume.exe
-                        // termina por CUALQUIER razón (incluso TerminateProcess de NSIS).
-                        #[cfg(windows)]
-                        {
-                            let job = create_job_for_child(pid);
-                            if job != 0 {
-                                log(&log_path, "Job Object creado — node.exe se matará al cerrar la app");
-                                *app.state::<ServerState>().node_job.lock().unwrap() = job;
-                            } else {
-                                log(&log_path, "WARN: No se pudo crear Job Object");
-                            }
-                        }
-
-                        // Guardamos el handle para poder matar el proceso explícitamente al cerrar
-                        *app.state::<ServerState>().node_child.lock().unwrap() = Some(child);
-
-                        // Arrancar llama-server en background si el modelo está disponible
-                        if llama_ok {
-                            let has_model = {
-                                let s = app.state::<LlamaState>();
-                                let result = s.model_path.lock().unwrap().is_some();
-                                result
-                            };
-                            if has_model {
-                                let app_llama = app.handle().clone();
-                                let log_path_llama = log_path.clone();
-                                std::thread::spawn(move || {
-                                    // Delay pequeño para no competir con el arranque de node.js
-                                    std::thread::sleep(std::time::Duration::from_secs(2));
-                                    log(&log_path_llama, "Arrancando llama-server...");
-                                    match spawn_llama_server_blocking(&app_llama) {
-                                        Ok(()) => log(&log_path_llama, "llama-server listo"),
-                                        Err(e) => log(&log_path_llama, &format!("llama-server error: {}", e)),
-                                    }
-                                });
-                            }
-                        }
+                    Err(problem) => {
+                        show_recovery_page(app.handle(), &problem, &log_path);
+                        None
                     }
-                    Err(e) => {
-                        log(&log_path, &format!("ERROR spawning: {}", e));
-                        if let Some(window) = app.get_webview_window("main") {
-                            let html = format!(
-                                concat!(
-                                    "data:text/html,<html><body style='background:%230d1117;color:%23fff;",
-                                    "font-family:sans-serif;display:flex;align-items:center;",
-                                    "justify-content:center;height:100vh;margin:0'>",
-                                    "<div style='text-align:center;padding:2rem;max-width:600px'>",
-                                    "<h2 style='color:%23ef4444;margin-bottom:1rem'>",
-                                    "Error al iniciar servidor</h2>",
-                                    "<p style='color:%23aaa;margin-bottom:1rem'>{e}</p>",
-                                    "<p style='font-size:12px;color:%23666'>Log: {log}</p>",
-                                    "</div></body></html>"
-                                ),
-                                e = e,
-                                log = log_path.file_name().unwrap_or_default().to_string_lossy()
-                            );
-                            if let Ok(url) = html.parse::<tauri::Url>() {
-                                let _ = window.navigate(url);
+                };
+                start_server_watchdog(app.handle().clone());
+                start_server_log_stream(app.handle().clone(), slog_path.clone());
+
+                // Arrancar llama-server en background si el modelo está disponible
+                if llama_ok {
+                    let has_model = {
+                        let s = app.state::<LlamaState>();
+                        let result = s.model_path.lock().unwrap().is_some();
+                        result
+                    };
+                    if has_model {
+                        let app_llama = app.handle().clone();
+                        let log_path_llama = log_path.clone();
+                        std::thread::spawn(move || {
+                            // Delay pequeño para no competir con el arranque de node.js
+                            std::thread::sleep(std::time::Duration::from_secs(2));
+                            log(&log_path_llama, "Arrancando llama-server...");
+                            match spawn_llama_server_blocking(&app_llama) {
+                                Ok(()) => log(&log_path_llama, "llama-server listo"),
+                                Err(e) => log(&log_path_llama, &format!("llama-server error: {}", e)),
                             }
-                        }
-                        return Ok(());
+                        });
                     }
                 }
 
                 // ── 7. Hilo de espera: navega al servidor cuando esté listo ─────
-                let app_handle = app.handle().clone();
-                let log_path2 = log_path.clone();
-                let slog_path2 = slog_path.clone();
+                if let Some(spawned) = spawned {
+                    start_server_waiter(app.handle().clone(), port, spawned, log_path.clone(), slog_path.clone(), true);
+                }
 
-                std::thread::spawn(move || {
-                    log(&log_path2, "Esperando que el servidor arranque...");
-                    let ready = wait_for_server(port);
-
-                    if ready {
-                        log(&log_path2, "Servidor listo — navegando");
-                        if let Some(window) = app_handle.get_webview_window("main") {
-                            let url_str = format!("http://127.0.0.1:{}", port);
-                            if let Ok(url) = url_str.parse::<tauri::Url>() {
-                                let rn = window.navigate(url);
-                                let rs = window.show();
-                                log(&log_path2, &format!("navigate: {:?} | show: {:?}", rn, rs));
-                            }
-                        }
-                    } else {
-                        // Timeout: leer el server.log para mostrar el error
-                        log(&log_path2, "TIMEOUT: El servidor no respondio en 40s");
-                        let tail = std::fs::read_to_string(&slog_path2)
-                            .unwrap_or_else(|_| "(servidor sin output)".into());
-                        let tail_last: String = tail
-                            .lines()
-                            .rev()
-                            .take(20)
-                            .collect::<Vec<_>>()
-                            .iter()
-                            .rev()
-                            .cloned()
-                            .collect::<Vec<_>>()
-                            .join("\n");
-                        log(&log_path2, &format!("Server.log tail:\n{}", tail_last));
-
-                        // Mostrar página de error con los últimos logs del servidor
-                        if let Some(window) = app_handle.get_webview_window("main") {
-                            // Codificar el tail para data URI (solo los chars peligrosos)
-                            let encoded_tail = tail_last
-                                .replace('%', "%25")
-                                .replace('#', "%23")
-                                .replace('<', "&lt;")
-                                .replace('>', "&gt;");
-                            let html = format!(
-                                concat!(
-                                    "data:text/html,<html><head><meta charset='utf-8'></head>",
-                                    "<body style='background:%230d1117;color:%23fff;",
-                                    "font-family:sans-serif;display:flex;align-items:center;",
-                                    "justify-content:center;height:100vh;margin:0'>",
-                                    "<div style='text-align:center;padding:2rem;max-width:700px;width:100%'>",
-                                    "<h2 style='color:%23f97316;margin-bottom:.5rem'>",
-                                    "El servidor no arranco</h2>",
-                                    "<p style='color:%23aaa;margin-bottom:1rem;font-size:14px'>",
-                                    "Puerto {port} - timeout 40s</p>",
-                                    "<pre style='background:%23111;border:1px solid %23333;",
-                                    "border-radius:8px;padding:1rem;font-size:11px;",
-                                    "text-align:left;overflow:auto;max-height:250px;",
-                                    "color:%23f87171;white-space:pre-wrap;word-break:break-all'>",
-                                    "{tail}</pre>",
-                                    "<p style='margin-top:1rem;font-size:11px;color:%23666'>",
-                                    "Log completo: {log}</p>",
-                                    "</div></body></html>"
-                                ),
-                                port = port,
-                                tail = encoded_tail,
-                                log = log_path2.file_name().unwrap_or_default().to_string_lossy()
-                            );
-                            if let Ok(url) = html.parse::<tauri::Url>() {
-                                let rn = window.navigate(url);
-                                let rs = window.show();
-                                log(&log_path2, &format!("error page nav: {:?} | show: {:?}", rn, rs));
-                            }
-                        }
-                    }
-                });
+                // ── 8. Reinicio diario programado (si está configurado) ─────────
+                arm_scheduled_restart(app.handle());
 
                 Ok(())
             }
@@ -2277,48 +7395,45 @@ pub fn run() {
         .on_window_event(|_window, event| {
             // Botón X → ocultar al tray en lugar de cerrar la aplicación.
             // Para cerrar completamente: menú del tray → "Cerrar" (app.exit(0)).
+            if matches!(event, tauri::WindowEvent::Resized(_) | tauri::WindowEvent::Focused(_)) {
+                if let Some(w) = _window.app_handle().get_webview_window(_window.label()) {
+                    emit_window_state(&w);
+                }
+            }
+
             if let tauri::WindowEvent::CloseRequested { api, .. } = event {
                 api.prevent_close();
+                if let Some(w) = _window.app_handle().get_webview_window(_window.label()) {
+                    save_window_geometry(&w);
+                }
                 let _ = _window.hide();
+                #[cfg(not(dev))]
+                schedule_keep_alive_stop(_window.app_handle());
+            }
+
+            // Ventana reabierta: cancela el keep-alive pendiente y, si node.exe ya se
+            // detuvo por inactividad, lo relanza con la misma configuración.
+            #[cfg(not(dev))]
+            if let tauri::WindowEvent::Focused(true) = event {
+                let app = _window.app_handle();
+                let state = app.state::<ServerState>();
+                state.visibility_seq.fetch_add(1, Ordering::SeqCst);
+                if state.idle_stopped.swap(false, Ordering::SeqCst) {
+                    let app = app.clone();
+                    std::thread::spawn(move || {
+                        note_restart(&app, "keep-alive");
+                        if let Err(e) = ensure_server(&app) {
+                            app_log(&app, &format!("keep-alive: no se pudo relanzar node.exe: {}", e));
+                        }
+                    });
+                }
             }
 
             // Cuando la ventana principal se destruye, matar node.exe y llama-server.
             // Esto evita que queden procesos bloqueando archivos durante reinstalaciones.
             if let tauri::WindowEvent::Destroyed = event {
                 #[cfg(not(dev))]
-                {
-                    let app = _window.app_handle();
-
-                    // Matar node.exe
-                    {
-                        let state = app.state::<ServerState>();
-                        let maybe_child = state
-                            .node_child
-                            .lock()
-                            .ok()
-                            .and_then(|mut g| g.take());
-                        drop(state);
-                        if let Some(mut child) = maybe_child {
-                            let _ = child.kill();
-                            let _ = child.wait();
-                        }
-                    }
-
-                    // Matar llama-server
-                    {
-                        let llama = app.state::<LlamaState>();
-                        let maybe_llama = llama
-                            .llama_child
-                            .lock()
-                            .ok()
-                            .and_then(|mut g| g.take());
-                        drop(llama);
-                        if let Some(mut child) = maybe_llama {
-                            let _ = child.kill();
-                            let _ = child.wait();
-                        }
-                    }
-                }
+                shutdown_children(_window.app_handle());
             }
         })
         .invoke_handler(tauri::generate_handler![
@@ -2328,11 +7443,61 @@ pub fn run() {
             open_in_vscode,
             get_server_port,
             get_app_data_dir,
+            open_app_data_dir,
             minimize_window,
             toggle_maximize_window,
             close_window,
+            reset_window_geometry,
+            set_always_on_top,
+            show_loading_screen,
+            show_maintenance_page,
+            hide_loading_screen,
+            instance_lock_info,
+            run_server_script,
+            health_config,
+            cancel_startup,
+            database_stats,
+            set_log_capture,
+            read_database_bytes,
+            get_confirm_quit,
+            set_confirm_quit,
+            confirm_quit,
+            child_supervision_info,
+            boost_log_level,
+            list_child_pids,
+            kill_pid,
+            schedule_restart,
+            clear_scheduled_restart,
+            set_server_busy,
+            debug_features,
+            verify_resources,
+            set_launch_visibility,
+            shortcut_hints,
+            restart_server,
+            kill_server,
+            get_locale,
+            set_locale,
+            get_server_logs,
+            supervision_counters,
+            get_health_status,
+            get_startup_metrics,
+            get_server_pid,
+            is_port_free,
+            open_log_file,
+            get_session_token,
+            quit_app,
+            relaunch_app,
+            get_window_state,
+            set_theme,
+            get_theme,
+            get_diagnostics,
+            export_diagnostics,
+            restore_db_backup,
             update_tray_icon,
             get_app_version,
+            get_version_info,
+            get_build_info,
+            check_health,
             get_llama_port,
             get_llm_status,
             start_llama_server,
@@ -2351,3 +7516,104 @@ pub fn run() {
         .run(tauri::generate_context!())
         .expect("Error al ejecutar Stacklume");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// Rutas de instalación reales que han dado problemas: espacios, paréntesis
+    /// ("Program Files (x86)") y unicode fuera de ASCII.
+    fn awkward_roots() -> Vec<PathBuf> {
+        let mut roots = vec![
+            PathBuf::from("/opt/Program Files (x86)/Stacklume"),
+            PathBuf::from("/home/José Ñúñez/Aplicación de prueba/数据"),
+        ];
+        if cfg!(windows) {
+            roots.push(PathBuf::from(r"C:\Program Files (x86)\Stacklume & Co"));
+            roots.push(PathBuf::from(r"C:\Users\Zoë\AppData\Local\Stäcklume (beta)"));
+        }
+        roots
+    }
+
+    fn launch_in(root: &Path) -> ServerLaunch {
+        ServerLaunch {
+            node_exe: root.join("node runtime").join("node.exe"),
+            server_dir: root.join("server (standalone)"),
+            db_path: root.join("datos del usuario").join("stacklume.db"),
+            port: 7879,
+            llama_port: 0,
+            locale: "es-ES".to_string(),
+            session_token: "token".to_string(),
+        }
+    }
+
+    fn env_value(cmd: &std::process::Command, key: &str) -> Option<std::ffi::OsString> {
+        cmd.get_envs()
+            .find(|(k, _)| *k == std::ffi::OsStr::new(key))
+            .and_then(|(_, v)| v.map(|v| v.to_os_string()))
+    }
+
+    #[test]
+    fn node_command_keeps_awkward_paths_intact() {
+        let log_path = std::env::temp_dir().join("stacklume-test.log");
+        for root in awkward_roots() {
+            let launch = launch_in(&root);
+            let cmd = launch.node_command(&log_path);
+            assert_eq!(Path::new(cmd.get_program()), launch.node_exe, "exe en {}", root.display());
+            assert_eq!(cmd.get_current_dir(), Some(launch.server_dir.as_path()));
+            assert_eq!(env_value(&cmd, "DATABASE_PATH"), Some(launch.db_path.clone().into_os_string()));
+            // Las rutas van como argumentos/entorno sueltos: nada de comillas añadidas
+            assert!(!cmd.get_program().to_string_lossy().contains('"'));
+        }
+    }
+
+    /// Decodifica `%XX` (las data URLs de `data_url_text` no llevan `+` ni nada más).
+    fn percent_decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn data_url_text_survives_url_parsing() {
+        let input = "100% listo #1? a=1&b=2 <script>alert('x')</script>\r\n\"comillas\" \
+                     ñandú 数据 🚀\tfin\u{1b}[31m";
+        let encoded = data_url_text(input);
+        let url = tauri::Url::parse(&format!("data:text/html,{}", encoded)).unwrap();
+        assert_eq!(url.scheme(), "data");
+        assert!(url.fragment().is_none() && url.query().is_none());
+        let body = url.path().split_once(',').unwrap().1;
+        // \r y el ESC de las secuencias ANSI se descartan; lo demás vuelve escapado como HTML
+        let expected = "100% listo #1? a=1&amp;b=2 &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;\n\
+                        &quot;comillas&quot; ñandú 数据 🚀\tfin[31m";
+        assert_eq!(percent_decode(body), expected);
+    }
+
+    #[test]
+    fn plain_path_strips_verbatim_prefix() {
+        let regular = Path::new("/opt/Program Files (x86)/Stacklume/server");
+        assert_eq!(plain_path(regular), regular);
+        if cfg!(windows) {
+            assert_eq!(
+                plain_path(Path::new(r"\\?\C:\Program Files (x86)\Stäcklume\node.exe")),
+                Path::new(r"C:\Program Files (x86)\Stäcklume\node.exe")
+            );
+            assert_eq!(
+                plain_path(Path::new(r"\\?\UNC\servidor\compartido\Stacklume")),
+                Path::new(r"\\servidor\compartido\Stacklume")
+            );
+        }
+    }
+}